@@ -2,11 +2,14 @@ package io.drogue.iot.hackathon.data;
 
 import io.quarkus.runtime.annotations.RegisterForReflection;
 
+/**
+ * Acceleration in m/s².
+ */
 @RegisterForReflection
 public class Acceleration {
-    public Long x;
+    public Double x;
 
-    public Long y;
+    public Double y;
 
-    public Long z;
+    public Double z;
 }
@@ -4,17 +4,20 @@ import io.quarkus.runtime.annotations.RegisterForReflection;
 
 @RegisterForReflection
 public class SensorPayload {
-    private Long temperature;
+    /**
+     * Temperature in °C.
+     */
+    private Double temperature;
 
     private Acceleration acceleration;
 
     private Long noise;
 
-    public Long getTemperature() {
+    public Double getTemperature() {
         return this.temperature;
     }
 
-    public void setTemperature(Long temperature) {
+    public void setTemperature(Double temperature) {
         this.temperature = temperature;
     }
 
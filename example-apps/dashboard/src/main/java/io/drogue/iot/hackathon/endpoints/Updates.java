@@ -137,7 +137,7 @@ public class Updates {
                         cell(ofNullable(entry.getKey())),
                         cell(ofNullable(values.get("temperature"))
                                         .flatMap(BasicFeature::toDouble),
-                                value -> String.format("%.0f °C", value)),
+                                value -> String.format("%.1f °C", value)),
                         cell(ofNullable(values.get("noise"))
                                 .flatMap(BasicFeature::toDouble)),
                         cell(ofNullable(values.get("acceleration"))
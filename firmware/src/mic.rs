@@ -5,7 +5,7 @@ use embassy_nrf::{
     peripherals::{P0_05, P0_20},
     saadc::*,
 };
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 
 /// Microphone interface
 pub struct Microphone<'a> {
@@ -21,33 +21,41 @@ impl<'a> Microphone<'a> {
         Self { adc, enable, mic }
     }
 
-    /// Enable the microphone and return the sound level as detected by the microphone.
+    /// Enable the microphone and return the sound level as detected by the microphone over the sampling window.
     ///
     /// The returned value is a number between 0 and 255 and does not correspond to any official sound level meter number.
-    pub async fn sound_level(&mut self) -> u8 {
+    pub async fn sound_level(&mut self, window: Duration) -> u8 {
         self.enable.set_high();
         Timer::after(Duration::from_millis(10)).await;
 
-        let mut bufs = [[[0; 1]; 1024]; 2];
-
-        let mut channel = ChannelConfig::single_ended(&mut self.mic);
-        channel.gain = Gain::GAIN4;
-        let mut adc = self.adc.lock().await;
-        let mut adc = adc.configure(Config::default(), [channel; 1]);
-        adc.run_timer_sampler::<u32, _, 1024>(&mut bufs, 727, move |_| SamplerState::Stopped)
-            .await;
-        self.enable.set_low();
-
         let mut max: i16 = i16::MIN;
         let mut min: i16 = i16::MAX;
-        for b in bufs[0] {
-            if b[0] > max {
-                max = b[0];
+        let end = Instant::now() + window;
+        loop {
+            let mut bufs = [[[0; 1]; 1024]; 2];
+
+            let mut channel = ChannelConfig::single_ended(&mut self.mic);
+            channel.gain = Gain::GAIN4;
+            let mut adc = self.adc.lock().await;
+            let mut adc = adc.configure(Config::default(), [channel; 1]);
+            adc.run_timer_sampler::<u32, _, 1024>(&mut bufs, 727, move |_| SamplerState::Stopped)
+                .await;
+
+            for b in bufs[0] {
+                if b[0] > max {
+                    max = b[0];
+                }
+                if b[0] < min {
+                    min = b[0];
+                }
             }
-            if b[0] < min {
-                min = b[0];
+
+            if Instant::now() >= end {
+                break;
             }
         }
+        self.enable.set_low();
+
         let amplitude = max - min;
         // Transpose to u8
         (amplitude / 16) as u8
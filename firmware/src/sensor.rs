@@ -5,13 +5,16 @@ use btmesh_device::{
 use btmesh_models::sensor::SensorStatus;
 use core::future::Future;
 use embassy_futures::select::{select, Either};
-use embassy_time::Ticker;
+use embassy_time::{Duration, Ticker};
 use futures::StreamExt;
 use microbit_bsp::accelerometer::Accelerometer;
 use nrf_softdevice::{temperature_celsius, Softdevice};
 
 use sensor_model::*;
 
+// How often readings are checked when publishing on change.
+const ON_CHANGE_INTERVAL: Duration = Duration::from_secs(1);
+
 // A sensor type implementing the SensorSetupServer model.
 #[allow(dead_code)]
 pub struct Sensor {
@@ -20,6 +23,13 @@ pub struct Sensor {
     ticker: Option<Ticker>,
     xl: Accelerometer<'static>,
     mic: Microphone<'static>,
+    // The publish period configured for the model, or None when publishing on change.
+    period: Option<Duration>,
+    // The period the ticker is running at, after applying the sensor cadence.
+    current: Option<Duration>,
+    // The sensor cadence and settings, configured through the setup server.
+    setup: SetupState,
+    last: Option<SensorPayload>,
 }
 
 impl Sensor {
@@ -33,50 +43,120 @@ impl Sensor {
             ticker: None,
             xl,
             mic,
+            period: None,
+            current: None,
+            setup: SetupState::default(),
+            last: None,
         }
     }
 
     // Read the current on-chip temperature
     async fn read(&mut self) -> Result<SensorPayload, ()> {
-        let temperature: i8 = temperature_celsius(self.sd).map_err(|_| ())?.to_num();
+        let temperature: f32 = temperature_celsius(self.sd).map_err(|_| ())?.to_num();
 
         // TODO Accelerometer - Read the accelerometer data and add to the sensor payload,
         let mut accel = Acceleration::default();
         let status = self.xl.accel_data().map_err(|_| ())?;
-        accel.x = status.x as i16;
-        accel.y = status.y as i16;
-        accel.z = status.z as i16;
+        accel.x = MilliG(status.x as i16);
+        accel.y = MilliG(status.y as i16);
+        accel.z = MilliG(status.z as i16);
 
         // TODO Microphone - Read the sound level data and add to sensor payload,
-        let noise: u8 = self.mic.sound_level().await;
+        let window = Duration::from_millis(self.setup.settings.noise_sampling_window as u64);
+        let noise: u8 = self.mic.sound_level(window).await;
 
         Ok(SensorPayload {
-            temperature: temperature * 2,
+            temperature: HalfDegreesCelsius::from_celsius(temperature),
             acceleration: accel,
-            noise,
+            noise: NoiseLevel(noise),
+            present: PropertySet::all(),
         })
     }
 
-    // Process an inbound control message
-    async fn process(&mut self, data: &InboundModelPayload<SensorMessage>) {
+    // Process an inbound message or control message
+    async fn process<C: BluetoothMeshModelContext<SensorServer>>(
+        &mut self,
+        ctx: &mut C,
+        data: &InboundModelPayload<SensorMessage>,
+    ) {
         match data {
+            // Sensor Cadence and Sensor Setting messages configure the setup server, Get and
+            // acknowledged Set messages are replied with the resulting status.
+            InboundModelPayload::Message(message, meta) => {
+                let reply = match SetupMessage::from_sensor_message(message) {
+                    Ok(Some(setup)) => {
+                        defmt::info!("Received sensor setup message");
+                        self.setup.process(&setup)
+                    }
+                    Ok(None) => None,
+                    Err(e) => {
+                        defmt::warn!("Received malformed sensor setup message: {:?}", e);
+                        None
+                    }
+                };
+                if let Some(reply) = reply {
+                    match reply.to_sensor_message() {
+                        Ok(message) => match ctx.send(message, meta.reply()).await {
+                            Ok(_) => {
+                                defmt::info!("Sent sensor setup status");
+                            }
+                            Err(e) => {
+                                defmt::warn!("Error sending sensor setup status: {:?}", e);
+                            }
+                        },
+                        Err(_) => {
+                            defmt::warn!("Error encoding sensor setup status");
+                        }
+                    }
+                }
+            }
             InboundModelPayload::Control(Control::PublicationCadence(cadence)) => match cadence {
                 PublicationCadence::Periodic(cadence) => {
                     defmt::info!("Enabling sensor publish at {:?}", cadence.as_secs());
+                    self.period.replace(*cadence);
+                    self.current.replace(*cadence);
                     self.ticker.replace(Ticker::every(*cadence));
                 }
                 PublicationCadence::OnChange => {
                     defmt::info!("Sensor publish on change!");
-                    self.ticker.take();
+                    self.period.take();
+                    self.current.replace(ON_CHANGE_INTERVAL);
+                    self.ticker.replace(Ticker::every(ON_CHANGE_INTERVAL));
                 }
                 PublicationCadence::None => {
                     defmt::info!("Disabling sensor publish");
+                    self.period.take();
+                    self.current.take();
                     self.ticker.take();
                 }
             },
             _ => {}
         }
     }
+
+    // Decide whether a reading should be published, and adjust the publish period to the sensor cadence.
+    fn update(&mut self, reading: &SensorPayload) -> bool {
+        let publish = match (self.period, self.last.as_ref()) {
+            // Publishing on change
+            (None, Some(last)) => self.setup.cadences.is_triggered(last, reading),
+            _ => true,
+        };
+
+        if let Some(period) = self.period {
+            let period_ms = period.as_millis() as u32;
+            let next = Duration::from_millis(self.setup.cadences.period_ms(period_ms, reading) as u64);
+            if self.current != Some(next) {
+                defmt::info!("Adjusting sensor publish period to {} ms", next.as_millis());
+                self.current.replace(next);
+                self.ticker.replace(Ticker::every(next));
+            }
+        }
+
+        if publish {
+            self.last.replace(reading.clone());
+        }
+        publish
+    }
 }
 
 impl BluetoothMeshModel<SensorServer> for Sensor {
@@ -87,15 +167,18 @@ impl BluetoothMeshModel<SensorServer> for Sensor {
 
     fn run<'run, C: BluetoothMeshModelContext<SensorServer> + 'run>(
         &'run mut self,
-        ctx: C,
+        mut ctx: C,
     ) -> Self::RunFuture<'_, C> {
         async move {
             loop {
                 if let Some(ticker) = self.ticker.as_mut() {
                     // When ticker is enabled, we emit sensor readings on each tick.
                     match select(ctx.receive(), ticker.next()).await {
-                        Either::First(data) => self.process(&data).await,
+                        Either::First(data) => self.process(&mut ctx, &data).await,
                         Either::Second(_) => match self.read().await {
+                            Ok(result) if !self.update(&result) => {
+                                defmt::trace!("Sensor reading unchanged, not publishing");
+                            }
                             Ok(result) => {
                                 defmt::info!("Read sensor data: {:?}", result);
                                 let message = SensorMessage::Status(SensorStatus::new(result));
@@ -116,7 +199,7 @@ impl BluetoothMeshModel<SensorServer> for Sensor {
                 } else {
                     // When ticker is disabled, we wait for commands.
                     let m = ctx.receive().await;
-                    self.process(&m).await;
+                    self.process(&mut ctx, &m).await;
                 }
             }
         }
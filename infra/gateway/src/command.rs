@@ -0,0 +1,343 @@
+//! Commands received from the cloud, and the reports of their delivery to the devices.
+//!
+//! Each command is identified by the correlation data of its MQTT message, and commands without
+//! one are reported without a correlation id. The delivery is reported on the [`STATUS_CHANNEL`]
+//! of the device: sent once forwarded to the mesh, or failed. Acknowledged messages are then
+//! reported as acked once the status reply of the device is received, or as timed out.
+//!
+//! Sensor Get and Generic Battery Get are not awaited: the device also publishes these statuses
+//! periodically, and a reply can't be told apart from them. They are only reported as sent, and
+//! their result arrives as telemetry.
+use crate::outbox::Frame;
+use btmesh_common::opcode::Opcode;
+use paho_mqtt as mqtt;
+use sensor_model::{
+    ContentType, GENERIC_ONOFF_GET, GENERIC_ONOFF_SET, GENERIC_ONOFF_STATUS, SENSOR_CADENCE_GET,
+    SENSOR_CADENCE_SET, SENSOR_CADENCE_STATUS, SENSOR_DESCRIPTOR_GET, SENSOR_DESCRIPTOR_STATUS,
+    SENSOR_SETTING_GET, SENSOR_SETTING_SET, SENSOR_SETTING_STATUS,
+};
+use serde::Serialize;
+use tokio::time::Instant;
+
+/// Channel of the delivery reports, published as `<channel>/<device>` like the telemetry, so
+/// that they are attributed to the device the command was sent to.
+pub const STATUS_CHANNEL: &str = "command-status";
+
+/// Acknowledged messages, and the opcode of their reply. Replies also published periodically
+/// are left out.
+const ACKNOWLEDGED: &[(Opcode, Opcode)] = &[
+    (GENERIC_ONOFF_GET, GENERIC_ONOFF_STATUS),
+    (GENERIC_ONOFF_SET, GENERIC_ONOFF_STATUS),
+    (SENSOR_DESCRIPTOR_GET, SENSOR_DESCRIPTOR_STATUS),
+    (SENSOR_CADENCE_GET, SENSOR_CADENCE_STATUS),
+    (SENSOR_CADENCE_SET, SENSOR_CADENCE_STATUS),
+    (SENSOR_SETTING_GET, SENSOR_SETTING_STATUS),
+    (SENSOR_SETTING_SET, SENSOR_SETTING_STATUS),
+];
+
+/// Opcode of the reply of the device to a message, if it is acknowledged.
+pub fn reply_opcode(opcode: Opcode) -> Option<Opcode> {
+    ACKNOWLEDGED
+        .iter()
+        .find(|(request, _)| *request == opcode)
+        .map(|(_, reply)| *reply)
+}
+
+/// A command received from the cloud.
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub topic: String,
+    pub content_type: ContentType,
+    pub payload: Vec<u8>,
+    /// Identifies the command in its delivery reports, if its message has correlation data.
+    pub correlation_id: Option<String>,
+}
+
+impl From<&mqtt::Message> for Command {
+    fn from(message: &mqtt::Message) -> Self {
+        let properties = message.properties();
+        let content_type = properties
+            .get_string(mqtt::PropertyCode::ContentType)
+            .and_then(|c| ContentType::from_mime(&c))
+            .unwrap_or_default();
+        let correlation_id = properties
+            .get_binary(mqtt::PropertyCode::CorrelationData)
+            .map(|data| String::from_utf8(data).unwrap_or_else(|e| hex::encode(e.into_bytes())));
+        Self {
+            topic: message.topic().to_string(),
+            content_type,
+            payload: message.payload().into(),
+            correlation_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum DeliveryStatus {
+    /// The command was forwarded to the mesh.
+    Sent,
+    /// The device replied to the command.
+    Acked,
+    /// The device did not reply to the command in time.
+    TimedOut,
+    /// The command could not be forwarded to the mesh.
+    Failed { error: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeliveryReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    pub device: String,
+    /// Address of the destination, unless the command could not be read.
+    pub address: Option<u16>,
+    #[serde(flatten)]
+    pub status: DeliveryStatus,
+}
+
+impl DeliveryReport {
+    /// Topic of the report, on the status channel of the device.
+    pub fn topic(&self) -> String {
+        format!("{STATUS_CHANNEL}/{}", self.device)
+    }
+
+    /// The report as a frame of the outbox, carrying the correlation data of the command.
+    pub fn to_frame(&self) -> Result<Frame, serde_json::Error> {
+        let mut frame = Frame::new(self.topic(), ContentType::Json, serde_json::to_vec(self)?);
+        frame.correlation_data = self.correlation_id.clone().map(String::into_bytes);
+        Ok(frame)
+    }
+
+    /// Identifies the command in the logs.
+    pub fn command(&self) -> &str {
+        self.correlation_id
+            .as_deref()
+            .unwrap_or("without correlation data")
+    }
+}
+
+/// A command waiting for the reply of its device.
+struct Pending {
+    correlation_id: Option<String>,
+    device: String,
+    address: u16,
+    reply: Opcode,
+    deadline: Instant,
+}
+
+impl Pending {
+    fn report(self, status: DeliveryStatus) -> DeliveryReport {
+        DeliveryReport {
+            correlation_id: self.correlation_id,
+            device: self.device,
+            address: Some(self.address),
+            status,
+        }
+    }
+}
+
+/// The commands waiting for the reply of their device, oldest first.
+#[derive(Default)]
+pub struct PendingAcks {
+    pending: Vec<Pending>,
+}
+
+impl PendingAcks {
+    /// Wait for the reply to a command sent to a device, until the deadline.
+    pub fn expect(
+        &mut self,
+        report: &DeliveryReport,
+        address: u16,
+        reply: Opcode,
+        deadline: Instant,
+    ) {
+        self.pending.push(Pending {
+            correlation_id: report.correlation_id.clone(),
+            device: report.device.clone(),
+            address,
+            reply,
+            deadline,
+        });
+    }
+
+    /// Acknowledge the oldest command a message from a device replies to.
+    pub fn received(&mut self, address: u16, opcode: Opcode) -> Option<DeliveryReport> {
+        let position = self
+            .pending
+            .iter()
+            .position(|p| p.address == address && p.reply == opcode)?;
+        Some(self.pending.remove(position).report(DeliveryStatus::Acked))
+    }
+
+    /// Time out the commands whose deadline passed.
+    pub fn expire(&mut self, now: Instant) -> Vec<DeliveryReport> {
+        let (expired, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|p| p.deadline <= now);
+        self.pending = pending;
+        expired
+            .into_iter()
+            .map(|p| p.report(DeliveryStatus::TimedOut))
+            .collect()
+    }
+
+    /// The earliest deadline of the pending commands.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|p| p.deadline).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sensor_model::{
+        GENERIC_BATTERY_GET, GENERIC_BATTERY_STATUS, GENERIC_ONOFF_SET_UNACKNOWLEDGED, SENSOR_GET,
+        SENSOR_STATUS,
+    };
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn report(correlation_id: &str) -> DeliveryReport {
+        DeliveryReport {
+            correlation_id: Some(correlation_id.to_string()),
+            device: "00ab".to_string(),
+            address: Some(0x00ab),
+            status: DeliveryStatus::Sent,
+        }
+    }
+
+    #[test]
+    fn test_reply_opcode() {
+        assert_eq!(reply_opcode(GENERIC_ONOFF_SET), Some(GENERIC_ONOFF_STATUS));
+        assert_eq!(reply_opcode(GENERIC_ONOFF_SET_UNACKNOWLEDGED), None);
+        assert_eq!(
+            reply_opcode(SENSOR_CADENCE_GET),
+            Some(SENSOR_CADENCE_STATUS)
+        );
+        // Their status is also published periodically
+        assert_eq!(reply_opcode(SENSOR_GET), None);
+        assert_eq!(reply_opcode(GENERIC_BATTERY_GET), None);
+    }
+
+    #[test]
+    fn test_command() {
+        let message = mqtt::MessageBuilder::new()
+            .topic("command/inbox/00ab/sensor")
+            .payload("{}")
+            .finalize();
+        let command = Command::from(&message);
+        assert_eq!(command.topic, "command/inbox/00ab/sensor");
+        assert_eq!(command.correlation_id, None);
+
+        let mut properties = mqtt::Properties::new();
+        properties
+            .push_binary(mqtt::PropertyCode::CorrelationData, &b"set-1"[..])
+            .unwrap();
+        let message = mqtt::MessageBuilder::new()
+            .topic("command/inbox/00ab/sensor")
+            .properties(properties)
+            .finalize();
+        let command = Command::from(&message);
+        assert_eq!(command.correlation_id.as_deref(), Some("set-1"));
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = report("set-1");
+        let frame = report.to_frame().unwrap();
+        assert_eq!(frame.topic, "command-status/00ab");
+        assert_eq!(frame.content_type, ContentType::Json.mime());
+        let message = frame.to_message().unwrap();
+        assert_eq!(
+            message
+                .properties()
+                .get_binary(mqtt::PropertyCode::CorrelationData),
+            Some(b"set-1".to_vec())
+        );
+        let payload: serde_json::Value = serde_json::from_slice(message.payload()).unwrap();
+        assert_eq!(
+            payload,
+            json!({"correlation_id": "set-1", "device": "00ab", "address": 0x00ab, "status": "sent"})
+        );
+
+        report.status = DeliveryStatus::Failed {
+            error: "no element at location 0x0101".to_string(),
+        };
+        let payload = serde_json::to_value(&report).unwrap();
+        assert_eq!(payload["status"], json!("failed"));
+        assert_eq!(payload["error"], json!("no element at location 0x0101"));
+        report.status = DeliveryStatus::TimedOut;
+        assert_eq!(
+            serde_json::to_value(&report).unwrap()["status"],
+            json!("timed-out")
+        );
+
+        // Commands without correlation data are reported without it
+        report.correlation_id = None;
+        let message = report.to_frame().unwrap().to_message().unwrap();
+        assert_eq!(
+            message
+                .properties()
+                .get_binary(mqtt::PropertyCode::CorrelationData),
+            None
+        );
+        let payload: serde_json::Value = serde_json::from_slice(message.payload()).unwrap();
+        assert_eq!(
+            payload,
+            json!({"device": "00ab", "address": 0x00ab, "status": "timed-out"})
+        );
+    }
+
+    #[test]
+    fn test_pending() {
+        let mut pending = PendingAcks::default();
+        let status = GENERIC_ONOFF_STATUS;
+        let now = Instant::now();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(pending.next_deadline(), None);
+
+        pending.expect(&report("set-1"), 0x00ab, status, now + timeout);
+        pending.expect(&report("set-2"), 0x00ab, status, now + timeout * 2);
+        pending.expect(
+            &report("get-1"),
+            0x00ab,
+            SENSOR_CADENCE_STATUS,
+            now + timeout,
+        );
+        assert_eq!(pending.next_deadline(), Some(now + timeout));
+
+        // Replies of other devices and models don't acknowledge the commands
+        assert_eq!(pending.received(0x00ac, status), None);
+        assert_eq!(pending.received(0x00ab, SENSOR_DESCRIPTOR_STATUS), None);
+        let acked = pending.received(0x00ab, status).unwrap();
+        assert_eq!(acked.correlation_id.as_deref(), Some("set-1"));
+        assert_eq!(acked.status, DeliveryStatus::Acked);
+
+        let expired = pending.expire(now + timeout);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].correlation_id.as_deref(), Some("get-1"));
+        assert_eq!(expired[0].status, DeliveryStatus::TimedOut);
+        assert_eq!(pending.next_deadline(), Some(now + timeout * 2));
+    }
+
+    #[test]
+    fn test_periodic_status() {
+        let mut pending = PendingAcks::default();
+        let reply = reply_opcode(SENSOR_CADENCE_GET).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        pending.expect(&report("get-1"), 0x00ab, reply, deadline);
+
+        // The statuses published periodically by the device don't acknowledge the command
+        for periodic in [SENSOR_STATUS, GENERIC_BATTERY_STATUS] {
+            assert_eq!(pending.received(0x00ab, periodic), None);
+        }
+        assert_eq!(
+            pending
+                .received(0x00ab, SENSOR_CADENCE_STATUS)
+                .unwrap()
+                .status,
+            DeliveryStatus::Acked
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! An in-memory mesh, for running the gateway without the BlueZ mesh daemon.
+//!
+//! Applications register and attach to it as they would to the daemon. What their nodes send is
+//! reported on the outbox returned by [`FakeMesh::new`], and messages are delivered to their
+//! elements and provisioner with [`FakeMesh::receive`] and [`FakeMesh::provisioned`].
+use crate::transport::{
+    ApplicationConfig, ElementEvent, MeshNode, MeshTransport, ProvisionerEvent, Registration,
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bluer::Uuid;
+use btmesh_models::foundation::configuration::ConfigurationMessage;
+use futures::stream::{self, StreamExt};
+use paho_mqtt as mqtt;
+use sensor_model::RawMessage;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc;
+
+/// What a node sent to the mesh.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sent {
+    Message {
+        element: usize,
+        destination: u16,
+        app_key: u16,
+        message: RawMessage,
+    },
+    DevKey {
+        element: usize,
+        destination: u16,
+        remote: bool,
+        net_index: u16,
+        message: RawMessage,
+    },
+    AppKey {
+        element: usize,
+        destination: u16,
+        app_key: u16,
+        net_index: u16,
+        update: bool,
+    },
+    AddNode(Uuid),
+}
+
+struct FakeApplication {
+    config: ApplicationConfig,
+    attached: bool,
+    elements: mpsc::UnboundedSender<ElementEvent>,
+    provisioner: Option<mpsc::UnboundedSender<ProvisionerEvent>>,
+}
+
+#[derive(Clone)]
+pub struct FakeMesh {
+    applications: Arc<Mutex<HashMap<String, FakeApplication>>>,
+    outbox: mpsc::UnboundedSender<Sent>,
+}
+
+impl FakeMesh {
+    /// Create a mesh, and the outbox of what its nodes send.
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<Sent>) {
+        let (outbox, sent) = mpsc::unbounded_channel();
+        let mesh = Self {
+            applications: Default::default(),
+            outbox,
+        };
+        (mesh, sent)
+    }
+
+    /// Whether an application is registered at the path, and attached to its node.
+    pub fn is_attached(&self, root: &str) -> bool {
+        let applications = self.applications.lock().unwrap();
+        applications.get(root).map_or(false, |app| app.attached)
+    }
+
+    /// Deliver a message to the elements of the application registered at the path.
+    pub fn receive(&self, root: &str, event: ElementEvent) -> anyhow::Result<()> {
+        let applications = self.applications.lock().unwrap();
+        let app = applications
+            .get(root)
+            .ok_or_else(|| anyhow!("no application registered at {root}"))?;
+        app.elements
+            .send(event)
+            .map_err(|_| anyhow!("application at {root} stopped receiving"))
+    }
+
+    /// Report the outcome of adding a node to the provisioner registered at the path.
+    pub fn provisioned(&self, root: &str, event: ProvisionerEvent) -> anyhow::Result<()> {
+        let applications = self.applications.lock().unwrap();
+        let provisioner = applications
+            .get(root)
+            .and_then(|app| app.provisioner.as_ref())
+            .ok_or_else(|| anyhow!("no provisioner registered at {root}"))?;
+        provisioner
+            .send(event)
+            .map_err(|_| anyhow!("provisioner at {root} stopped receiving"))
+    }
+}
+
+/// An MQTT client that never connects, to run the gateway without a broker. Publishing fails.
+pub fn disconnected_client() -> mqtt::AsyncClient {
+    let options = mqtt::CreateOptionsBuilder::new()
+        .server_uri("tcp://localhost:1883")
+        .persistence(mqtt::PersistenceType::None)
+        .finalize();
+    mqtt::AsyncClient::new(options).unwrap()
+}
+
+/// Unregisters the application when dropped.
+struct Unregister {
+    applications: Arc<Mutex<HashMap<String, FakeApplication>>>,
+    root: String,
+}
+
+impl Drop for Unregister {
+    fn drop(&mut self) {
+        self.applications.lock().unwrap().remove(&self.root);
+    }
+}
+
+#[async_trait]
+impl MeshTransport for FakeMesh {
+    type Node = FakeNode;
+
+    async fn register(
+        &self,
+        root: &str,
+        application: ApplicationConfig,
+    ) -> anyhow::Result<Registration> {
+        let mut applications = self.applications.lock().unwrap();
+        if applications.contains_key(root) {
+            return Err(anyhow!("an application is already registered at {root}"));
+        }
+
+        let (elements_tx, elements_rx) = mpsc::unbounded_channel();
+        let (provisioner_tx, provisioner_rx) = mpsc::unbounded_channel();
+        let provisioner = application.provisioner.map(|_| provisioner_tx);
+        applications.insert(
+            root.to_string(),
+            FakeApplication {
+                config: application,
+                attached: false,
+                elements: elements_tx,
+                provisioner,
+            },
+        );
+
+        let elements = stream::unfold(elements_rx, |mut rx| async move {
+            rx.recv().await.map(|event| (event, rx))
+        });
+        let provisioner = stream::unfold(provisioner_rx, |mut rx| async move {
+            rx.recv().await.map(|event| (event, rx))
+        });
+        let unregister = Unregister {
+            applications: self.applications.clone(),
+            root: root.to_string(),
+        };
+        Ok(Registration {
+            elements: elements.boxed(),
+            provisioner: provisioner.boxed(),
+            handle: Box::new(unregister),
+        })
+    }
+
+    async fn attach(&self, root: &str, _token: &str) -> anyhow::Result<Self::Node> {
+        let mut applications = self.applications.lock().unwrap();
+        let app = applications
+            .get_mut(root)
+            .ok_or_else(|| anyhow!("no application registered at {root}"))?;
+        app.attached = true;
+        Ok(FakeNode {
+            mesh: self.clone(),
+            root: root.to_string(),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct FakeNode {
+    mesh: FakeMesh,
+    root: String,
+}
+
+impl FakeNode {
+    /// Report what was sent from an element of the application, if it exists.
+    fn send_from(&self, element: usize, sent: Sent) -> anyhow::Result<()> {
+        let applications = self.mesh.applications.lock().unwrap();
+        let elements = applications
+            .get(&self.root)
+            .map_or(0, |app| app.config.elements.len());
+        if element >= elements {
+            return Err(anyhow!("no element {element} in application {}", self.root));
+        }
+        self.mesh
+            .outbox
+            .send(sent)
+            .map_err(|_| anyhow!("mesh outbox closed"))
+    }
+}
+
+#[async_trait]
+impl MeshNode for FakeNode {
+    async fn send(
+        &self,
+        message: &RawMessage,
+        element: usize,
+        destination: u16,
+        app_key: u16,
+    ) -> anyhow::Result<()> {
+        self.send_from(
+            element,
+            Sent::Message {
+                element,
+                destination,
+                app_key,
+                message: message.clone(),
+            },
+        )
+    }
+
+    async fn dev_key_send(
+        &self,
+        message: &ConfigurationMessage,
+        element: usize,
+        destination: u16,
+        remote: bool,
+        net_index: u16,
+    ) -> anyhow::Result<()> {
+        let message = RawMessage::from_message(message)?;
+        self.send_from(
+            element,
+            Sent::DevKey {
+                element,
+                destination,
+                remote,
+                net_index,
+                message,
+            },
+        )
+    }
+
+    async fn add_app_key(
+        &self,
+        element: usize,
+        destination: u16,
+        app_key: u16,
+        net_index: u16,
+        update: bool,
+    ) -> anyhow::Result<()> {
+        self.send_from(
+            element,
+            Sent::AppKey {
+                element,
+                destination,
+                app_key,
+                net_index,
+                update,
+            },
+        )
+    }
+
+    async fn add_node(&self, uuid: Uuid) -> anyhow::Result<()> {
+        self.send_from(0, Sent::AddNode(uuid))
+    }
+}
@@ -1,139 +1,148 @@
-use crate::utils::AttachRetry;
-use bluer::mesh::{application::Application, element::*, network::Network};
-use btmesh_models::{
-    self,
-    generic::{
-        battery::GenericBatteryClient,
-        onoff::{GenericOnOffClient, GenericOnOffServer},
-    },
-    Model,
+use crate::{
+    command::{reply_opcode, Command, DeliveryReport, DeliveryStatus, PendingAcks},
+    keys::AppKeys,
+    outbox::Frame,
+    routing::RoutingTable,
+    transport::{ElementEvent, MeshNode, MeshTransport},
+    utils::AttachRetry,
 };
-use dbus::Path;
+use btmesh_common::opcode::Opcode;
+use btmesh_models::Message;
 use futures::StreamExt;
-use paho_mqtt as mqtt;
 use sensor_model::*;
 use std::{sync::Arc, time::Duration};
 use tokio::{
     sync::{broadcast, mpsc},
-    time::sleep,
+    time::{sleep, sleep_until, Instant},
 };
 
-type Sensor = SensorClient;
+/// How long to wait for the reply of a device to an acknowledged command.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct Config {
     token: String,
+    /// Encoding of the messages published to the cloud.
+    encoding: ContentType,
+    /// Elements exposed by the gateway.
+    routes: RoutingTable,
+    /// Application keys, shared with the provisioner.
+    keys: Arc<AppKeys>,
 }
 
 impl Config {
-    pub fn new(token: String) -> Self {
-        Self { token }
+    pub fn new(
+        token: String,
+        encoding: ContentType,
+        routes: RoutingTable,
+        keys: Arc<AppKeys>,
+    ) -> Self {
+        Self {
+            token,
+            encoding,
+            routes,
+            keys,
+        }
+    }
+}
+
+/// Forward a command to a device, returning the opcode of the reply of the device if the
+/// command is acknowledged.
+async fn forward<N: MeshNode>(
+    node: &N,
+    config: &Config,
+    raw: &RawMessage,
+    address: u16,
+) -> Result<Option<Opcode>, anyhow::Error> {
+    let element = config
+        .routes
+        .element(raw.location)
+        .ok_or_else(|| anyhow::anyhow!("no element at location {:#06x}", raw.location))?;
+    let app_key = config.keys.key_for(address, raw);
+    node.send(raw, element, address, app_key).await?;
+    Ok(reply_opcode(raw.opcode()))
+}
+
+/// Hand a delivery report to the outbox, to be published like the telemetry.
+async fn publish_report(outbox: &mpsc::Sender<Frame>, report: &DeliveryReport) {
+    log::info!(
+        "Command {} for {}: {:?}",
+        report.command(),
+        report.device,
+        report.status
+    );
+    let frame = match report.to_frame() {
+        Ok(frame) => frame,
+        Err(e) => {
+            log::warn!("Error encoding delivery report: {:?}", e);
+            return;
+        }
+    };
+    if outbox.send(frame).await.is_err() {
+        log::warn!("Outbox closed, dropping delivery report");
     }
 }
 
-pub async fn run(
-    mesh: Network,
+pub async fn run<T: MeshTransport>(
+    mesh: T,
     config: Config,
-    mut commands: broadcast::Receiver<(String, Vec<u8>)>,
-    mqtt_client: mqtt::AsyncClient,
+    mut commands: broadcast::Receiver<Command>,
+    telemetry: mpsc::Sender<Frame>,
 ) -> Result<(), anyhow::Error> {
-    let (mut element_control, element_handle) = element_control(1);
-    let (app_tx, _app_rx) = mpsc::channel(1);
-
-    let root_path = Path::from("/gateway");
-    let app_path = Path::from(format!("{}/{}", root_path.clone(), "application"));
-
-    let front = Path::from(format!("{}/ele{}", root_path.clone(), "00"));
-    let left = Path::from(format!("{}/ele{}", root_path.clone(), "01"));
-    let right = Path::from(format!("{}/ele{}", root_path.clone(), "02"));
-
-    let front_loc = 0x0100;
-    let left_loc = 0x010D;
-    let right_loc = 0x010E;
-
-    let sim = Application {
-        path: app_path,
-        elements: vec![
-            Element {
-                path: front.clone(),
-                location: Some(front_loc),
-                models: vec![
-                    Arc::new(FromDrogue::new(GenericOnOffClient)),
-                    Arc::new(FromDrogue::new(GenericBatteryClient)),
-                    Arc::new(FromDrogue::new(Sensor::new())),
-                ],
-                control_handle: Some(element_handle.clone()),
-            },
-            Element {
-                path: left.clone(),
-                location: Some(left_loc),
-                models: vec![Arc::new(FromDrogue::new(GenericOnOffServer))],
-                control_handle: Some(element_handle.clone()),
-            },
-            Element {
-                path: right.clone(),
-                location: Some(right_loc),
-                models: vec![Arc::new(FromDrogue::new(GenericOnOffServer))],
-                control_handle: Some(element_handle),
-            },
-        ],
-        events_tx: app_tx,
-        provisioner: None,
-    };
+    let root_path = "/gateway";
 
-    let registered = mesh.application(root_path.clone(), sim).await?;
+    let mut registered = mesh
+        .register(root_path, config.routes.application())
+        .await?;
 
     let node = mesh
-        .attach_retry(10, Duration::from_secs(2), root_path.clone(), &config.token)
+        .attach_retry(10, Duration::from_secs(2), root_path, &config.token)
         .await?;
 
+    let mut acks = PendingAcks::default();
+
     log::info!("Starting gateway event loop");
     loop {
         tokio::select! {
-            evt = element_control.next() => {
+            evt = registered.elements.next() => {
                 match evt {
                     Some(msg) => {
                         match msg {
-                            ElementMessage::Received(received) => {
-                                match SensorClient::parse(&received.opcode, &received.parameters).map_err(|_| std::fmt::Error)? {
-                                    Some(message) => {
+                            ElementEvent::Received { app_key, src, location, opcode, parameters } => {
+                                match MeshEvent::parse(&opcode, &parameters) {
+                                    Ok(Some(message)) => {
                                         log::trace!("Received {:?}", message);
                                     },
-                                    None => {}
-                                }
-
-                                match GenericBatteryClient ::parse(&received.opcode, &received.parameters).map_err(|_| std::fmt::Error)? {
-                                    Some(message) => {
-                                        log::trace!("Received {:?}", message);
-                                    },
-                                    None => {}
+                                    Ok(None) => {}
+                                    Err(e) => {
+                                        log::warn!("Received malformed message: {:?}", e);
+                                    }
                                 }
-                                let mut opcode: heapless::Vec<u8, 16> = heapless::Vec::new();
-                                received.opcode.emit(&mut opcode).map_err(|_| std::fmt::Error)?;
-
-                                let mut parameters = Vec::new();
-                                parameters.extend_from_slice(&received.parameters);
-                                let message = RawMessage {
-                                    address: Some(u16::from_le_bytes(received.src.as_bytes())),
-                                    location: received.location.unwrap(),
-                                    opcode: opcode.to_vec(),
-                                    parameters,
+                                let mut message = match RawMessage::new(opcode, parameters.clone()) {
+                                    Ok(message) => message,
+                                    Err(e) => {
+                                        log::warn!("Not forwarding invalid message: {e}");
+                                        continue;
+                                    }
                                 };
-                                let data = serde_json::to_string(&message)?;
+                                let source = u16::from_le_bytes(src);
+                                config.keys.received(source, app_key);
+                                if let Some(report) = acks.received(source, opcode) {
+                                    publish_report(&telemetry, &report).await;
+                                }
+                                message.address = Some(source);
+                                message.location = location.unwrap();
+                                message.metadata.source = Some(source);
+                                let data = config.encoding.encode(&message)?;
 
-                                let src = received.src.as_bytes();
                                 let topic = format!("sensor/{:02x}{:02x}", src[0], src[1]);
-                                log::info!("Forwarding message with opcode {:?} and {} parameter bytes to {}!", received.opcode, received.parameters.len(), topic);
-
-                                let message = mqtt::Message::new(topic, data.as_bytes(), 1);
-                                if let Err(e) = mqtt_client.publish(message).await {
-                                    log::warn!(
-                                        "Error publishing events from device device: {:?}",
-                                        e
-                                    );
+                                log::info!("Forwarding message with opcode {:?} and {} parameter bytes to {}!", opcode, parameters.len(), topic);
+
+                                if telemetry.send(Frame::new(topic, config.encoding, data)).await.is_err() {
+                                    log::warn!("Outbox closed, dropping events from device");
                                 }
                             },
-                            ElementMessage::DevKey(received) => {
-                                log::info!("Gateway Received dev key message: {:?}", received);
+                            ElementEvent::DevKey { src, opcode, .. } => {
+                                log::info!("Gateway Received dev key message with opcode {:?} from {:02x?}", opcode, src);
                             }
                         }
                     },
@@ -142,42 +151,54 @@ pub async fn run(
             },
             command = commands.recv() => {
                 match command {
-                    Ok((topic, payload)) => {
-                        let mut parts = topic.rsplit("/");
-                        if let Some(channel) = parts.next() {
-                            if channel == "sensor" {
-                                log::info!("Got message on sensor channel");
-                                if let Some(device) = parts.next() {
-                                    log::info!("Command is for {}", device);
-                                    // Check if it's a device'y destination
-                                    if let Ok(command) = serde_json::from_slice(&payload[..]) {
-                                        let raw: RawMessage = command;
-                                        if let Some(address) = raw.address {
-                                            log::info!("Destination is {}", address);
-                                            let path = if raw.location == front_loc {
-                                                front.clone()
-                                            } else if raw.location == left_loc {
-                                                left.clone()
-                                            } else if raw.location == right_loc {
-                                                right.clone()
-                                            } else {
-                                                front.clone()
-                                            };
-                                            // TODO: Hmm, where to get this?
-                                            let app_key = 0;
-                                            match node.send(&raw, path, address, app_key).await {
-                                                Ok(_) => {
-                                                    log::info!("Forwarded message to device");
-                                                }
-                                                Err(e) => {
-                                                    log::warn!("Error forwarding message to device: {:?}", e);
-                                                }
-                                            }
-                                        }
-                                    }
+                    Ok(command) => {
+                        let mut parts = command.topic.rsplit('/');
+                        if parts.next() != Some("sensor") {
+                            continue;
+                        }
+                        log::info!("Got message on sensor channel");
+                        let device = match parts.next() {
+                            Some(device) => device.to_string(),
+                            None => continue,
+                        };
+                        let mut report = DeliveryReport {
+                            correlation_id: command.correlation_id,
+                            device,
+                            address: None,
+                            status: DeliveryStatus::Sent,
+                        };
+                        log::info!("Command {} is for {}", report.command(), report.device);
+                        let raw = match command.content_type.decode(&command.payload[..]) {
+                            Ok(raw) => raw,
+                            Err(e) => {
+                                report.status = DeliveryStatus::Failed { error: format!("invalid command: {e}") };
+                                publish_report(&telemetry, &report).await;
+                                continue;
+                            }
+                        };
+                        report.address = raw.address;
+                        let address = match raw.address {
+                            Some(address) => address,
+                            None => {
+                                report.status = DeliveryStatus::Failed { error: "no destination address".to_string() };
+                                publish_report(&telemetry, &report).await;
+                                continue;
+                            }
+                        };
+                        log::info!("Destination is {}", address);
+                        match forward(&node, &config, &raw, address).await {
+                            Ok(reply) => {
+                                log::info!("Forwarded message to device");
+                                if let Some(reply) = reply {
+                                    acks.expect(&report, address, reply, Instant::now() + ACK_TIMEOUT);
                                 }
                             }
+                            Err(e) => {
+                                log::warn!("Error forwarding message to device: {:?}", e);
+                                report.status = DeliveryStatus::Failed { error: e.to_string() };
+                            }
                         }
+                        publish_report(&telemetry, &report).await;
                     }
                     Err(_) => {
                         log::info!("Got error waiting for event");
@@ -185,6 +206,11 @@ pub async fn run(
                     }
                 }
             }
+            _ = sleep_until(acks.next_deadline().unwrap_or_else(Instant::now)), if acks.next_deadline().is_some() => {
+                for report in acks.expire(Instant::now()) {
+                    publish_report(&telemetry, &report).await;
+                }
+            }
         }
     }
 
@@ -194,3 +220,167 @@ pub async fn run(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fake::{FakeMesh, Sent},
+        keys,
+    };
+    use serde_json::json;
+
+    const ROOT: &str = "/gateway";
+
+    fn command(commands: &broadcast::Sender<Command>, raw: &RawMessage, correlation_id: &str) {
+        commands
+            .send(Command {
+                topic: "command/inbox/00ab/sensor".to_string(),
+                content_type: ContentType::Json,
+                payload: ContentType::Json.encode(raw).unwrap(),
+                correlation_id: Some(correlation_id.to_string()),
+            })
+            .unwrap();
+    }
+
+    /// The next delivery report handed to the outbox.
+    async fn report(frames: &mut mpsc::Receiver<Frame>) -> serde_json::Value {
+        let frame = frames.recv().await.unwrap();
+        assert_eq!(frame.topic, "command-status/00ab");
+        serde_json::from_slice(&frame.payload).unwrap()
+    }
+
+    /// A Generic OnOff Status from the front element of the device.
+    fn onoff_status() -> ElementEvent {
+        ElementEvent::Received {
+            app_key: 1,
+            src: [0xab, 0x00],
+            location: Some(MicrobitComposition::FRONT.location()),
+            opcode: GENERIC_ONOFF_STATUS,
+            parameters: vec![0x01],
+        }
+    }
+
+    async fn start(
+        mesh: &FakeMesh,
+        keys: Arc<AppKeys>,
+    ) -> (
+        broadcast::Sender<Command>,
+        mpsc::Receiver<Frame>,
+        tokio::task::JoinHandle<Result<(), anyhow::Error>>,
+    ) {
+        let (commands_tx, commands) = broadcast::channel(10);
+        let (telemetry, frames) = mpsc::channel(10);
+        let gateway = tokio::spawn(run(
+            mesh.clone(),
+            Config::new(
+                "token".to_string(),
+                ContentType::Json,
+                RoutingTable::default(),
+                keys,
+            ),
+            commands,
+            telemetry,
+        ));
+        while !mesh.is_attached(ROOT) {
+            sleep(Duration::from_millis(10)).await;
+        }
+        (commands_tx, frames, gateway)
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let (mesh, mut sent) = FakeMesh::new();
+        let keys = Arc::new(keys::tests::keys());
+        let (commands_tx, mut frames, gateway) = start(&mesh, keys.clone()).await;
+
+        // Generic OnOff Set Unacknowledged, for the left button
+        let mut raw = RawMessage::new(GENERIC_ONOFF_SET_UNACKNOWLEDGED, vec![0x01, 0x00]).unwrap();
+        raw.address = Some(0x00ab);
+        raw.location = MicrobitComposition::LEFT.location();
+        command(&commands_tx, &raw, "set-1");
+        assert_eq!(
+            sent.recv().await,
+            Some(Sent::Message {
+                element: 1,
+                destination: 0x00ab,
+                app_key: 0,
+                message: raw.clone(),
+            })
+        );
+        assert_eq!(
+            report(&mut frames).await,
+            json!({"correlation_id": "set-1", "device": "00ab", "address": 0x00ab, "status": "sent"})
+        );
+
+        // Nothing is sent from another element when none is at the location
+        let mut unknown = raw.clone();
+        unknown.location = 0x0101;
+        command(&commands_tx, &unknown, "set-2");
+        command(&commands_tx, &raw, "set-3");
+        assert!(matches!(
+            sent.recv().await,
+            Some(Sent::Message { element: 1, .. })
+        ));
+        let failed = report(&mut frames).await;
+        assert_eq!(failed["status"], json!("failed"));
+        assert_eq!(failed["error"], json!("no element at location 0x0101"));
+        assert_eq!(report(&mut frames).await["status"], json!("sent"));
+
+        // Telemetry is handed to the outbox, and commands follow the key it was received with
+        mesh.receive(ROOT, onoff_status()).unwrap();
+        let frame = frames.recv().await.unwrap();
+        assert_eq!(frame.topic, "sensor/ab00");
+        assert_eq!(frame.content_type, ContentType::Json.mime());
+        let status = ContentType::Json.decode(&frame.payload[..]).unwrap();
+        assert_eq!(status.address, Some(0x00ab));
+        assert_eq!(status.location, MicrobitComposition::FRONT.location());
+        assert_eq!(keys.key_for(0x00ab, &raw), 1);
+        command(&commands_tx, &raw, "set-4");
+        assert!(matches!(
+            sent.recv().await,
+            Some(Sent::Message { app_key: 1, .. })
+        ));
+
+        drop(commands_tx);
+        gateway.await.unwrap().unwrap();
+        assert!(!mesh.is_attached(ROOT));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acknowledged() {
+        let (mesh, mut sent) = FakeMesh::new();
+        let (commands_tx, mut frames, gateway) = start(&mesh, Arc::new(keys::tests::keys())).await;
+
+        // Generic OnOff Set, for the display
+        let mut raw = RawMessage::new(GENERIC_ONOFF_SET, vec![0x01, 0x00]).unwrap();
+        raw.address = Some(0x00ab);
+        raw.location = MicrobitComposition::FRONT.location();
+
+        // Acked once the status of the device is received, before it is forwarded as telemetry
+        command(&commands_tx, &raw, "set-1");
+        assert!(matches!(sent.recv().await, Some(Sent::Message { .. })));
+        assert_eq!(report(&mut frames).await["status"], json!("sent"));
+        mesh.receive(ROOT, onoff_status()).unwrap();
+        let frame = frames.recv().await.unwrap();
+        assert_eq!(frame.correlation_data.as_deref(), Some(&b"set-1"[..]));
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&frame.payload).unwrap(),
+            json!({"correlation_id": "set-1", "device": "00ab", "address": 0x00ab, "status": "acked"})
+        );
+        assert_eq!(frames.recv().await.unwrap().topic, "sensor/ab00");
+
+        // Timed out without a reply
+        let start = Instant::now();
+        command(&commands_tx, &raw, "set-2");
+        assert!(matches!(sent.recv().await, Some(Sent::Message { .. })));
+        assert_eq!(report(&mut frames).await["status"], json!("sent"));
+        let timed_out = report(&mut frames).await;
+        assert_eq!(timed_out["correlation_id"], json!("set-2"));
+        assert_eq!(timed_out["status"], json!("timed-out"));
+        assert!(start.elapsed() >= ACK_TIMEOUT);
+
+        drop(commands_tx);
+        gateway.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+//! Application keys of the mesh, and the keys the nodes are bound to.
+//!
+//! Devices are only reachable with the application keys their models are bound to, so that
+//! separate teams can share a mesh without controlling each other's devices. The keys are read
+//! from a TOML file, such as:
+//!
+//! ```toml
+//! # Key of the devices not listed under another key
+//! default = 0
+//!
+//! [[app-key]]
+//! index = 0
+//! name = "default"
+//!
+//! [[app-key]]
+//! index = 1
+//! name = "team-a"
+//! devices = ["6f3b9d2e4c1a4e7b9a0d5c8e2f1b7a34"]
+//! ```
+//!
+//! The keys must exist in the keyring of the mesh daemon. The provisioner binds the models of
+//! each device to its key, and the gateway also learns the key of a node from its messages.
+//! Neither is persisted: after a restart, the gateway relearns the key of a node from its next
+//! message, and uses the default key until then.
+use anyhow::{anyhow, Context};
+use bluer::Uuid;
+use btmesh_common::ModelIdentifier;
+use btmesh_models::{
+    generic::{battery::GENERIC_BATTERY_SERVER, onoff::GENERIC_ONOFF_SERVER},
+    sensor::SENSOR_SETUP_SERVER,
+    Message,
+};
+use sensor_model::{MeshEvent, RawMessage};
+use serde::Deserialize;
+use std::{collections::HashMap, path::Path, sync::RwLock};
+
+/// Largest application key index, indexes are 12 bits long.
+const MAX_INDEX: u16 = 0x0fff;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AppKey {
+    pub index: u16,
+    pub name: String,
+    /// UUIDs of the devices bound to the key when provisioned.
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct AppKeysFile {
+    default: u16,
+    #[serde(rename = "app-key")]
+    keys: Vec<AppKey>,
+}
+
+/// Keys a node is known to be bound to.
+#[derive(Debug, Default)]
+struct NodeKeys {
+    /// Keys the models of the node were bound to when configured.
+    models: Vec<(ModelIdentifier, u16)>,
+    /// Key of the last message received from the node.
+    received: Option<u16>,
+}
+
+pub struct AppKeys {
+    keys: Vec<AppKey>,
+    default: u16,
+    devices: HashMap<Uuid, u16>,
+    nodes: RwLock<HashMap<u16, NodeKeys>>,
+}
+
+impl AppKeys {
+    pub fn new(keys: Vec<AppKey>, default: u16) -> anyhow::Result<Self> {
+        let mut devices = HashMap::new();
+        for (position, key) in keys.iter().enumerate() {
+            if key.index > MAX_INDEX {
+                return Err(anyhow!(
+                    "index {} of key '{}' exceeds {MAX_INDEX}",
+                    key.index,
+                    key.name
+                ));
+            }
+            if let Some(other) = keys[..position].iter().find(|k| k.index == key.index) {
+                return Err(anyhow!(
+                    "keys '{}' and '{}' share index {}",
+                    other.name,
+                    key.name,
+                    key.index
+                ));
+            }
+            for device in &key.devices {
+                let uuid = Uuid::parse_str(device)
+                    .with_context(|| format!("invalid device '{device}' of key '{}'", key.name))?;
+                if devices.insert(uuid, key.index).is_some() {
+                    return Err(anyhow!("device '{device}' is listed under several keys"));
+                }
+            }
+        }
+        if !keys.iter().any(|k| k.index == default) {
+            return Err(anyhow!("default key {default} is not declared"));
+        }
+        Ok(Self {
+            keys,
+            default,
+            devices,
+            nodes: Default::default(),
+        })
+    }
+
+    /// Read the keys from a TOML file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read app keys from {}", path.display()))?;
+        let file: AppKeysFile = toml::from_str(&content)
+            .with_context(|| format!("failed to parse app keys from {}", path.display()))?;
+        Self::new(file.keys, file.default)
+    }
+
+    pub fn keys(&self) -> &[AppKey] {
+        &self.keys
+    }
+
+    /// Key to bind the models of a device to, when provisioning it.
+    pub fn key_for_device(&self, uuid: &Uuid) -> u16 {
+        self.devices.get(uuid).copied().unwrap_or(self.default)
+    }
+
+    /// Record that a model of a node was bound to a key.
+    pub fn bind(&self, address: u16, model: ModelIdentifier, key: u16) {
+        let mut nodes = self.nodes.write().unwrap();
+        let models = &mut nodes.entry(address).or_default().models;
+        match models.iter_mut().find(|(m, _)| *m == model) {
+            Some((_, bound)) => *bound = key,
+            None => models.push((model, key)),
+        }
+    }
+
+    /// Record the key of a message received from a node.
+    pub fn received(&self, address: u16, key: u16) {
+        if !self.keys.iter().any(|k| k.index == key) {
+            log::warn!("Node {address:04x} uses undeclared app key {key}");
+        }
+        self.nodes
+            .write()
+            .unwrap()
+            .entry(address)
+            .or_default()
+            .received = Some(key);
+    }
+
+    /// Forget the keys of a node, once it is reset.
+    pub fn forget(&self, address: u16) {
+        self.nodes.write().unwrap().remove(&address);
+    }
+
+    /// Key to send a message to a node with: the key its target model is bound to, or else the
+    /// key the node last used, or else the default key.
+    pub fn key_for(&self, address: u16, message: &RawMessage) -> u16 {
+        let nodes = self.nodes.read().unwrap();
+        let node = match nodes.get(&address) {
+            Some(node) => node,
+            None => return self.default,
+        };
+        let bound = target_model(message)
+            .and_then(|model| node.models.iter().find(|(m, _)| *m == model))
+            .map(|(_, key)| *key);
+        bound
+            .or(node.received)
+            .or_else(|| node.models.first().map(|(_, key)| *key))
+            .unwrap_or(self.default)
+    }
+}
+
+impl Default for AppKeys {
+    /// A single key, of index 0, for all devices.
+    fn default() -> Self {
+        let key = AppKey {
+            index: 0,
+            name: "default".to_string(),
+            devices: Vec::new(),
+        };
+        Self::new(vec![key], 0).unwrap()
+    }
+}
+
+/// The model of the micro:bit handling a message sent to it.
+fn target_model(message: &RawMessage) -> Option<ModelIdentifier> {
+    match MeshEvent::parse(&message.opcode(), message.parameters()) {
+        Ok(Some(MeshEvent::OnOff(_))) => Some(GENERIC_ONOFF_SERVER),
+        Ok(Some(MeshEvent::Battery(_))) => Some(GENERIC_BATTERY_SERVER),
+        Ok(Some(MeshEvent::Sensor(_) | MeshEvent::SensorQuery(_) | MeshEvent::SensorSetup(_))) => {
+            Some(SENSOR_SETUP_SERVER)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use btmesh_common::opcode::Opcode;
+
+    /// Device bound to the `team-a` key of index 1.
+    pub(crate) const TEAM_DEVICE: &str = "6f3b9d2e4c1a4e7b9a0d5c8e2f1b7a34";
+
+    const KEYS: &str = r#"
+        default = 0
+
+        [[app-key]]
+        index = 0
+        name = "default"
+
+        [[app-key]]
+        index = 1
+        name = "team-a"
+        devices = ["6f3b9d2e4c1a4e7b9a0d5c8e2f1b7a34"]
+    "#;
+
+    /// The keys of index 0, the default one, and 1, for [`TEAM_DEVICE`].
+    pub(crate) fn keys() -> AppKeys {
+        let file: AppKeysFile = toml::from_str(KEYS).unwrap();
+        AppKeys::new(file.keys, file.default).unwrap()
+    }
+
+    fn onoff_set() -> RawMessage {
+        RawMessage::new(Opcode::TwoOctet(0x82, 0x03), vec![0x01, 0x00]).unwrap()
+    }
+
+    #[test]
+    fn test_devices() {
+        let keys = keys();
+        assert_eq!(keys.keys().len(), 2);
+        let team = Uuid::parse_str("6f3b9d2e-4c1a-4e7b-9a0d-5c8e2f1b7a34").unwrap();
+        assert_eq!(keys.key_for_device(&team), 1);
+        assert_eq!(keys.key_for_device(&Uuid::nil()), 0);
+    }
+
+    #[test]
+    fn test_bindings() {
+        let keys = keys();
+        let set = onoff_set();
+        assert_eq!(keys.key_for(0x00ab, &set), 0);
+
+        // Learned from the messages of the node
+        keys.received(0x00ab, 1);
+        assert_eq!(keys.key_for(0x00ab, &set), 1);
+        assert_eq!(keys.key_for(0x00ac, &set), 0);
+
+        // Bindings of the target model take precedence
+        keys.bind(0x00ac, GENERIC_BATTERY_SERVER, 1);
+        keys.bind(0x00ac, GENERIC_ONOFF_SERVER, 0);
+        keys.received(0x00ac, 1);
+        assert_eq!(keys.key_for(0x00ac, &set), 0);
+        let unknown = RawMessage::new(Opcode::TwoOctet(0x82, 0xff), Vec::new()).unwrap();
+        assert_eq!(keys.key_for(0x00ac, &unknown), 1);
+
+        keys.forget(0x00ac);
+        assert_eq!(keys.key_for(0x00ac, &set), 0);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let key = |index, name: &str, devices: &[&str]| AppKey {
+            index,
+            name: name.to_string(),
+            devices: devices.iter().map(|d| d.to_string()).collect(),
+        };
+        assert!(AppKeys::new(vec![key(0, "a", &[])], 1).is_err());
+        assert!(AppKeys::new(vec![key(0x1000, "a", &[])], 0x1000).is_err());
+        assert!(AppKeys::new(vec![key(0, "a", &[]), key(0, "b", &[])], 0).is_err());
+        assert!(AppKeys::new(vec![key(0, "a", &["not-a-uuid"])], 0).is_err());
+
+        let device = TEAM_DEVICE;
+        let error = AppKeys::new(vec![key(0, "a", &[device]), key(1, "b", &[device])], 0)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!("device '{device}' is listed under several keys")
+        );
+    }
+}
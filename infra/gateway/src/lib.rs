@@ -1,5 +1,11 @@
 #![feature(generic_associated_types)]
+pub mod command;
+pub mod fake;
 pub mod gateway;
+pub mod keys;
 pub mod node_configurator;
+pub mod outbox;
 pub mod provisioner;
+pub mod routing;
+pub mod transport;
 pub mod utils;
@@ -2,11 +2,23 @@
 
 use clap::Parser;
 use clap_num::maybe_hex;
-use eclipsecon_gateway::{gateway, provisioner};
+use eclipsecon_gateway::{
+    command::Command,
+    gateway,
+    keys::AppKeys,
+    outbox::{self, Outbox},
+    provisioner,
+    routing::RoutingTable,
+    transport::BluerTransport,
+};
 use paho_mqtt as mqtt;
 use rand::{rngs::OsRng, seq::SliceRandom};
-use std::time::Duration;
-use tokio::{signal, sync::broadcast};
+use sensor_model::ContentType;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    signal,
+    sync::{broadcast, mpsc},
+};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -31,6 +43,24 @@ struct Args {
     provisioner_token: Option<String>,
     #[clap(long, parse(try_from_str=maybe_hex))]
     provisioner_start_address: Option<u16>,
+    /// Encoding of the messages published to the cloud: json, cbor or postcard. Commands are
+    /// decoded according to their content type.
+    #[clap(long, env, default_value = "json")]
+    encoding: ContentType,
+    /// TOML file describing the elements exposed by the gateway, their locations and models.
+    /// Defaults to the elements of the micro:bit.
+    #[clap(long, env)]
+    elements: Option<PathBuf>,
+    /// TOML file declaring the application keys of the mesh, and the devices bound to each.
+    /// Defaults to a single key, of index 0, for all devices.
+    #[clap(long, env)]
+    app_keys: Option<PathBuf>,
+    /// File keeping the telemetry received while the broker is unreachable, until published.
+    #[clap(long, env, default_value = "outbox")]
+    outbox: PathBuf,
+    /// Number of messages kept in the outbox, the oldest ones are dropped beyond it.
+    #[clap(long, env, default_value = "10000")]
+    outbox_capacity: usize,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -39,6 +69,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     console_subscriber::init();
     let args = Args::parse();
 
+    let routes = match &args.elements {
+        Some(path) => RoutingTable::load(path)?,
+        None => RoutingTable::default(),
+    };
+    let keys = Arc::new(match &args.app_keys {
+        Some(path) => AppKeys::load(path)?,
+        None => AppKeys::default(),
+    });
+    let outbox = Outbox::open(&args.outbox, args.outbox_capacity)?;
+
     let mqtt_uri = args.drogue_mqtt_uri;
 
     log::info!("Connecting to: {}", mqtt_uri);
@@ -77,26 +117,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     let conn_opts = conn_opts.finalize();
 
-    mqtt_client.set_disconnected_callback(|c, _, _| {
-        log::info!("Disconnected");
-        let t = c.reconnect();
-        if let Err(_e) = t.wait_for(Duration::from_secs(10)) {
-            //log::warn!("Error reconnecting to broker ({:?}), exiting", e);
-            std::process::exit(1);
-        }
+    // Telemetry is kept in the outbox until the client reconnects
+    mqtt_client.set_disconnected_callback(|c, _, reason| {
+        log::warn!("Disconnected by the broker ({:?}), reconnecting", reason);
+        let _ = c.reconnect();
     });
 
-    mqtt_client.set_connection_lost_callback(|c| {
-        log::info!("Connection lost");
-        let t = c.reconnect();
-        if let Err(e) = t.wait_for(Duration::from_secs(10)) {
-            log::warn!("Error reconnecting to broker ({:?}), exiting", e);
-            std::process::exit(1);
-        }
+    mqtt_client.set_connection_lost_callback(|_| {
+        log::warn!("Connection lost, reconnecting");
     });
 
-    mqtt_client.set_connected_callback(|_| {
+    // The session starts clean, so the subscription is renewed on each connection
+    mqtt_client.set_connected_callback(|c| {
         log::info!("Gateway connected");
+        let _ = c.subscribe("command/inbox/#", 1);
     });
 
     mqtt_client.connect(conn_opts).await?;
@@ -106,9 +140,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     log::info!("Subscribed to commands");
 
     let session = bluer::Session::new().await?;
-    let mesh = session.mesh().await?;
+    let mesh = BluerTransport::new(session.mesh().await?);
 
     let (commands_tx, _) = broadcast::channel(10);
+    let (telemetry, frames) = mpsc::channel(100);
 
     let mut tasks = Vec::new();
     if let Some(token) = args.provisioner_token {
@@ -126,7 +161,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         );
         tasks.push(tokio::spawn(provisioner::run(
             mesh.clone(),
-            provisioner::Config::new(token, start_address),
+            provisioner::Config::new(token, start_address, keys.clone()),
             commands_tx.subscribe(),
             mqtt_client.clone(),
         )));
@@ -134,11 +169,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tasks.push(tokio::spawn(gateway::run(
         mesh,
-        gateway::Config::new(args.token),
+        gateway::Config::new(args.token, args.encoding, routes, keys),
         commands_tx.subscribe(),
-        mqtt_client,
+        telemetry,
     )));
 
+    tasks.push(tokio::spawn(outbox::run(outbox, frames, mqtt_client)));
+
     log::info!("Gateway ready. Press Ctrl+C to quit.");
 
     tasks.push(tokio::spawn(async move {
@@ -151,12 +188,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
                 command = mqtt_commands.recv() => {
                     if let Ok(Some(command)) = command {
-                        let topic = command.topic().to_string();
-                        let payload : Vec<u8>= command.payload().into();
+                        let command = Command::from(&command);
                         if log::log_enabled!(log::Level::Info) {
-                            log::info!("Received command: {topic} / {}", String::from_utf8_lossy(&payload) );
+                            log::info!(
+                                "Received {} command {}: {} / {}",
+                                command.content_type,
+                                command
+                                    .correlation_id
+                                    .as_deref()
+                                    .unwrap_or("without correlation data"),
+                                command.topic,
+                                String::from_utf8_lossy(&command.payload)
+                            );
                         }
-                        commands_tx.send((topic, payload))
+                        commands_tx.send(command)
                             .map_err(|err| {
                                 log::warn!("Failed to queue command: {err}");
                                 err
@@ -1,10 +1,8 @@
-use bluer::{
-    mesh::{
-        element::{ElementControl, ElementMessage},
-        node::Node,
-    },
-    Uuid,
+use crate::{
+    keys::AppKeys,
+    transport::{ElementEvent, MeshNode},
 };
+use bluer::{mesh::node::Node, Uuid};
 use btmesh_common::address::LabelUuid;
 use btmesh_models::{
     foundation::configuration::{
@@ -16,22 +14,21 @@ use btmesh_models::{
     sensor::SENSOR_SETUP_SERVER,
 };
 use btmesh_operator::{BtMeshDeviceState, BtMeshEvent};
-use dbus::Path;
 use paho_mqtt as mqtt;
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 use tokio::sync::mpsc::Receiver;
 
 use crate::provisioner::{NodeConfiguration, NodeConfigurationMessage};
 use btmesh_models::Model;
-use futures::StreamExt;
+use futures::{stream::BoxStream, StreamExt};
 
-async fn send_recv(
-    node: &Node,
-    element_control: &mut ElementControl,
-    msg: NodeConfiguration<'_>,
+async fn send_recv<N: MeshNode>(
+    node: &N,
+    element_control: &mut BoxStream<'static, ElementEvent>,
+    msg: NodeConfiguration,
 ) -> Result<(), anyhow::Error> {
     // TODO appkey?
-    node.dev_key_send(&msg.message, msg.path.clone(), msg.address, true, 0)
+    node.dev_key_send(&msg.message, msg.element, msg.address, true, 0)
         .await?;
     let mut retries = 0;
     loop {
@@ -44,13 +41,15 @@ async fn send_recv(
         let response = tokio::time::timeout(Duration::from_secs(10), element_control.next()).await;
         match response {
             Ok(Some(msg)) => match msg {
-                ElementMessage::Received(received) => {
-                    log::info!("Received element message: {:?}", received);
+                ElementEvent::Received { .. } => {
+                    log::info!("Received element message: {:?}", msg);
                 }
-                ElementMessage::DevKey(received) => {
-                    log::info!("Received devkey message with opcode {:?}", received.opcode);
+                ElementEvent::DevKey {
+                    opcode, parameters, ..
+                } => {
+                    log::info!("Received devkey message with opcode {:?}", opcode);
                     // TODO parse the message and make sure we got the right one (and indication of success) before continuing
-                    match ConfigurationClient::parse(&received.opcode, &received.parameters)
+                    match ConfigurationClient::parse(&opcode, &parameters)
                         .map_err(|_| std::fmt::Error)?
                     {
                         Some(message) => {
@@ -66,7 +65,7 @@ async fn send_recv(
             }
             Err(_) => {
                 log::info!("Timeout waiting for the configuration message repsone");
-                node.dev_key_send(&msg.message, msg.path.clone(), msg.address, true, 0)
+                node.dev_key_send(&msg.message, msg.element, msg.address, true, 0)
                     .await?;
                 retries += 1;
             }
@@ -74,55 +73,59 @@ async fn send_recv(
     }
 }
 
-async fn configure(
-    node: &Node,
-    element_control: &mut ElementControl,
-    element_path: &Path<'_>,
-    _uuid: &str,
+async fn configure<N: MeshNode>(
+    node: &N,
+    element_control: &mut BoxStream<'static, ElementEvent>,
+    element: usize,
+    keys: &AppKeys,
+    app_key: u16,
     unicast: u16,
 ) -> Result<(), anyhow::Error> {
-    log::info!("Add app key");
-    node.add_app_key(element_path.clone(), unicast, 0, 0, false)
+    log::info!("Add app key {}", app_key);
+    node.add_app_key(element, unicast, app_key, 0, false)
         .await?;
 
     log::info!("Bind sensor server");
-    let msg = Node::bind_create(unicast, 0, SENSOR_SETUP_SERVER)?;
+    let msg = Node::bind_create(unicast, app_key, SENSOR_SETUP_SERVER)?;
     send_recv(
         node,
         element_control,
         NodeConfiguration {
             message: msg,
-            path: element_path.clone(),
+            element,
             address: unicast,
         },
     )
     .await?;
+    keys.bind(unicast, SENSOR_SETUP_SERVER, app_key);
 
     log::info!("Bind onoff server");
-    let msg = Node::bind_create(unicast, 0, GENERIC_ONOFF_SERVER)?;
+    let msg = Node::bind_create(unicast, app_key, GENERIC_ONOFF_SERVER)?;
     send_recv(
         node,
         element_control,
         NodeConfiguration {
             message: msg,
-            path: element_path.clone(),
+            element,
             address: unicast,
         },
     )
     .await?;
+    keys.bind(unicast, GENERIC_ONOFF_SERVER, app_key);
 
     log::info!("Bind battery server");
-    let msg = Node::bind_create(unicast, 0, GENERIC_BATTERY_SERVER)?;
+    let msg = Node::bind_create(unicast, app_key, GENERIC_BATTERY_SERVER)?;
     send_recv(
         node,
         element_control,
         NodeConfiguration {
             message: msg,
-            path: element_path.clone(),
+            element,
             address: unicast,
         },
     )
     .await?;
+    keys.bind(unicast, GENERIC_BATTERY_SERVER, app_key);
 
     let label = LabelUuid::new(Uuid::parse_str("f0bfd803cde184133096f003ea4a3dc2")?.into_bytes())
         .map_err(|_| std::fmt::Error)?;
@@ -132,7 +135,7 @@ async fn configure(
     let msg = Node::pub_set_create(
         unicast,
         pub_address,
-        0,
+        app_key,
         PublishPeriod::new(4, Resolution::Seconds1),
         PublishRetransmit::from(0),
         SENSOR_SETUP_SERVER,
@@ -142,7 +145,7 @@ async fn configure(
         element_control,
         NodeConfiguration {
             message: msg,
-            path: element_path.clone(),
+            element,
             address: unicast,
         },
     )
@@ -151,7 +154,7 @@ async fn configure(
     let msg = Node::pub_set_create(
         unicast,
         pub_address,
-        0,
+        app_key,
         PublishPeriod::new(60, Resolution::Seconds1),
         PublishRetransmit::from(0),
         GENERIC_BATTERY_SERVER,
@@ -161,7 +164,7 @@ async fn configure(
         element_control,
         NodeConfiguration {
             message: msg,
-            path: element_path.clone(),
+            element,
             address: unicast,
         },
     )
@@ -169,12 +172,13 @@ async fn configure(
     Ok(())
 }
 
-pub async fn run<'a>(
+pub async fn run<N: MeshNode>(
     mut config_rx: Receiver<NodeConfigurationMessage>,
     mqtt_client: mqtt::AsyncClient,
-    element_path: Path<'a>,
-    mut element_control: ElementControl,
-    node: Node,
+    element: usize,
+    mut element_control: BoxStream<'static, ElementEvent>,
+    node: N,
+    keys: Arc<AppKeys>,
 ) -> Result<(), anyhow::Error> {
     loop {
         match config_rx.recv().await {
@@ -182,24 +186,31 @@ pub async fn run<'a>(
                 NodeConfigurationMessage::Configure(uuid, unicast) => {
                     log::info!("Configuring node {:?} (address {:#04x})", uuid, unicast);
 
+                    let app_key = keys.key_for_device(&uuid);
                     let uuid = uuid.as_simple().to_string();
-                    let status =
-                        match configure(&node, &mut element_control, &element_path, &uuid, unicast)
-                            .await
-                        {
-                            Ok(_) => BtMeshEvent {
-                                status: BtMeshDeviceState::Provisioned {
-                                    device: uuid.clone(),
-                                    address: unicast,
-                                },
+                    let status = match configure(
+                        &node,
+                        &mut element_control,
+                        element,
+                        &keys,
+                        app_key,
+                        unicast,
+                    )
+                    .await
+                    {
+                        Ok(_) => BtMeshEvent {
+                            status: BtMeshDeviceState::Provisioned {
+                                device: uuid.clone(),
+                                address: unicast,
                             },
-                            Err(e) => BtMeshEvent {
-                                status: BtMeshDeviceState::Provisioning {
-                                    device: uuid.clone(),
-                                    error: Some(e.to_string()),
-                                },
+                        },
+                        Err(e) => BtMeshEvent {
+                            status: BtMeshDeviceState::Provisioning {
+                                device: uuid.clone(),
+                                error: Some(e.to_string()),
                             },
-                        };
+                        },
+                    };
 
                     log::info!(
                         "Finished configuring {:?} assigned address {:04x}. Status: {:?}",
@@ -224,11 +235,12 @@ pub async fn run<'a>(
                     let msg = ConfigurationMessage::from(NodeResetMessage::Reset);
                     let msg = NodeConfiguration {
                         message: msg,
-                        path: element_path.clone(),
+                        element,
                         address,
                     };
 
                     if let Ok(_) = send_recv(&node, &mut element_control, msg).await {
+                        keys.forget(address);
                         let status = BtMeshEvent {
                             status: BtMeshDeviceState::Reset {
                                 error,
@@ -0,0 +1,394 @@
+//! Telemetry waiting to be published, kept on disk while the broker is unreachable.
+//!
+//! Frames received from the mesh, and the delivery reports of the commands, are published as they arrive while the gateway is connected.
+//! Otherwise they are appended to a file, along with the time they were received, and published
+//! in order once the connection is back. The outbox is bounded: when full, the oldest frames are
+//! dropped. A frame is only removed once the broker acknowledged it, so frames being drained when
+//! the gateway stops may be published twice.
+use paho_mqtt as mqtt;
+use sensor_model::ContentType;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, time::sleep};
+
+/// How often to check whether the broker is reachable again, while frames are waiting.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// User property carrying the time a frame was received, in milliseconds since the Unix epoch.
+pub const TIMESTAMP_PROPERTY: &str = "timestamp";
+
+/// A message received from the mesh or a delivery report, to publish to the cloud.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Frame {
+    pub topic: String,
+    /// MIME type of the payload.
+    pub content_type: String,
+    pub payload: Vec<u8>,
+    /// Time the frame was received, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Correlation data of the command a delivery report is about.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_data: Option<Vec<u8>>,
+}
+
+impl Frame {
+    /// A frame received now.
+    pub fn new(topic: String, content_type: ContentType, payload: Vec<u8>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        Self {
+            topic,
+            content_type: content_type.mime().to_string(),
+            payload,
+            timestamp,
+            correlation_data: None,
+        }
+    }
+
+    /// The frame as a message, carrying the time it was received.
+    pub fn to_message(&self) -> Result<mqtt::Message, mqtt::Error> {
+        let mut properties = mqtt::Properties::new();
+        properties.push_string(mqtt::PropertyCode::ContentType, &self.content_type)?;
+        properties.push_string_pair(
+            mqtt::PropertyCode::UserProperty,
+            TIMESTAMP_PROPERTY,
+            &self.timestamp.to_string(),
+        )?;
+        if let Some(data) = &self.correlation_data {
+            properties.push_binary(mqtt::PropertyCode::CorrelationData, data.as_slice())?;
+        }
+        Ok(mqtt::MessageBuilder::new()
+            .topic(&self.topic)
+            .payload(self.payload.clone())
+            .qos(1)
+            .properties(properties)
+            .finalize())
+    }
+}
+
+/// A bounded queue of frames, backed by an append-only file.
+///
+/// Each frame is stored as its length, in 4 little endian bytes, followed by its JSON encoding.
+/// Frames published or dropped stay in the file until it is compacted.
+pub struct Outbox {
+    path: PathBuf,
+    file: File,
+    capacity: usize,
+    frames: VecDeque<Frame>,
+    /// Frames at the start of the file which were published or dropped.
+    stale: usize,
+}
+
+impl Outbox {
+    /// Open the outbox stored at a path, with the frames left by a previous run.
+    pub fn open(path: &Path, capacity: usize) -> anyhow::Result<Self> {
+        if capacity == 0 {
+            return Err(anyhow::anyhow!("the outbox must hold at least one frame"));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut frames = match fs::read(path) {
+            Ok(data) => decode(&data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+            Err(e) => return Err(e.into()),
+        };
+        if frames.len() > capacity {
+            log::warn!(
+                "Dropping {} frames exceeding the outbox capacity",
+                frames.len() - capacity
+            );
+            frames.drain(..frames.len() - capacity);
+        }
+        let mut outbox = Self {
+            path: path.to_path_buf(),
+            file: append(path)?,
+            capacity,
+            frames,
+            stale: 0,
+        };
+        // Leave out the dropped frames, and anything that could not be read
+        outbox.compact()?;
+        Ok(outbox)
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// The oldest frame.
+    pub fn front(&self) -> Option<&Frame> {
+        self.frames.front()
+    }
+
+    /// Append a frame, dropping the oldest one if the outbox is full.
+    pub fn push(&mut self, frame: Frame) -> anyhow::Result<()> {
+        self.file.write_all(&encode(&frame)?)?;
+        if self.frames.len() == self.capacity {
+            if let Some(dropped) = self.frames.pop_front() {
+                log::warn!(
+                    "Outbox full, dropping frame for {} received at {}",
+                    dropped.topic,
+                    dropped.timestamp
+                );
+                self.stale += 1;
+            }
+        }
+        self.frames.push_back(frame);
+        self.compact_if_stale()
+    }
+
+    /// Remove the oldest frame, once published.
+    pub fn pop(&mut self) -> anyhow::Result<Option<Frame>> {
+        let frame = self.frames.pop_front();
+        if frame.is_some() {
+            self.stale += 1;
+            self.compact_if_stale()?;
+        }
+        Ok(frame)
+    }
+
+    fn compact_if_stale(&mut self) -> anyhow::Result<()> {
+        if self.frames.is_empty() {
+            self.file.set_len(0)?;
+            self.stale = 0;
+        } else if self.stale >= self.capacity {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the file with the waiting frames only.
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let mut temporary = OsString::from(self.path.as_os_str());
+        temporary.push(".tmp");
+        let mut data = Vec::new();
+        for frame in &self.frames {
+            data.extend(encode(frame)?);
+        }
+        fs::write(&temporary, data)?;
+        fs::rename(&temporary, &self.path)?;
+        self.file = append(&self.path)?;
+        self.stale = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn encode(frame: &Frame) -> anyhow::Result<Vec<u8>> {
+    let json = serde_json::to_vec(frame)?;
+    let mut record = (json.len() as u32).to_le_bytes().to_vec();
+    record.extend(json);
+    Ok(record)
+}
+
+/// Read the frames of a file, up to the first one which is incomplete or invalid.
+fn decode(mut data: &[u8]) -> VecDeque<Frame> {
+    let mut frames = VecDeque::new();
+    while !data.is_empty() {
+        let end = data.get(..4).map(|length| {
+            4 + u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize
+        });
+        let frame = end
+            .and_then(|end| data.get(4..end))
+            .and_then(|json| serde_json::from_slice::<Frame>(json).ok());
+        match (frame, end) {
+            (Some(frame), Some(end)) => {
+                data = &data[end..];
+                frames.push_back(frame);
+            }
+            _ => {
+                log::warn!("Ignoring {} unreadable bytes in the outbox", data.len());
+                break;
+            }
+        }
+    }
+    frames
+}
+
+async fn publish(mqtt_client: &mqtt::AsyncClient, frame: &Frame) -> Result<(), mqtt::Error> {
+    mqtt_client.publish(frame.to_message()?).await
+}
+
+/// Publish the frames of the gateway in order, keeping them in the outbox while the broker is
+/// unreachable.
+pub async fn run(
+    mut outbox: Outbox,
+    mut frames: mpsc::Receiver<Frame>,
+    mqtt_client: mqtt::AsyncClient,
+) -> Result<(), anyhow::Error> {
+    if !outbox.is_empty() {
+        log::info!("{} frames waiting in the outbox", outbox.len());
+    }
+    loop {
+        // Frames waiting are published first, frames received meanwhile are queued after them
+        while let Some(frame) = outbox.front() {
+            if !mqtt_client.is_connected() {
+                break;
+            }
+            if let Err(e) = publish(&mqtt_client, frame).await {
+                log::warn!("Error publishing frame from the outbox: {:?}", e);
+                break;
+            }
+            outbox.pop()?;
+            while let Ok(frame) = frames.try_recv() {
+                outbox.push(frame)?;
+            }
+            if outbox.is_empty() {
+                log::info!("Outbox drained");
+            }
+        }
+
+        let frame = if outbox.is_empty() {
+            frames.recv().await
+        } else {
+            tokio::select! {
+                frame = frames.recv() => frame,
+                _ = sleep(RETRY_INTERVAL) => continue,
+            }
+        };
+        let frame = match frame {
+            Some(frame) => frame,
+            None => break,
+        };
+        if outbox.is_empty() && mqtt_client.is_connected() {
+            match publish(&mqtt_client, &frame).await {
+                Ok(_) => continue,
+                Err(e) => log::warn!("Error publishing frame, keeping it: {:?}", e),
+            }
+        }
+        outbox.push(frame)?;
+    }
+
+    if !outbox.is_empty() {
+        log::info!("Keeping {} frames in the outbox", outbox.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake;
+
+    fn path() -> PathBuf {
+        std::env::temp_dir().join(format!("gateway-outbox-{:016x}", rand::random::<u64>()))
+    }
+
+    fn frame(n: u8) -> Frame {
+        Frame {
+            topic: "sensor/ab00".to_string(),
+            content_type: ContentType::Json.mime().to_string(),
+            payload: vec![n],
+            timestamp: 1_660_000_000_000 + n as u64,
+            correlation_data: None,
+        }
+    }
+
+    #[test]
+    fn test_message() {
+        let message = frame(1).to_message().unwrap();
+        assert_eq!(message.topic(), "sensor/ab00");
+        assert_eq!(message.payload(), &[1]);
+        assert_eq!(
+            message
+                .properties()
+                .find_user_property(TIMESTAMP_PROPERTY)
+                .as_deref(),
+            Some("1660000000001")
+        );
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = path();
+        let mut outbox = Outbox::open(&path, 3).unwrap();
+        for n in 0..3 {
+            outbox.push(frame(n)).unwrap();
+        }
+        assert_eq!(outbox.pop().unwrap(), Some(frame(0)));
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&path, 3).unwrap();
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.pop().unwrap(), Some(frame(1)));
+        assert_eq!(outbox.pop().unwrap(), Some(frame(2)));
+        assert_eq!(outbox.pop().unwrap(), None);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bounded() {
+        let path = path();
+        let mut outbox = Outbox::open(&path, 2).unwrap();
+        for n in 0..5 {
+            outbox.push(frame(n)).unwrap();
+        }
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.front(), Some(&frame(3)));
+        drop(outbox);
+
+        // Fewer frames are kept if the capacity shrinks
+        let outbox = Outbox::open(&path, 1).unwrap();
+        assert_eq!(outbox.front(), Some(&frame(4)));
+        assert_eq!(outbox.len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_truncated() {
+        let path = path();
+        let mut data = encode(&frame(0)).unwrap();
+        let partial = encode(&frame(1)).unwrap();
+        data.extend(&partial[..partial.len() - 1]);
+        fs::write(&path, data).unwrap();
+
+        let mut outbox = Outbox::open(&path, 10).unwrap();
+        assert_eq!(outbox.len(), 1);
+        outbox.push(frame(2)).unwrap();
+        drop(outbox);
+        let mut outbox = Outbox::open(&path, 10).unwrap();
+        assert_eq!(outbox.pop().unwrap(), Some(frame(0)));
+        assert_eq!(outbox.pop().unwrap(), Some(frame(2)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unreachable() {
+        let path = path();
+        let (frames_tx, frames) = mpsc::channel(10);
+        let forwarder = tokio::spawn(run(
+            Outbox::open(&path, 10).unwrap(),
+            frames,
+            fake::disconnected_client(),
+        ));
+        for n in 0..3 {
+            frames_tx.send(frame(n)).await.unwrap();
+        }
+        drop(frames_tx);
+        forwarder.await.unwrap().unwrap();
+
+        let mut outbox = Outbox::open(&path, 10).unwrap();
+        for n in 0..3 {
+            assert_eq!(outbox.pop().unwrap(), Some(frame(n)));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,20 +1,17 @@
 //! Attach and send/receive BT Mesh messages
 use super::node_configurator;
-use crate::utils::AttachRetry;
-use bluer::{
-    mesh::{
-        application::Application,
-        element::*,
-        network::Network,
-        provisioner::{Provisioner, ProvisionerControlHandle, ProvisionerMessage},
+use crate::{
+    command::Command,
+    keys::AppKeys,
+    transport::{
+        ApplicationConfig, ElementConfig, MeshNode, MeshTransport, ModelKind, ProvisionerEvent,
     },
-    Uuid,
-};
-use btmesh_models::foundation::configuration::{
-    ConfigurationClient, ConfigurationMessage, ConfigurationServer,
+    utils::AttachRetry,
 };
+use bluer::Uuid;
+use btmesh_models::foundation::configuration::ConfigurationMessage;
 use btmesh_operator::{BtMeshCommand, BtMeshDeviceState, BtMeshEvent, BtMeshOperation};
-use dbus::Path;
+use futures::StreamExt;
 use paho_mqtt as mqtt;
 use std::{collections::HashMap, sync::Arc, time::Duration};
 use tokio::{
@@ -25,57 +22,45 @@ use tokio::{
 pub struct Config {
     start_address: u16,
     token: String,
+    /// Application keys the provisioned devices are bound to.
+    keys: Arc<AppKeys>,
 }
 
 impl Config {
-    pub fn new(token: String, start_address: u16) -> Self {
+    pub fn new(token: String, start_address: u16, keys: Arc<AppKeys>) -> Self {
         Self {
             token,
             start_address,
+            keys,
         }
     }
 }
 
-pub async fn run(
-    mesh: Network,
+pub async fn run<T: MeshTransport>(
+    mesh: T,
     config: Config,
-    mut commands: broadcast::Receiver<(String, Vec<u8>)>,
+    mut commands: broadcast::Receiver<Command>,
     mqtt_client: mqtt::AsyncClient,
 ) -> Result<(), anyhow::Error> {
-    let (element_control, element_handle) = element_control(10);
-    let (app_tx, _app_rx) = mpsc::channel(4);
-
-    let root_path = Path::from("/mesh/cfgclient");
-    let app_path = Path::from(format!("{}/{}", root_path.clone(), "application"));
-    let element_path = Path::from(format!("{}/{}", root_path.clone(), "ele00"));
+    let root_path = "/mesh/cfgclient";
+    let element = 0;
 
-    let (prov_tx, mut prov_rx) = mpsc::channel(4);
-
-    let sim = Application {
-        path: app_path,
-        elements: vec![Element {
-            path: element_path.clone(),
+    let sim = ApplicationConfig {
+        elements: vec![ElementConfig {
             location: None,
             models: vec![
-                Arc::new(FromDrogue::new(ConfigurationServer::default())),
-                Arc::new(FromDrogue::new(ConfigurationClient::default())),
+                ModelKind::ConfigurationServer,
+                ModelKind::ConfigurationClient,
             ],
-            control_handle: Some(element_handle),
         }],
-        provisioner: Some(Provisioner {
-            control_handle: ProvisionerControlHandle {
-                messages_tx: prov_tx,
-            },
-            // TODO fix bluer
-            start_address: config.start_address as i32,
-        }),
-        events_tx: app_tx,
+        provisioner: Some(config.start_address),
     };
 
-    let registered = mesh.application(root_path.clone(), sim).await?;
+    let registered = mesh.register(root_path, sim).await?;
+    let mut prov_rx = registered.provisioner;
 
     let node = mesh
-        .attach_retry(10, Duration::from_secs(2), root_path.clone(), &config.token)
+        .attach_retry(10, Duration::from_secs(2), root_path, &config.token)
         .await?;
 
     let mut provisioned: HashMap<Uuid, Instant> = HashMap::new();
@@ -87,22 +72,23 @@ pub async fn run(
     tasks.push(tokio::spawn(node_configurator::run(
         configure_rx,
         mqtt_client.clone(),
-        element_path.clone(),
-        element_control,
+        element,
+        registered.elements,
         node.clone(),
+        config.keys.clone(),
     )));
 
     log::info!("Starting provisioner event loop");
     loop {
         tokio::select! {
-            evt = prov_rx.recv() => {
+            evt = prov_rx.next() => {
                 match evt {
                     Some(msg) => {
                         match msg {
-                            ProvisionerMessage::AddNodeComplete(uuid, unicast, _count) => {
+                            ProvisionerEvent::AddNodeComplete(uuid, unicast) => {
                                 configure_tx.send(NodeConfigurationMessage::Configure(uuid, unicast)).await?;
                             },
-                            ProvisionerMessage::AddNodeFailed(uuid, reason) => {
+                            ProvisionerEvent::AddNodeFailed(uuid, reason) => {
                                 log::info!("Failed to add node {:?}: '{:?}'", uuid, reason);
 
                                 let device = uuid.as_simple().to_string();
@@ -131,7 +117,7 @@ pub async fn run(
                 if do_provision {
                     provisioned.insert(uuid, now);
                     log::info!("Provisioning {:?}", uuid);
-                    match node.management.add_node(uuid).await {
+                    match node.add_node(uuid).await {
                         Ok(_) => {
                             log::info!("Add node started");
                         }
@@ -162,8 +148,8 @@ pub async fn run(
             },
             command = commands.recv() => {
                 match command {
-                    Ok((_, command)) => {
-                        if let Ok(data) = serde_json::from_slice::<BtMeshCommand>(&command[..]) {
+                    Ok(command) => {
+                        if let Ok(data) = serde_json::from_slice::<BtMeshCommand>(&command.payload[..]) {
                             log::info!("Parsed command payload: {:?}", data);
                             match data.command {
                                 BtMeshOperation::Provision {
@@ -199,7 +185,7 @@ pub async fn run(
 
     futures::future::join_all(tasks).await;
     log::info!("Shutting down provisioner");
-    drop(registered);
+    drop(registered.handle);
     sleep(Duration::from_secs(1)).await;
 
     Ok(())
@@ -212,8 +198,83 @@ pub enum NodeConfigurationMessage {
 }
 
 #[derive(Debug)]
-pub struct NodeConfiguration<'a> {
+pub struct NodeConfiguration {
     pub message: ConfigurationMessage,
-    pub path: Path<'a>,
+    pub element: usize,
     pub address: u16,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fake::{self, FakeMesh, Sent},
+        keys,
+        transport::ElementEvent,
+    };
+    use btmesh_common::opcode::Opcode;
+    use sensor_model::RawMessage;
+
+    const ROOT: &str = "/mesh/cfgclient";
+
+    #[tokio::test]
+    async fn test_configure() {
+        let (mesh, mut sent) = FakeMesh::new();
+        let (commands_tx, commands) = broadcast::channel(10);
+        let uuid = Uuid::parse_str(keys::tests::TEAM_DEVICE).unwrap();
+        let keys = Arc::new(keys::tests::keys());
+        let provisioner = tokio::spawn(run(
+            mesh.clone(),
+            Config::new("token".to_string(), 0x0100, keys.clone()),
+            commands,
+            fake::disconnected_client(),
+        ));
+        while !mesh.is_attached(ROOT) {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        mesh.provisioned(ROOT, ProvisionerEvent::AddNodeComplete(uuid, 0x0100))
+            .unwrap();
+        assert_eq!(
+            sent.recv().await,
+            Some(Sent::AppKey {
+                element: 0,
+                destination: 0x0100,
+                app_key: 1,
+                net_index: 0,
+                update: false,
+            })
+        );
+        // The three models are bound, and the sensor and battery servers publish
+        for _ in 0..5 {
+            match sent.recv().await {
+                Some(Sent::DevKey {
+                    element: 0,
+                    destination: 0x0100,
+                    remote: true,
+                    ..
+                }) => {}
+                other => panic!("unexpected {other:?}"),
+            }
+            // The configurator only waits for a reply from the node
+            mesh.receive(
+                ROOT,
+                ElementEvent::DevKey {
+                    src: [0x01, 0x00],
+                    opcode: Opcode::TwoOctet(0x82, 0x04),
+                    parameters: vec![0x01],
+                },
+            )
+            .unwrap();
+        }
+
+        drop(commands_tx);
+        provisioner.await.unwrap().unwrap();
+        assert!(!mesh.is_attached(ROOT));
+        assert!(sent.try_recv().is_err());
+
+        // Commands to the device use its key
+        let set = RawMessage::new(Opcode::TwoOctet(0x82, 0x03), vec![0x01, 0x00]).unwrap();
+        assert_eq!(keys.key_for(0x0100, &set), 1);
+    }
+}
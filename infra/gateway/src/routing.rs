@@ -0,0 +1,173 @@
+//! Elements exposed by the gateway, and routing of the commands to them by location.
+//!
+//! The elements are read from a TOML file, such as:
+//!
+//! ```toml
+//! [[element]]
+//! name = "front"
+//! location = 0x0100
+//! models = ["generic-onoff-client", "generic-battery-client", "sensor-client"]
+//!
+//! [[element]]
+//! name = "left"
+//! location = 0x010d
+//! models = ["generic-onoff-server"]
+//! ```
+//!
+//! Commands are sent from the element at the location they target.
+use crate::transport::{ApplicationConfig, ElementConfig, ModelKind};
+use anyhow::{anyhow, Context};
+use sensor_model::MicrobitComposition;
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RoutedElement {
+    pub name: String,
+    /// Location descriptor, as carried in mesh messages.
+    pub location: u16,
+    pub models: Vec<ModelKind>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RoutingTable {
+    #[serde(rename = "element")]
+    elements: Vec<RoutedElement>,
+}
+
+impl RoutingTable {
+    pub fn new(elements: Vec<RoutedElement>) -> anyhow::Result<Self> {
+        if elements.is_empty() {
+            return Err(anyhow!("the gateway must expose at least one element"));
+        }
+        for (index, element) in elements.iter().enumerate() {
+            if let Some(other) = elements[..index]
+                .iter()
+                .find(|other| other.location == element.location)
+            {
+                return Err(anyhow!(
+                    "elements '{}' and '{}' share location {:#06x}",
+                    other.name,
+                    element.name,
+                    element.location
+                ));
+            }
+        }
+        Ok(Self { elements })
+    }
+
+    /// Read the elements from a TOML file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read elements from {}", path.display()))?;
+        let table: Self = toml::from_str(&content)
+            .with_context(|| format!("failed to parse elements from {}", path.display()))?;
+        Self::new(table.elements)
+    }
+
+    pub fn elements(&self) -> &[RoutedElement] {
+        &self.elements
+    }
+
+    /// Index of the element at a location.
+    pub fn element(&self, location: u16) -> Option<usize> {
+        self.elements.iter().position(|e| e.location == location)
+    }
+
+    /// The application exposing the elements, in order.
+    pub fn application(&self) -> ApplicationConfig {
+        ApplicationConfig {
+            elements: self
+                .elements
+                .iter()
+                .map(|element| ElementConfig {
+                    location: Some(element.location),
+                    models: element.models.clone(),
+                })
+                .collect(),
+            provisioner: None,
+        }
+    }
+}
+
+impl Default for RoutingTable {
+    /// The elements of the micro:bit, each with the counterparts of its models: the front one
+    /// reads the sensors and battery, and the buttons are the other ones.
+    fn default() -> Self {
+        Self {
+            elements: MicrobitComposition::ELEMENTS
+                .iter()
+                .map(|element| RoutedElement {
+                    name: element.name.to_string(),
+                    location: element.location(),
+                    models: element
+                        .models
+                        .iter()
+                        .filter_map(|model| ModelKind::counterpart(*model))
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELEMENTS: &str = r#"
+        [[element]]
+        name = "front"
+        location = 0x0100
+        models = ["generic-onoff-client", "generic-battery-client", "sensor-client"]
+
+        [[element]]
+        name = "left"
+        location = 0x010d
+        models = ["generic-onoff-server"]
+
+        [[element]]
+        name = "right"
+        location = 0x010e
+        models = ["generic-onoff-server"]
+    "#;
+
+    #[test]
+    fn test_parse() {
+        let table: RoutingTable = toml::from_str(ELEMENTS).unwrap();
+        assert_eq!(table, RoutingTable::default());
+        assert_eq!(table.element(0x010d), Some(1));
+        assert_eq!(table.element(0x0101), None);
+
+        let application = table.application();
+        assert_eq!(application.elements[2].location, Some(0x010e));
+        assert_eq!(application.provisioner, None);
+    }
+
+    #[test]
+    fn test_composition() {
+        // Every model of the micro:bit has a counterpart, in the same order
+        let table = RoutingTable::default();
+        for (element, layout) in table.elements.iter().zip(MicrobitComposition::ELEMENTS) {
+            assert_eq!(element.location, layout.location());
+            assert_eq!(element.models.len(), layout.models.len(), "{}", layout.name);
+        }
+        assert_eq!(table.elements.len(), MicrobitComposition::ELEMENTS.len());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(RoutingTable::new(Vec::new()).is_err());
+
+        let mut elements = RoutingTable::default().elements;
+        elements[2].location = elements[1].location;
+        let error = RoutingTable::new(elements).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "elements 'left' and 'right' share location 0x010d"
+        );
+
+        let unknown = "[[element]]\nname = \"front\"\nlocation = 0x0100\nmodels = [\"light\"]";
+        assert!(toml::from_str::<RoutingTable>(unknown).is_err());
+    }
+}
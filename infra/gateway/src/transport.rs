@@ -0,0 +1,355 @@
+//! Transport of the BT Mesh messages of the gateway and provisioner.
+//!
+//! The gateway only talks to the mesh through [`MeshTransport`] and [`MeshNode`].
+//! [`BluerTransport`] reaches the BlueZ mesh daemon, and [`crate::fake::FakeMesh`] keeps
+//! everything in memory, so that the gateway runs without a daemon in tests.
+use async_trait::async_trait;
+use bluer::{
+    mesh::{
+        application::Application,
+        element::{element_control, Element, ElementMessage, FromDrogue},
+        network::Network,
+        node::Node,
+        provisioner::{Provisioner, ProvisionerControlHandle, ProvisionerMessage},
+    },
+    Uuid,
+};
+use btmesh_common::{opcode::Opcode, ModelIdentifier};
+use btmesh_models::{
+    foundation::configuration::{ConfigurationClient, ConfigurationMessage, ConfigurationServer},
+    generic::{
+        battery::{GenericBatteryClient, GENERIC_BATTERY_SERVER},
+        onoff::{
+            GenericOnOffClient, GenericOnOffServer, GENERIC_ONOFF_CLIENT, GENERIC_ONOFF_SERVER,
+        },
+    },
+    sensor::SENSOR_SETUP_SERVER,
+};
+use dbus::Path;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use sensor_model::{RawMessage, SensorClient};
+use serde::Deserialize;
+use std::{any::Any, sync::Arc};
+use tokio::sync::mpsc;
+
+/// Models an element of an application can host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModelKind {
+    ConfigurationServer,
+    ConfigurationClient,
+    GenericOnOffClient,
+    GenericOnOffServer,
+    GenericBatteryClient,
+    SensorClient,
+}
+
+impl ModelKind {
+    /// The model talking to a model of the micro:bit, such as the client of one of its servers.
+    pub fn counterpart(model: ModelIdentifier) -> Option<Self> {
+        [
+            (GENERIC_ONOFF_SERVER, Self::GenericOnOffClient),
+            (GENERIC_ONOFF_CLIENT, Self::GenericOnOffServer),
+            (GENERIC_BATTERY_SERVER, Self::GenericBatteryClient),
+            (SENSOR_SETUP_SERVER, Self::SensorClient),
+        ]
+        .into_iter()
+        .find(|(m, _)| *m == model)
+        .map(|(_, kind)| kind)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElementConfig {
+    pub location: Option<u16>,
+    pub models: Vec<ModelKind>,
+}
+
+/// An application to register on the mesh. Its elements are addressed by their index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApplicationConfig {
+    pub elements: Vec<ElementConfig>,
+    /// First unicast address assigned to the nodes it provisions, if it is a provisioner.
+    pub provisioner: Option<u16>,
+}
+
+/// A message received by an element of the application.
+#[derive(Debug, Clone)]
+pub enum ElementEvent {
+    /// A message encrypted with an application key.
+    Received {
+        app_key: u16,
+        /// Source address, as received.
+        src: [u8; 2],
+        location: Option<u16>,
+        opcode: Opcode,
+        parameters: Vec<u8>,
+    },
+    /// A message encrypted with the device key of its source, in reply to configuration
+    /// messages.
+    DevKey {
+        src: [u8; 2],
+        opcode: Opcode,
+        parameters: Vec<u8>,
+    },
+}
+
+/// Outcome of adding a node, for provisioner applications.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProvisionerEvent {
+    /// The node was provisioned and assigned the unicast address.
+    AddNodeComplete(Uuid, u16),
+    AddNodeFailed(Uuid, String),
+}
+
+/// A registered application: the messages received by its elements and its provisioner.
+///
+/// The provisioner messages end right away if the application is not a provisioner.
+pub struct Registration {
+    pub elements: BoxStream<'static, ElementEvent>,
+    pub provisioner: BoxStream<'static, ProvisionerEvent>,
+    /// Keeps the application registered until dropped.
+    pub handle: Box<dyn Any + Send>,
+}
+
+#[async_trait]
+pub trait MeshTransport: Send + Sync {
+    type Node: MeshNode;
+
+    /// Register an application, rooted at the given object path.
+    async fn register(
+        &self,
+        root: &str,
+        application: ApplicationConfig,
+    ) -> anyhow::Result<Registration>;
+
+    /// Attach to the node of the application registered at the given object path.
+    async fn attach(&self, root: &str, token: &str) -> anyhow::Result<Self::Node>;
+}
+
+/// A node of the mesh, attached by an application. Elements are the indexes in the
+/// [`ApplicationConfig`] it was registered with.
+#[async_trait]
+pub trait MeshNode: Clone + Send + Sync + 'static {
+    async fn send(
+        &self,
+        message: &RawMessage,
+        element: usize,
+        destination: u16,
+        app_key: u16,
+    ) -> anyhow::Result<()>;
+
+    async fn dev_key_send(
+        &self,
+        message: &ConfigurationMessage,
+        element: usize,
+        destination: u16,
+        remote: bool,
+        net_index: u16,
+    ) -> anyhow::Result<()>;
+
+    async fn add_app_key(
+        &self,
+        element: usize,
+        destination: u16,
+        app_key: u16,
+        net_index: u16,
+        update: bool,
+    ) -> anyhow::Result<()>;
+
+    /// Start provisioning a device, the outcome is reported as a [`ProvisionerEvent`].
+    async fn add_node(&self, uuid: Uuid) -> anyhow::Result<()>;
+}
+
+/// Capacity of the channel of the messages received by the elements.
+const ELEMENT_CAPACITY: usize = 10;
+
+/// The mesh of the BlueZ mesh daemon.
+#[derive(Clone)]
+pub struct BluerTransport {
+    network: Network,
+}
+
+impl BluerTransport {
+    pub fn new(network: Network) -> Self {
+        Self { network }
+    }
+}
+
+#[async_trait]
+impl MeshTransport for BluerTransport {
+    type Node = BluerNode;
+
+    async fn register(
+        &self,
+        root: &str,
+        application: ApplicationConfig,
+    ) -> anyhow::Result<Registration> {
+        let (element_control, element_handle) = element_control(ELEMENT_CAPACITY);
+        let (app_tx, app_rx) = mpsc::channel(4);
+        let (prov_tx, prov_rx) = mpsc::channel(4);
+
+        let elements = application
+            .elements
+            .iter()
+            .enumerate()
+            .map(|(index, element)| Element {
+                path: element_path(root, index),
+                location: element.location,
+                models: element
+                    .models
+                    .iter()
+                    .map(|model| match model {
+                        ModelKind::ConfigurationServer => {
+                            Arc::new(FromDrogue::new(ConfigurationServer::default())) as _
+                        }
+                        ModelKind::ConfigurationClient => {
+                            Arc::new(FromDrogue::new(ConfigurationClient::default())) as _
+                        }
+                        ModelKind::GenericOnOffClient => {
+                            Arc::new(FromDrogue::new(GenericOnOffClient)) as _
+                        }
+                        ModelKind::GenericOnOffServer => {
+                            Arc::new(FromDrogue::new(GenericOnOffServer)) as _
+                        }
+                        ModelKind::GenericBatteryClient => {
+                            Arc::new(FromDrogue::new(GenericBatteryClient)) as _
+                        }
+                        ModelKind::SensorClient => {
+                            Arc::new(FromDrogue::new(SensorClient::new())) as _
+                        }
+                    })
+                    .collect(),
+                control_handle: Some(element_handle.clone()),
+            })
+            .collect();
+
+        let sim = Application {
+            path: Path::from(format!("{}/application", root)),
+            elements,
+            provisioner: application.provisioner.map(|start_address| Provisioner {
+                control_handle: ProvisionerControlHandle {
+                    messages_tx: prov_tx,
+                },
+                // TODO fix bluer
+                start_address: start_address as i32,
+            }),
+            events_tx: app_tx,
+        };
+
+        let registered = self
+            .network
+            .application(Path::from(root.to_string()), sim)
+            .await?;
+
+        let provisioner = stream::unfold(prov_rx, |mut rx| async move {
+            let event = match rx.recv().await? {
+                ProvisionerMessage::AddNodeComplete(uuid, unicast, _count) => {
+                    ProvisionerEvent::AddNodeComplete(uuid, unicast)
+                }
+                ProvisionerMessage::AddNodeFailed(uuid, reason) => {
+                    ProvisionerEvent::AddNodeFailed(uuid, reason)
+                }
+            };
+            Some((event, rx))
+        });
+
+        Ok(Registration {
+            elements: element_control.map(ElementEvent::from).boxed(),
+            provisioner: provisioner.boxed(),
+            handle: Box::new((registered, app_rx)),
+        })
+    }
+
+    async fn attach(&self, root: &str, token: &str) -> anyhow::Result<Self::Node> {
+        let node = self
+            .network
+            .attach(Path::from(root.to_string()), token)
+            .await?;
+        Ok(BluerNode {
+            node,
+            root: root.to_string(),
+        })
+    }
+}
+
+impl From<ElementMessage> for ElementEvent {
+    fn from(message: ElementMessage) -> Self {
+        match message {
+            ElementMessage::Received(received) => Self::Received {
+                app_key: received.app_key,
+                src: received.src.as_bytes(),
+                location: received.location,
+                opcode: received.opcode,
+                parameters: received.parameters.to_vec(),
+            },
+            ElementMessage::DevKey(received) => Self::DevKey {
+                src: received.src.as_bytes(),
+                opcode: received.opcode,
+                parameters: received.parameters.to_vec(),
+            },
+        }
+    }
+}
+
+/// Object path of an element of the application rooted at the given path.
+fn element_path(root: &str, element: usize) -> Path<'static> {
+    Path::from(format!("{}/ele{:02}", root, element))
+}
+
+#[derive(Clone)]
+pub struct BluerNode {
+    node: Node,
+    root: String,
+}
+
+#[async_trait]
+impl MeshNode for BluerNode {
+    async fn send(
+        &self,
+        message: &RawMessage,
+        element: usize,
+        destination: u16,
+        app_key: u16,
+    ) -> anyhow::Result<()> {
+        let path = element_path(&self.root, element);
+        Ok(self.node.send(message, path, destination, app_key).await?)
+    }
+
+    async fn dev_key_send(
+        &self,
+        message: &ConfigurationMessage,
+        element: usize,
+        destination: u16,
+        remote: bool,
+        net_index: u16,
+    ) -> anyhow::Result<()> {
+        let path = element_path(&self.root, element);
+        Ok(self
+            .node
+            .dev_key_send(message, path, destination, remote, net_index)
+            .await?)
+    }
+
+    async fn add_app_key(
+        &self,
+        element: usize,
+        destination: u16,
+        app_key: u16,
+        net_index: u16,
+        update: bool,
+    ) -> anyhow::Result<()> {
+        let path = element_path(&self.root, element);
+        Ok(self
+            .node
+            .add_app_key(path, destination, app_key, net_index, update)
+            .await?)
+    }
+
+    async fn add_node(&self, uuid: Uuid) -> anyhow::Result<()> {
+        Ok(self.node.management.add_node(uuid).await?)
+    }
+}
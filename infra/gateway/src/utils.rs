@@ -1,30 +1,29 @@
+use crate::transport::MeshTransport;
 use async_trait::async_trait;
-use bluer::mesh::{network::Network, node::Node};
-use dbus::Path;
 use std::time::Duration;
 
 #[async_trait]
-pub trait AttachRetry {
+pub trait AttachRetry: MeshTransport {
     async fn attach_retry(
         &self,
         mut retries: usize,
         delay: Duration,
-        path: Path<'_>,
+        root: &str,
         token: &str,
-    ) -> bluer::Result<Node>;
+    ) -> anyhow::Result<Self::Node>;
 }
 
 #[async_trait]
-impl AttachRetry for Network {
+impl<T: MeshTransport> AttachRetry for T {
     async fn attach_retry(
         &self,
         mut retries: usize,
         delay: Duration,
-        path: Path<'_>,
+        root: &str,
         token: &str,
-    ) -> bluer::Result<Node> {
+    ) -> anyhow::Result<Self::Node> {
         loop {
-            match self.attach(path.clone(), token).await {
+            match self.attach(root, token).await {
                 Ok(node) => break Ok(node),
                 Err(err) if retries == 0 => break Err(err),
                 Err(err) => {
@@ -0,0 +1,262 @@
+//! Rolling statistics of the sensor readings of each device.
+//!
+//! The converter is otherwise stateless, so this stage is optional. When enabled, the state
+//! converted from a sensor status gets a `sensor.stats` entry, with the minimum, maximum, mean
+//! and standard deviation of the readings of the same element over each window.
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// Key of the converted state carrying the statistics.
+pub const STATS_KEY: &str = "sensor.stats";
+
+/// The aggregated quantities, and how to read them from a sensor payload.
+const METRICS: &[(&str, fn(&Value) -> Option<f64>)] = &[
+    ("temperature", |p| p.get("temperature")?.as_f64()),
+    ("noise", |p| p.get("noise")?.as_f64()),
+    ("acceleration", |p| {
+        let acceleration = p.get("acceleration")?;
+        let mut sum = 0.0;
+        for axis in ["x", "y", "z"] {
+            sum += acceleration.get(axis)?.as_f64()?.powi(2);
+        }
+        Some(sum.sqrt())
+    }),
+];
+
+/// Parse a window duration, such as `30s`, `5m` or `1h`.
+pub fn parse_window(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid window '{s}'");
+    let unit = s.chars().last().ok_or_else(invalid)?;
+    let value: u64 = s[..s.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        _ => return Err(invalid()),
+    };
+    match value.checked_mul(multiplier) {
+        Some(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Name of a window in the statistics, the inverse of [`parse_window`].
+fn window_name(window: Duration) -> String {
+    match window.as_secs() {
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Population standard deviation.
+    pub stddev: f64,
+    pub count: usize,
+}
+
+impl Stats {
+    fn compute(values: impl Iterator<Item = f64>) -> Option<Self> {
+        let (mut min, mut max, mut sum, mut squares, mut count) =
+            (f64::INFINITY, f64::NEG_INFINITY, 0.0, 0.0, 0);
+        for value in values {
+            min = min.min(value);
+            max = max.max(value);
+            sum += value;
+            squares += value * value;
+            count += 1;
+        }
+        if count == 0 {
+            return None;
+        }
+        let mean = sum / count as f64;
+        // Rounding may make the variance slightly negative for constant readings
+        let variance = (squares / count as f64 - mean * mean).max(0.0);
+        Some(Self {
+            min,
+            max,
+            mean,
+            stddev: variance.sqrt(),
+            count,
+        })
+    }
+}
+
+/// Readings of one element of a device, for each metric, oldest first.
+#[derive(Default)]
+struct Samples(HashMap<&'static str, VecDeque<(SystemTime, f64)>>);
+
+impl Samples {
+    /// Time of the most recent reading, of any metric.
+    fn newest(&self) -> Option<SystemTime> {
+        self.0
+            .values()
+            .filter_map(|readings| readings.back())
+            .map(|(time, _)| *time)
+            .max()
+    }
+}
+
+pub struct Aggregator {
+    windows: Vec<Duration>,
+    samples: Mutex<HashMap<(Option<u16>, u16), Samples>>,
+}
+
+impl Aggregator {
+    pub fn new(windows: Vec<Duration>) -> Self {
+        Self {
+            windows,
+            samples: Default::default(),
+        }
+    }
+
+    /// Record the readings of a sensor payload at the given time, and return the statistics
+    /// over each window ending at that time.
+    pub fn record(
+        &self,
+        address: Option<u16>,
+        location: u16,
+        time: SystemTime,
+        payload: &Value,
+    ) -> Value {
+        let longest = self.windows.iter().max().copied().unwrap_or_default();
+        let mut samples = self.samples.lock().unwrap();
+        // Forget the elements without any reading in the longest window, such as the ones of
+        // devices that left the mesh
+        samples.retain(|_, samples| {
+            samples.newest().map_or(false, |newest| {
+                time.duration_since(newest)
+                    .map_or(true, |age| age <= longest)
+            })
+        });
+        let samples = samples.entry((address, location)).or_default();
+
+        let mut stats = Map::new();
+        stats.insert("location".to_string(), location.into());
+        for window in &self.windows {
+            stats.insert(window_name(*window), Value::Object(Map::new()));
+        }
+        for (name, read) in METRICS {
+            let readings = samples.0.entry(*name).or_default();
+            if let Some(value) = read(payload) {
+                readings.push_back((time, value));
+            }
+            while let Some((oldest, _)) = readings.front() {
+                match time.duration_since(*oldest) {
+                    Ok(age) if age > longest => readings.pop_front(),
+                    _ => break,
+                };
+            }
+            for window in &self.windows {
+                let recent = readings
+                    .iter()
+                    .filter(|(t, _)| time.duration_since(*t).map_or(true, |age| age <= *window))
+                    .map(|(_, value)| *value);
+                if let Some(s) = Stats::compute(recent) {
+                    stats[&window_name(*window)][*name] = serde_json::to_value(s).unwrap();
+                }
+            }
+        }
+        Value::Object(stats)
+    }
+
+    /// Add the statistics to the state converted from a sensor status, other states are left
+    /// untouched.
+    pub fn annotate(&self, address: Option<u16>, time: SystemTime, state: &mut Value) {
+        let sensor = match state.get("sensor") {
+            Some(sensor) => sensor,
+            None => return,
+        };
+        let location = sensor
+            .get("location")
+            .and_then(Value::as_u64)
+            .unwrap_or_default() as u16;
+        let stats = match sensor.get("payload") {
+            Some(payload) => self.record(address, location, time, payload),
+            None => return,
+        };
+        state[STATS_KEY] = stats;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_window("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_window("1h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_window("0m").is_err());
+        assert!(parse_window("5").is_err());
+        assert!(parse_window("").is_err());
+        assert!(parse_window("5µ").is_err());
+        assert!(parse_window("µ").is_err());
+        assert!(parse_window("99999999999999999h").is_err());
+        assert_eq!(window_name(Duration::from_secs(300)), "5m");
+        assert_eq!(window_name(Duration::from_secs(90)), "90s");
+    }
+
+    #[test]
+    fn test_windows() {
+        let aggregator = Aggregator::new(vec![Duration::from_secs(60), Duration::from_secs(300)]);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let at = |seconds| start + Duration::from_secs(seconds);
+
+        aggregator.record(Some(1), 0x100, at(0), &json!({"temperature": 20.0}));
+        aggregator.record(Some(1), 0x100, at(200), &json!({"temperature": 22.0}));
+        // Other elements and devices are aggregated separately
+        aggregator.record(Some(1), 0x101, at(210), &json!({"temperature": 40.0}));
+        aggregator.record(Some(2), 0x100, at(220), &json!({"temperature": 40.0}));
+        let stats = aggregator.record(
+            Some(1),
+            0x100,
+            at(230),
+            &json!({"temperature": 26.0, "noise": 12, "acceleration": {"x": 3.0, "y": 0.0, "z": 4.0}}),
+        );
+
+        assert_eq!(stats["location"], json!(0x100));
+        assert_eq!(
+            stats["1m"]["temperature"],
+            json!({"min": 22.0, "max": 26.0, "mean": 24.0, "stddev": 2.0, "count": 2})
+        );
+        assert_eq!(stats["5m"]["temperature"]["count"], json!(3));
+        assert_eq!(stats["5m"]["temperature"]["min"], json!(20.0));
+        assert_eq!(stats["1m"]["noise"]["mean"], json!(12.0));
+        assert_eq!(stats["1m"]["acceleration"]["max"], json!(5.0));
+
+        // Readings older than the longest window are dropped
+        let stats = aggregator.record(Some(1), 0x100, at(600), &json!({"temperature": 30.0}));
+        assert_eq!(stats["5m"]["temperature"]["count"], json!(1));
+        assert!(stats["1m"].get("noise").is_none());
+
+        // So are the elements without any reading in the longest window
+        assert_eq!(aggregator.samples.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_annotate() {
+        let aggregator = Aggregator::new(vec![Duration::from_secs(60)]);
+        let mut state = json!({"sensor": {"payload": {"noise": 3}, "location": 256}});
+        aggregator.annotate(Some(1), SystemTime::now(), &mut state);
+        assert_eq!(state[STATS_KEY]["1m"]["noise"]["count"], json!(1));
+        assert_eq!(state[STATS_KEY]["location"], json!(256));
+
+        let mut state = json!({"button": {"on": true, "location": 256}});
+        aggregator.annotate(Some(1), SystemTime::now(), &mut state);
+        assert!(state.get(STATS_KEY).is_none());
+    }
+}
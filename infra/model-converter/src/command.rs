@@ -0,0 +1,537 @@
+//! JSON commands sent to the micro:bit, and their conversion to mesh messages.
+//!
+//! The JSON schema of the commands is derived from the types in this module, and served at
+//! `/schema/command`. The format of each command is described in [MESHMODEL](../MESHMODEL.md).
+use crate::{
+    convert::{parse, MeshCodec},
+    error::ConversionError,
+};
+use btmesh_models::{
+    generic::{
+        battery::GenericBatteryMessage,
+        onoff::{GenericOnOffMessage, Set as GenericOnOffSet},
+    },
+    sensor::PropertyId,
+    Message,
+};
+use schemars::{schema::RootSchema, JsonSchema};
+use sensor_model::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Mutex};
+
+/// A command addressed to a single device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Command {
+    /// Unicast address of the device.
+    pub address: u16,
+    #[serde(flatten)]
+    pub kind: CommandKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandKind {
+    /// Turn the display on or off.
+    Display(DisplayCommand),
+    /// Request the current state of a model.
+    Get(GetCommand),
+    /// Change the publication cadence of a sensor property.
+    SensorCadence(CadenceCommand),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DisplayCommand {
+    pub on: bool,
+    #[serde(default)]
+    pub location: u16,
+    /// Time to reach the target state, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transition_time: Option<u32>,
+    /// Delay before starting the transition, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<u32>,
+    /// Whether the device responds with its new state.
+    #[serde(default = "acknowledged")]
+    pub acknowledged: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GetModel {
+    Onoff,
+    Battery,
+    Sensor,
+    SensorDescriptor,
+    SensorCadence,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GetCommand {
+    pub model: GetModel,
+    #[serde(default)]
+    pub location: u16,
+    /// Name of the sensor property, all properties are queried if omitted. Required for
+    /// `sensor_cadence`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub property: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CadenceCommand {
+    /// Name of the sensor property.
+    pub property: String,
+    #[serde(default)]
+    pub location: u16,
+    /// Divisor of the publish period while in fast cadence, as a power of two.
+    pub period_divisor: u8,
+    pub trigger: TriggerCommand,
+    /// Minimum interval between two publications, as a power of two in milliseconds.
+    pub min_interval: u8,
+    /// Lower bound of the fast cadence range, in wire units.
+    pub fast_low: PropertyValue,
+    /// Upper bound of the fast cadence range, in wire units.
+    pub fast_high: PropertyValue,
+    /// Whether the device responds with its new cadence.
+    #[serde(default = "acknowledged")]
+    pub acknowledged: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerCommand {
+    /// Deltas in wire units of the property.
+    Value {
+        down: PropertyValue,
+        up: PropertyValue,
+    },
+    /// Deltas in units of 0.01 percent.
+    Percent { down: u16, up: u16 },
+}
+
+/// A property value in wire units: a number, or `[x, y, z]` for vectors.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum PropertyValue {
+    Scalar(i64),
+    Vector([i16; 3]),
+}
+
+fn acknowledged() -> bool {
+    true
+}
+
+/// Transaction identifiers of the Generic OnOff Set messages, per device.
+///
+/// Devices discard a set with the same identifier as the previous one, so each command needs a
+/// new identifier for its device.
+#[derive(Default)]
+pub struct TidTracker {
+    tids: Mutex<HashMap<u16, u8>>,
+}
+
+impl TidTracker {
+    /// The identifier for the next transaction with a device.
+    pub fn next(&self, address: u16) -> u8 {
+        let mut tids = self.tids.lock().unwrap();
+        let tid = tids.entry(address).or_insert(u8::MAX);
+        *tid = tid.wrapping_add(1);
+        *tid
+    }
+}
+
+impl Command {
+    pub fn json_schema() -> RootSchema {
+        schemars::schema_for!(Command)
+    }
+}
+
+impl MeshCodec for Command {
+    fn decode(message: &RawMessage) -> Result<Self, ConversionError> {
+        let address = message
+            .address
+            .ok_or_else(|| ConversionError::InvalidMessage("missing address".to_string()))?;
+        let location = message.location;
+        let get = |model, id: Option<PropertyId>| -> Result<_, ConversionError> {
+            Ok(CommandKind::Get(GetCommand {
+                model,
+                location,
+                property: id.map(property_name).transpose()?,
+            }))
+        };
+        let kind = match parse(message)? {
+            MeshEvent::OnOff(GenericOnOffMessage::Set(set)) => display(&set, true, location),
+            MeshEvent::OnOff(GenericOnOffMessage::SetUnacknowledged(set)) => {
+                display(&set, false, location)
+            }
+            MeshEvent::OnOff(GenericOnOffMessage::Get) => get(GetModel::Onoff, None)?,
+            MeshEvent::Battery(GenericBatteryMessage::Get) => get(GetModel::Battery, None)?,
+            MeshEvent::SensorQuery(SensorQuery::Get(id)) => get(GetModel::Sensor, id)?,
+            MeshEvent::SensorQuery(SensorQuery::DescriptorGet(id)) => {
+                get(GetModel::SensorDescriptor, id)?
+            }
+            MeshEvent::SensorSetup(SetupMessage::CadenceGet(id)) => {
+                get(GetModel::SensorCadence, Some(id))?
+            }
+            MeshEvent::SensorSetup(SetupMessage::CadenceSet(id, cadence)) => {
+                sensor_cadence(id, &cadence, true, location)?
+            }
+            MeshEvent::SensorSetup(SetupMessage::CadenceSetUnacknowledged(id, cadence)) => {
+                sensor_cadence(id, &cadence, false, location)?
+            }
+            _ => return Err(ConversionError::Unconverted),
+        };
+        Ok(Self { address, kind })
+    }
+
+    /// Convert the command to a raw message. Each display command is a new transaction.
+    fn encode(&self, tids: &TidTracker) -> Result<RawMessage, ConversionError> {
+        let (location, mut message) = match &self.kind {
+            CommandKind::Display(display) => {
+                let set = GenericOnOffSet {
+                    on_off: if display.on { 1 } else { 0 },
+                    tid: tids.next(self.address),
+                    transition_time: None,
+                    delay: None,
+                };
+                let set = match (display.transition_time, display.delay) {
+                    (None, None) => set,
+                    (transition_time, delay) => GenericOnOffSet {
+                        transition_time: Some(transition_time_steps(transition_time.unwrap_or(0))?),
+                        delay: Some(delay_steps(delay.unwrap_or(0))?),
+                        ..set
+                    },
+                };
+                let message = if display.acknowledged {
+                    GenericOnOffMessage::Set(set)
+                } else {
+                    GenericOnOffMessage::SetUnacknowledged(set)
+                };
+                (display.location, raw(&message)?)
+            }
+            CommandKind::Get(get) => {
+                let property = get.property.as_deref().map(property).transpose()?;
+                let message = match get.model {
+                    GetModel::Onoff => raw(&GenericOnOffMessage::Get)?,
+                    GetModel::Battery => raw(&GenericBatteryMessage::Get)?,
+                    GetModel::Sensor => raw(&SensorQuery::Get(property.map(|p| p.id)))?,
+                    GetModel::SensorDescriptor => {
+                        raw(&SensorQuery::DescriptorGet(property.map(|p| p.id)))?
+                    }
+                    GetModel::SensorCadence => {
+                        let property = property.ok_or_else(|| {
+                            invalid("'property' is required to get the sensor cadence")
+                        })?;
+                        raw(&SetupMessage::CadenceGet(property.id))?
+                    }
+                };
+                (get.location, message)
+            }
+            CommandKind::SensorCadence(cadence) => {
+                let property = property(&cadence.property)?;
+                let trigger = match &cadence.trigger {
+                    TriggerCommand::Value { down, up } => StatusTrigger::Value {
+                        down: value(property, down)?,
+                        up: value(property, up)?,
+                    },
+                    TriggerCommand::Percent { down, up } => StatusTrigger::Percent {
+                        down: *down,
+                        up: *up,
+                    },
+                };
+                let state = SensorCadence {
+                    period_divisor: cadence.period_divisor,
+                    trigger,
+                    min_interval: cadence.min_interval,
+                    fast_low: value(property, &cadence.fast_low)?,
+                    fast_high: value(property, &cadence.fast_high)?,
+                };
+                let message = if cadence.acknowledged {
+                    SetupMessage::CadenceSet(property.id, state)
+                } else {
+                    SetupMessage::CadenceSetUnacknowledged(property.id, state)
+                };
+                (cadence.location, raw(&message)?)
+            }
+        };
+        message.address = Some(self.address);
+        message.location = location;
+        Ok(message)
+    }
+}
+
+fn display(set: &GenericOnOffSet, acknowledged: bool, location: u16) -> CommandKind {
+    CommandKind::Display(DisplayCommand {
+        on: set.on_off == 1,
+        location,
+        transition_time: set.transition_time.map(transition_time_ms),
+        delay: set.delay.map(|delay| delay as u32 * 5),
+        acknowledged,
+    })
+}
+
+fn sensor_cadence(
+    id: PropertyId,
+    cadence: &SensorCadence,
+    acknowledged: bool,
+    location: u16,
+) -> Result<CommandKind, ConversionError> {
+    Ok(CommandKind::SensorCadence(CadenceCommand {
+        property: property_name(id)?,
+        location,
+        period_divisor: cadence.period_divisor,
+        trigger: match cadence.trigger {
+            StatusTrigger::Value { down, up } => TriggerCommand::Value {
+                down: down.into(),
+                up: up.into(),
+            },
+            StatusTrigger::Percent { down, up } => TriggerCommand::Percent { down, up },
+        },
+        min_interval: cadence.min_interval,
+        fast_low: cadence.fast_low.into(),
+        fast_high: cadence.fast_high.into(),
+        acknowledged,
+    }))
+}
+
+fn invalid(e: &str) -> ConversionError {
+    ConversionError::InvalidCommand(e.to_string())
+}
+
+fn raw<M: Message>(message: &M) -> Result<RawMessage, ConversionError> {
+    RawMessage::from_message(message).map_err(|e| invalid(&e.to_string()))
+}
+
+fn property(name: &str) -> Result<&'static SensorProperty, ConversionError> {
+    PROPERTIES
+        .iter()
+        .copied()
+        .find(|p| p.name == name)
+        .ok_or_else(|| invalid(&format!("unknown sensor property '{name}'")))
+}
+
+fn property_name(id: PropertyId) -> Result<String, ConversionError> {
+    SensorProperty::lookup(id)
+        .map(|p| p.name.to_string())
+        .ok_or_else(|| ConversionError::MalformedMessage(format!("unknown property {:#x}", id.0)))
+}
+
+impl From<Value> for PropertyValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::I8(v) => Self::Scalar(v.into()),
+            Value::U8(v) => Self::Scalar(v.into()),
+            Value::I16(v) => Self::Scalar(v.into()),
+            Value::U16(v) => Self::Scalar(v.into()),
+            Value::Vec3I16(x, y, z) => Self::Vector([x, y, z]),
+        }
+    }
+}
+
+/// Convert a JSON value to the wire representation of the property.
+fn value(property: &SensorProperty, value: &PropertyValue) -> Result<Value, ConversionError> {
+    let out_of_range = || invalid(&format!("value out of range for '{}'", property.name));
+    match (property.encoding, value) {
+        (Encoding::I8, PropertyValue::Scalar(v)) => {
+            i8::try_from(*v).map(Value::I8).map_err(|_| out_of_range())
+        }
+        (Encoding::U8, PropertyValue::Scalar(v)) => {
+            u8::try_from(*v).map(Value::U8).map_err(|_| out_of_range())
+        }
+        (Encoding::I16, PropertyValue::Scalar(v)) => i16::try_from(*v)
+            .map(Value::I16)
+            .map_err(|_| out_of_range()),
+        (Encoding::U16, PropertyValue::Scalar(v)) => u16::try_from(*v)
+            .map(Value::U16)
+            .map_err(|_| out_of_range()),
+        (Encoding::Vec3I16, PropertyValue::Vector([x, y, z])) => Ok(Value::Vec3I16(*x, *y, *z)),
+        _ => Err(invalid(&format!(
+            "expected a {} for '{}'",
+            if property.encoding == Encoding::Vec3I16 {
+                "vector"
+            } else {
+                "number"
+            },
+            property.name
+        ))),
+    }
+}
+
+/// Step resolutions of a transition time, in milliseconds.
+const RESOLUTIONS: [u32; 4] = [100, 1_000, 10_000, 600_000];
+
+/// Encode a transition time in milliseconds as the number of steps (6 bits) and the step
+/// resolution (2 bits), using the finest resolution the time fits in.
+fn transition_time_steps(ms: u32) -> Result<u8, ConversionError> {
+    const MAX_STEPS: u64 = 0x3E;
+    RESOLUTIONS
+        .iter()
+        .enumerate()
+        .find_map(|(i, &resolution)| {
+            // In 64 bits, so that rounding up can't overflow
+            let resolution = u64::from(resolution);
+            let steps = (u64::from(ms) + resolution / 2) / resolution;
+            (steps <= MAX_STEPS).then(|| ((i as u8) << 6) | steps as u8)
+        })
+        .ok_or_else(|| invalid("'transition_time' is too long"))
+}
+
+fn transition_time_ms(steps: u8) -> u32 {
+    (steps & 0x3F) as u32 * RESOLUTIONS[(steps >> 6) as usize]
+}
+
+/// Encode a delay in milliseconds as a number of 5 millisecond steps.
+fn delay_steps(ms: u32) -> Result<u8, ConversionError> {
+    u8::try_from((u64::from(ms) + 2) / 5).map_err(|_| invalid("'delay' is too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn convert(
+        command: serde_json::Value,
+        tids: &TidTracker,
+    ) -> Result<MeshEvent, ConversionError> {
+        let command: Command = serde_json::from_value(command).unwrap();
+        let raw = command.encode(tids)?;
+        assert_eq!(raw.address, Some(command.address));
+        Ok(MeshEvent::parse(&raw.opcode(), raw.parameters())
+            .unwrap()
+            .unwrap())
+    }
+
+    #[test]
+    fn test_display() {
+        let tids = TidTracker::default();
+        let command =
+            json!({"address": 171, "display": {"on": true, "transition_time": 1500, "delay": 100}});
+        match convert(command.clone(), &tids).unwrap() {
+            MeshEvent::OnOff(GenericOnOffMessage::Set(set)) => {
+                assert_eq!(set.on_off, 1);
+                assert_eq!(set.tid, 0);
+                // 15 steps of 100 milliseconds
+                assert_eq!(set.transition_time, Some(15));
+                assert_eq!(set.delay, Some(20));
+            }
+            event => panic!("unexpected {event:?}"),
+        }
+        match convert(command, &tids).unwrap() {
+            MeshEvent::OnOff(GenericOnOffMessage::Set(set)) => assert_eq!(set.tid, 1),
+            event => panic!("unexpected {event:?}"),
+        }
+
+        let command = json!({"address": 172, "display": {"on": false, "acknowledged": false}});
+        match convert(command, &tids).unwrap() {
+            MeshEvent::OnOff(GenericOnOffMessage::SetUnacknowledged(set)) => {
+                assert_eq!(set.on_off, 0);
+                assert_eq!(set.tid, 0);
+                assert_eq!(set.transition_time, None);
+            }
+            event => panic!("unexpected {event:?}"),
+        }
+    }
+
+    #[test]
+    fn test_transition_time() {
+        assert_eq!(transition_time_steps(0), Ok(0));
+        assert_eq!(transition_time_steps(6_200), Ok(62));
+        assert_eq!(transition_time_steps(6_300), Ok(0x40 | 6));
+        assert_eq!(transition_time_steps(120_000), Ok(0x80 | 12));
+        assert_eq!(transition_time_steps(3_600_000), Ok(0xC0 | 6));
+        assert_eq!(transition_time_steps(40 * 60_000), Ok(0xC0 | 4));
+        assert!(transition_time_steps(63 * 600_000).is_err());
+        assert!(transition_time_steps(u32::MAX).is_err());
+        assert_eq!(delay_steps(1_275), Ok(255));
+        assert!(delay_steps(1_280).is_err());
+        assert!(delay_steps(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn test_get() {
+        let tids = TidTracker::default();
+        assert!(matches!(
+            convert(json!({"address": 1, "get": {"model": "onoff"}}), &tids),
+            Ok(MeshEvent::OnOff(GenericOnOffMessage::Get))
+        ));
+        assert!(matches!(
+            convert(json!({"address": 1, "get": {"model": "battery"}}), &tids),
+            Ok(MeshEvent::Battery(GenericBatteryMessage::Get))
+        ));
+        assert!(matches!(
+            convert(json!({"address": 1, "get": {"model": "sensor", "property": "noise"}}), &tids),
+            Ok(MeshEvent::SensorQuery(SensorQuery::Get(Some(id)))) if id == NOISE.id
+        ));
+        assert!(matches!(
+            convert(
+                json!({"address": 1, "get": {"model": "sensor_descriptor"}}),
+                &tids
+            ),
+            Ok(MeshEvent::SensorQuery(SensorQuery::DescriptorGet(None)))
+        ));
+        assert!(matches!(
+            convert(json!({"address": 1, "get": {"model": "sensor_cadence", "property": "acceleration"}}), &tids),
+            Ok(MeshEvent::SensorSetup(SetupMessage::CadenceGet(id))) if id == ACCELERATION.id
+        ));
+        assert!(matches!(
+            convert(
+                json!({"address": 1, "get": {"model": "sensor_cadence"}}),
+                &tids
+            ),
+            Err(ConversionError::InvalidCommand(_))
+        ));
+        assert!(matches!(
+            convert(
+                json!({"address": 1, "get": {"model": "sensor", "property": "humidity"}}),
+                &tids
+            ),
+            Err(ConversionError::InvalidCommand(_))
+        ));
+    }
+
+    #[test]
+    fn test_sensor_cadence() {
+        let tids = TidTracker::default();
+        let command = json!({
+            "address": 1,
+            "sensor_cadence": {
+                "property": "acceleration",
+                "period_divisor": 2,
+                "trigger": {"value": {"down": [10, 10, 10], "up": [20, 20, 20]}},
+                "min_interval": 10,
+                "fast_low": [0, 0, 0],
+                "fast_high": [1000, 1000, 1000],
+            }
+        });
+        match convert(command, &tids).unwrap() {
+            MeshEvent::SensorSetup(SetupMessage::CadenceSet(id, cadence)) => {
+                assert_eq!(id, ACCELERATION.id);
+                assert_eq!(
+                    cadence.trigger,
+                    StatusTrigger::Value {
+                        down: Value::Vec3I16(10, 10, 10),
+                        up: Value::Vec3I16(20, 20, 20),
+                    }
+                );
+                assert_eq!(cadence.fast_high, Value::Vec3I16(1000, 1000, 1000));
+            }
+            event => panic!("unexpected {event:?}"),
+        }
+
+        let command = json!({
+            "address": 1,
+            "sensor_cadence": {
+                "property": "noise",
+                "period_divisor": 1,
+                "trigger": {"percent": {"down": 500, "up": 500}},
+                "min_interval": 10,
+                "fast_low": 300,
+                "fast_high": 255,
+            }
+        });
+        assert!(matches!(
+            convert(command, &tids),
+            Err(ConversionError::InvalidCommand(_))
+        ));
+    }
+}
@@ -0,0 +1,27 @@
+//! Conversion between raw mesh messages and their JSON representation, in both directions.
+use crate::{command::TidTracker, error::ConversionError};
+use btmesh_models::Message;
+use sensor_model::{MeshEvent, RawMessage};
+
+/// A JSON representation of mesh messages.
+///
+/// Decoding and encoding are inverse operations, so that the JSON produced by the converter can
+/// be turned back into the same mesh message, for replaying telemetry or simulating devices.
+pub trait MeshCodec: Sized {
+    /// Convert a mesh message to its JSON representation.
+    fn decode(message: &RawMessage) -> Result<Self, ConversionError>;
+
+    /// Convert to a mesh message, allocating transaction identifiers from `tids`.
+    fn encode(&self, tids: &TidTracker) -> Result<RawMessage, ConversionError>;
+}
+
+/// Parse the mesh message carried by a raw message.
+pub fn parse(message: &RawMessage) -> Result<MeshEvent, ConversionError> {
+    match MeshEvent::parse(&message.opcode(), message.parameters()) {
+        Ok(Some(event)) => Ok(event),
+        Ok(None) => Err(ConversionError::UnsupportedMessage(
+            message.opcode_bytes().to_vec(),
+        )),
+        Err(e) => Err(ConversionError::MalformedMessage(format!("{e:?}"))),
+    }
+}
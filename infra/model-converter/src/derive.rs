@@ -0,0 +1,449 @@
+//! Higher level events derived from the telemetry of each device.
+//!
+//! Button frames only report the edges of the button state, and sensor frames the raw
+//! acceleration. When enabled, the converter tracks them per element and reports clicks,
+//! double clicks and long presses of the button, and shakes, tilts and free falls of the device,
+//! alongside the converted state.
+//!
+//! Events are derived as frames arrive, without timers, so the second click of a double click
+//! is reported as both a click and a double click. Elements without any frame in the longest
+//! window of the gestures are forgotten, so a press held longer than that is not reported.
+use crate::convert::parse;
+use btmesh_models::{generic::onoff::GenericOnOffMessage, sensor::SensorMessage};
+use schemars::JsonSchema;
+use sensor_model::{MeshEvent, RawMessage, ACCELERATION};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// How long a transaction identifier identifies retransmissions of the same message, as in the
+/// generic models.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// Deviation from gravity, in standard gravity, below which the device is considered at rest,
+/// so that its orientation can be measured.
+const REST_TOLERANCE: f64 = 0.2;
+
+/// Thresholds of the derived events. Durations are in milliseconds, and accelerations in
+/// standard gravity.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeriveConfig {
+    /// Minimum duration of a press to be a long press rather than a click.
+    pub long_press: u64,
+    /// Maximum time between the release of a click and the press of the next one, for them to
+    /// form a double click.
+    pub double_click: u64,
+    /// Acceleration, beyond gravity, of a shaking movement.
+    pub shake_threshold: f64,
+    /// Number of readings beyond the shake threshold making a shake.
+    pub shake_count: usize,
+    /// Time within which these readings must happen.
+    pub shake_window: u64,
+    /// Angle from the vertical, in degrees, beyond which the device is tilted.
+    pub tilt_angle: f64,
+    /// Acceleration below which the device is falling.
+    pub freefall_threshold: f64,
+}
+
+impl Default for DeriveConfig {
+    fn default() -> Self {
+        Self {
+            long_press: 1000,
+            double_click: 400,
+            shake_threshold: 1.5,
+            shake_count: 2,
+            shake_window: 2000,
+            tilt_angle: 45.0,
+            freefall_threshold: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Gesture {
+    Click,
+    DoubleClick,
+    LongPress {
+        /// Duration of the press, in milliseconds.
+        duration: u64,
+    },
+    Shake,
+    Tilt {
+        /// Angle from the vertical, in degrees.
+        angle: f64,
+    },
+    Freefall,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct DerivedEvent {
+    #[serde(flatten)]
+    pub gesture: Gesture,
+    pub location: u16,
+}
+
+/// What is known of an element of a device.
+#[derive(Default)]
+struct ElementState {
+    /// When the last frame of the element was received.
+    last_seen: Option<SystemTime>,
+    /// Last transaction of the button, and when it was received.
+    transaction: Option<(u8, SystemTime)>,
+    pressed: Option<SystemTime>,
+    /// Release of the last click, which may start a double click.
+    last_click: Option<SystemTime>,
+    /// Readings beyond the shake threshold, in the shake window.
+    shakes: Vec<SystemTime>,
+    tilted: bool,
+    falling: bool,
+}
+
+pub struct EventDeriver {
+    config: DeriveConfig,
+    elements: Mutex<HashMap<(Option<u16>, u16), ElementState>>,
+}
+
+impl EventDeriver {
+    pub fn new(config: DeriveConfig) -> Self {
+        Self {
+            config,
+            elements: Default::default(),
+        }
+    }
+
+    /// Longest time the state of an element is needed for, between two of its frames.
+    fn longest_window(&self) -> Duration {
+        [
+            self.config.long_press,
+            self.config.double_click,
+            self.config.shake_window,
+        ]
+        .into_iter()
+        .map(Duration::from_millis)
+        .fold(TRANSACTION_TIMEOUT, Duration::max)
+    }
+
+    /// Track a message received at the given time, and return the events it completes.
+    pub fn derive(&self, message: &RawMessage, time: SystemTime) -> Vec<DerivedEvent> {
+        let longest = self.longest_window();
+        let mut elements = self.elements.lock().unwrap();
+        // Forget the elements without any frame in the longest window, such as the ones of
+        // devices that left the mesh
+        elements.retain(|_, element| {
+            element
+                .last_seen
+                .map_or(false, |seen| elapsed(seen, time) <= longest)
+        });
+        let element = elements
+            .entry((message.address, message.location))
+            .or_default();
+        element.last_seen = Some(time);
+        let gestures = match parse(message) {
+            Ok(MeshEvent::OnOff(
+                GenericOnOffMessage::Set(set) | GenericOnOffMessage::SetUnacknowledged(set),
+            )) => self.button(element, set.on_off == 1, set.tid, time),
+            Ok(MeshEvent::Sensor(SensorMessage::Status(status)))
+                if status.data.present.contains(ACCELERATION.id) =>
+            {
+                let acceleration = &status.data.acceleration;
+                let g = |v: i16| v as f64 / 1000.0;
+                let vector = [
+                    g(acceleration.x.0),
+                    g(acceleration.y.0),
+                    g(acceleration.z.0),
+                ];
+                self.motion(element, vector, time)
+            }
+            _ => Vec::new(),
+        };
+        gestures
+            .into_iter()
+            .map(|gesture| DerivedEvent {
+                gesture,
+                location: message.location,
+            })
+            .collect()
+    }
+
+    fn button(
+        &self,
+        element: &mut ElementState,
+        on: bool,
+        tid: u8,
+        time: SystemTime,
+    ) -> Vec<Gesture> {
+        // Retransmissions of the same transaction are not new edges
+        if let Some((last, received)) = element.transaction {
+            if last == tid && elapsed(received, time) < TRANSACTION_TIMEOUT {
+                return Vec::new();
+            }
+        }
+        element.transaction = Some((tid, time));
+
+        if on {
+            element.pressed = Some(time);
+            return Vec::new();
+        }
+        // A release without a press, if the press was lost
+        let pressed = match element.pressed.take() {
+            Some(pressed) => pressed,
+            None => return Vec::new(),
+        };
+        let duration = elapsed(pressed, time);
+        if duration >= Duration::from_millis(self.config.long_press) {
+            element.last_click = None;
+            return vec![Gesture::LongPress {
+                duration: duration.as_millis() as u64,
+            }];
+        }
+        let double = element.last_click.take().map_or(false, |last| {
+            elapsed(last, pressed) <= Duration::from_millis(self.config.double_click)
+        });
+        if double {
+            vec![Gesture::Click, Gesture::DoubleClick]
+        } else {
+            element.last_click = Some(time);
+            vec![Gesture::Click]
+        }
+    }
+
+    /// Track an acceleration, in standard gravity.
+    fn motion(
+        &self,
+        element: &mut ElementState,
+        vector: [f64; 3],
+        time: SystemTime,
+    ) -> Vec<Gesture> {
+        let config = &self.config;
+        let magnitude = vector.iter().map(|v| v * v).sum::<f64>().sqrt();
+        let mut gestures = Vec::new();
+
+        let falling = magnitude < config.freefall_threshold;
+        if falling && !element.falling {
+            gestures.push(Gesture::Freefall);
+        }
+        element.falling = falling;
+        // Without gravity, the orientation of the device is unknown
+        if falling {
+            return gestures;
+        }
+
+        let window = Duration::from_millis(config.shake_window);
+        element
+            .shakes
+            .retain(|shake| elapsed(*shake, time) <= window);
+        if magnitude - 1.0 > config.shake_threshold {
+            element.shakes.push(time);
+            if element.shakes.len() >= config.shake_count {
+                element.shakes.clear();
+                gestures.push(Gesture::Shake);
+            }
+        }
+
+        // The device only measures its orientation when at rest
+        if (magnitude - 1.0).abs() > REST_TOLERANCE {
+            return gestures;
+        }
+        let angle = (vector[2].abs() / magnitude).acos().to_degrees();
+        let tilted = angle > config.tilt_angle;
+        if tilted && !element.tilted {
+            gestures.push(Gesture::Tilt {
+                angle: (angle * 10.0).round() / 10.0,
+            });
+        }
+        element.tilted = tilted;
+        gestures
+    }
+}
+
+/// Time between two frames, zero if they arrived out of order.
+fn elapsed(earlier: SystemTime, later: SystemTime) -> Duration {
+    later.duration_since(earlier).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btmesh_models::{generic::onoff::Set as GenericOnOffSet, sensor::SensorStatus};
+    use sensor_model::{Acceleration, MilliG, SensorPayload};
+
+    const LOCATION: u16 = 0x0100;
+
+    fn at(millis: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000) + Duration::from_millis(millis)
+    }
+
+    /// Replay a recorded sequence of button frames: time in milliseconds, state and tid.
+    fn buttons(deriver: &EventDeriver, frames: &[(u64, bool, u8)]) -> Vec<Gesture> {
+        frames
+            .iter()
+            .flat_map(|(time, on, tid)| {
+                let set = GenericOnOffMessage::Set(GenericOnOffSet {
+                    on_off: u8::from(*on),
+                    tid: *tid,
+                    transition_time: None,
+                    delay: None,
+                });
+                let mut message = RawMessage::from_message(&set).unwrap();
+                message.address = Some(0x00ab);
+                message.location = LOCATION;
+                deriver.derive(&message, at(*time))
+            })
+            .map(|event| {
+                assert_eq!(event.location, LOCATION);
+                event.gesture
+            })
+            .collect()
+    }
+
+    /// Replay a recorded sequence of accelerations: time in milliseconds and milli-g.
+    fn motions(deriver: &EventDeriver, frames: &[(u64, [i16; 3])]) -> Vec<Gesture> {
+        frames
+            .iter()
+            .flat_map(|(time, [x, y, z])| {
+                let data = SensorPayload {
+                    acceleration: Acceleration {
+                        x: MilliG(*x),
+                        y: MilliG(*y),
+                        z: MilliG(*z),
+                    },
+                    ..Default::default()
+                };
+                let status = SensorMessage::Status(SensorStatus::new(data));
+                let mut message = RawMessage::from_message(&status).unwrap();
+                message.address = Some(0x00ab);
+                message.location = LOCATION;
+                deriver.derive(&message, at(*time))
+            })
+            .map(|event| event.gesture)
+            .collect()
+    }
+
+    #[test]
+    fn test_clicks() {
+        let deriver = EventDeriver::new(DeriveConfig::default());
+        let gestures = buttons(
+            &deriver,
+            &[
+                (0, true, 0),
+                (150, false, 1),
+                // Retransmission of the release
+                (170, false, 1),
+                (400, true, 2),
+                (520, false, 3),
+                // A third click starts over
+                (700, true, 4),
+                (800, false, 5),
+            ],
+        );
+        assert_eq!(
+            gestures,
+            vec![
+                Gesture::Click,
+                Gesture::Click,
+                Gesture::DoubleClick,
+                Gesture::Click
+            ]
+        );
+    }
+
+    #[test]
+    fn test_long_press() {
+        let deriver = EventDeriver::new(DeriveConfig::default());
+        let gestures = buttons(
+            &deriver,
+            &[
+                (0, true, 7),
+                (1500, false, 8),
+                // Too long after the previous release for a double click
+                (2000, true, 9),
+                (2100, false, 10),
+                (2600, true, 11),
+                (2700, false, 12),
+            ],
+        );
+        assert_eq!(
+            gestures,
+            vec![
+                Gesture::LongPress { duration: 1500 },
+                Gesture::Click,
+                Gesture::Click
+            ]
+        );
+
+        // A release whose press was lost is ignored
+        let gestures = buttons(&deriver, &[(3000, false, 13)]);
+        assert!(gestures.is_empty());
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let deriver = EventDeriver::new(DeriveConfig {
+            long_press: 300,
+            ..Default::default()
+        });
+        let gestures = buttons(&deriver, &[(0, true, 0), (400, false, 1)]);
+        assert_eq!(gestures, vec![Gesture::LongPress { duration: 400 }]);
+    }
+
+    #[test]
+    fn test_forget() {
+        let deriver = EventDeriver::new(DeriveConfig::default());
+        assert!(buttons(&deriver, &[(0, true, 0)]).is_empty());
+        let mut other = RawMessage::from_message(&GenericOnOffMessage::Get).unwrap();
+        other.address = Some(0x00ac);
+        other.location = LOCATION;
+        deriver.derive(&other, at(1000));
+        assert_eq!(deriver.elements.lock().unwrap().len(), 2);
+
+        // The transaction timeout is the longest window by default
+        deriver.derive(&other, at(7000));
+        assert_eq!(deriver.elements.lock().unwrap().len(), 1);
+        // So the press of the first device is forgotten
+        assert!(buttons(&deriver, &[(7100, false, 1)]).is_empty());
+    }
+
+    #[test]
+    fn test_motion() {
+        let deriver = EventDeriver::new(DeriveConfig::default());
+        let gestures = motions(
+            &deriver,
+            &[
+                // Resting flat
+                (0, [0, 0, 1000]),
+                (1000, [10, -20, 990]),
+                // Shaken
+                (2000, [2500, 300, 1000]),
+                (2500, [-2400, -200, 900]),
+                (3000, [0, 0, 1000]),
+                // Standing on its edge
+                (4000, [1000, 0, 0]),
+                (5000, [990, 0, 50]),
+                // Dropped
+                (6000, [50, 0, 100]),
+                (6100, [20, 10, 50]),
+                (7000, [0, 0, 1000]),
+            ],
+        );
+        assert_eq!(
+            gestures,
+            vec![
+                Gesture::Shake,
+                Gesture::Tilt { angle: 90.0 },
+                Gesture::Freefall
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        let config: DeriveConfig = serde_json::from_str(r#"{"tilt_angle": 30}"#).unwrap();
+        assert_eq!(config.tilt_angle, 30.0);
+        assert_eq!(config.long_press, DeriveConfig::default().long_press);
+    }
+}
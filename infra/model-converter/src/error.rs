@@ -0,0 +1,189 @@
+//! Reporting of events and commands that could not be converted.
+use actix_web::{http::StatusCode, HttpResponse};
+use cloudevents::{AttributesReader, Event};
+use prometheus::{IntCounterVec, Opts, Registry};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, fmt, str::FromStr, sync::Mutex};
+
+/// Reason a conversion failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversionError {
+    /// The event does not carry any data.
+    NoData,
+    UnsupportedContentType(String),
+    /// The data is not a valid raw message.
+    InvalidMessage(String),
+    /// The opcode does not belong to any of the models of the micro:bit.
+    UnsupportedMessage(Vec<u8>),
+    /// The message parameters are not valid for its opcode.
+    MalformedMessage(String),
+    /// The message is valid, but has no JSON representation.
+    Unconverted,
+    /// The JSON command is not understood.
+    InvalidCommand(String),
+    /// The JSON telemetry to encode is not understood.
+    InvalidTelemetry(String),
+    /// The converted raw message can't be written in the requested encoding.
+    EncodingFailed(String),
+}
+
+impl ConversionError {
+    /// Short, stable identifier of the failure, used as counter label and in the error type URI.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::NoData => "no-data",
+            Self::UnsupportedContentType(_) => "unsupported-content-type",
+            Self::InvalidMessage(_) => "invalid-message",
+            Self::UnsupportedMessage(_) => "unsupported-message",
+            Self::MalformedMessage(_) => "malformed-message",
+            Self::Unconverted => "unconverted",
+            Self::InvalidCommand(_) => "invalid-command",
+            Self::InvalidTelemetry(_) => "invalid-telemetry",
+            Self::EncodingFailed(_) => "encoding-failed",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Self::NoData => "Event has no data",
+            Self::UnsupportedContentType(_) => "Unsupported content type",
+            Self::InvalidMessage(_) => "Invalid raw message",
+            Self::UnsupportedMessage(_) => "Unsupported mesh message",
+            Self::MalformedMessage(_) => "Malformed mesh message",
+            Self::Unconverted => "Mesh message has no JSON representation",
+            Self::InvalidCommand(_) => "Invalid command",
+            Self::InvalidTelemetry(_) => "Invalid telemetry",
+            Self::EncodingFailed(_) => "Raw message can't be encoded",
+        }
+    }
+
+    /// Status of the response to a failed conversion. Only a failure to encode a valid message
+    /// is the fault of the converter.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::EncodingFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// The error as an RFC 7807 problem details document.
+    pub fn problem(&self) -> Value {
+        json!({
+            "type": format!("urn:eclipsecon:model-converter:{}", self.reason()),
+            "title": self.title(),
+            "status": self.status().as_u16(),
+            "detail": self.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoData => write!(f, "event has no data"),
+            Self::UnsupportedContentType(c) => write!(f, "unsupported content type '{c}'"),
+            Self::InvalidMessage(e) => write!(f, "invalid raw message: {e}"),
+            Self::UnsupportedMessage(opcode) => write!(f, "unsupported opcode {opcode:02x?}"),
+            Self::MalformedMessage(e) => write!(f, "malformed mesh message: {e}"),
+            Self::Unconverted => write!(f, "mesh message has no JSON representation"),
+            Self::InvalidCommand(e) => write!(f, "invalid command: {e}"),
+            Self::InvalidTelemetry(e) => write!(f, "invalid telemetry: {e}"),
+            Self::EncodingFailed(e) => write!(f, "failed to encode raw message: {e}"),
+        }
+    }
+}
+
+/// How conversion failures are reported to Drogue Cloud.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorMode {
+    /// Respond with `400 Bad Request`, or `500 Internal Server Error` if the converted message
+    /// can't be encoded, and a problem+json body.
+    Http,
+    /// Return the unconverted event, annotated with the `conversionerror` and
+    /// `conversionerrordetail` extension attributes.
+    #[default]
+    Annotate,
+}
+
+impl FromStr for ErrorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(Self::Http),
+            "annotate" => Ok(Self::Annotate),
+            _ => Err(format!(
+                "unknown error mode '{s}', expected 'http' or 'annotate'"
+            )),
+        }
+    }
+}
+
+impl ErrorMode {
+    /// Respond to a failed conversion of `event`.
+    pub fn respond(&self, event: Event, error: &ConversionError) -> Result<Event, HttpResponse> {
+        log::warn!("Failed to convert event {}: {error}", event.id());
+        match self {
+            Self::Http => Err(HttpResponse::build(error.status())
+                .content_type("application/problem+json")
+                .body(error.problem().to_string())),
+            Self::Annotate => Ok(annotate(event, error)),
+        }
+    }
+}
+
+/// Set the `conversionerror` and `conversionerrordetail` extension attributes of an event.
+pub fn annotate(mut event: Event, error: &ConversionError) -> Event {
+    event.set_extension("conversionerror", error.reason());
+    event.set_extension("conversionerrordetail", error.to_string());
+    event
+}
+
+/// Number of failed conversions, per direction and reason.
+pub struct ErrorCounters {
+    counts: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    /// The same counts, exported as Prometheus metrics.
+    counter: IntCounterVec,
+}
+
+impl Default for ErrorCounters {
+    fn default() -> Self {
+        let counter = IntCounterVec::new(
+            Opts::new(
+                "model_converter_conversion_errors_total",
+                "Number of failed conversions, per direction and reason",
+            ),
+            &["direction", "reason"],
+        )
+        .unwrap();
+        Self {
+            counts: Default::default(),
+            counter,
+        }
+    }
+}
+
+impl ErrorCounters {
+    pub fn increment(&self, direction: &'static str, error: &ConversionError) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts.entry((direction, error.reason())).or_default() += 1;
+        self.counter
+            .with_label_values(&[direction, error.reason()])
+            .inc();
+    }
+
+    /// Register the counters as Prometheus metrics.
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.counter.clone()))
+    }
+
+    /// The counters as a JSON object, keyed by direction and reason.
+    pub fn to_json(&self) -> Value {
+        let counts = self.counts.lock().unwrap();
+        let mut output = json!({});
+        for ((direction, reason), count) in counts.iter() {
+            output[*direction][*reason] = json!(count);
+        }
+        output
+    }
+}
@@ -1,129 +1,474 @@
-use actix_web::{get, post, App, HttpResponse, HttpServer};
-use btmesh_common::opcode::Opcode;
-use btmesh_models::{
-    generic::{
-        battery::{GenericBatteryClient, GenericBatteryFlagsPresence, GenericBatteryMessage},
-        onoff::{GenericOnOffMessage, GenericOnOffServer, Set as GenericOnOffSet},
-    },
-    sensor::SensorMessage,
-    Message, Model,
+use actix_web::{
+    get, guard::GuardContext, http::header, post, web, App, Either, HttpRequest, HttpResponse,
+    HttpServer,
 };
-use cloudevents::{Data, Event};
+use aggregate::*;
+use cloudevents::{AttributesReader, Data, Event};
+use command::*;
+use convert::*;
+use derive::*;
+use error::*;
+use futures::future;
+use metrics::{Metrics, Readiness};
+use openapi::Validator;
 use sensor_model::*;
 use serde_json::{json, Value};
+use std::{
+    str::FromStr,
+    time::{Instant, SystemTime},
+};
+use telemetry::*;
+use vendor::*;
 
-#[post("/telemetry")]
-async fn convert_telemetry(mut event: Event) -> Event {
-    log::info!("Received Event: {:?}", event);
-    if let Some(Data::Json(data)) = event.data() {
-        if let Ok(data) = serde_json::from_value::<RawMessage>(data.clone()) {
-            let converted = telemetry2json(data);
-            if let Some(state) = converted {
-                let output = json!({
+mod aggregate;
+mod command;
+mod convert;
+mod derive;
+mod error;
+mod metrics;
+mod openapi;
+mod stream;
+mod telemetry;
+mod vendor;
+
+/// Content type of a batch of events, in the CloudEvents batched content mode.
+const BATCH_CONTENT_TYPE: &str = "application/cloudevents-batch+json";
+
+/// Maximum size of a batch request, in bytes.
+const BATCH_LIMIT: usize = 64 * 1024 * 1024;
+
+/// A conversion offered by the converter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conversion {
+    Telemetry,
+    TelemetryEncode,
+    Command,
+    CommandDecode,
+}
+
+impl Conversion {
+    /// Name of the conversion, used as direction in the error counters.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Telemetry => "telemetry",
+            Self::TelemetryEncode => "telemetry-encode",
+            Self::Command => "command",
+            Self::CommandDecode => "command-decode",
+        }
+    }
+}
+
+impl FromStr for Conversion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Self::Telemetry,
+            Self::TelemetryEncode,
+            Self::Command,
+            Self::CommandDecode,
+        ]
+        .into_iter()
+        .find(|c| c.name() == s)
+        .ok_or_else(|| format!("unknown conversion '{s}'"))
+    }
+}
+
+/// State shared by the conversion endpoints.
+pub struct Converter {
+    /// Encoding of the raw messages produced, unless the request accepts another one.
+    encoding: ContentType,
+    mode: ErrorMode,
+    tids: TidTracker,
+    /// Transactions of the button presses encoded from telemetry, separate from the commands,
+    /// so that encoding telemetry doesn't change the identifiers of the commands sent.
+    telemetry_tids: TidTracker,
+    counters: ErrorCounters,
+    decoders: DecoderRegistry,
+    /// Rolling statistics of the sensor readings, if enabled.
+    aggregator: Option<Aggregator>,
+    /// Derivation of gestures from the telemetry, if enabled.
+    deriver: Option<EventDeriver>,
+    validator: Validator,
+    metrics: Metrics,
+    readiness: Readiness,
+}
+
+impl Converter {
+    /// A converter with the optional stages disabled and no vendor models.
+    fn new(encoding: ContentType, mode: ErrorMode) -> Self {
+        let counters = ErrorCounters::default();
+        Self {
+            encoding,
+            mode,
+            tids: TidTracker::default(),
+            telemetry_tids: TidTracker::default(),
+            metrics: Metrics::new(&counters),
+            counters,
+            decoders: DecoderRegistry::default(),
+            aggregator: None,
+            deriver: None,
+            validator: Validator::default(),
+            readiness: Readiness::default(),
+        }
+    }
+
+    /// Encoding of the raw messages produced for a request, the first of the `Accept` header
+    /// that is supported, or the configured one.
+    fn encoding(&self, request: &HttpRequest) -> ContentType {
+        request
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(negotiate)
+            .unwrap_or(self.encoding)
+    }
+
+    /// Convert the data of an event in place, recording the metrics of the conversion.
+    fn apply(
+        &self,
+        conversion: Conversion,
+        event: &mut Event,
+        encoding: ContentType,
+    ) -> Result<(), ConversionError> {
+        let start = Instant::now();
+        let result = self.convert_data(conversion, event, encoding);
+        let message = result
+            .as_ref()
+            .ok()
+            .map(|(opcode, address)| (&opcode[..], *address));
+        self.metrics
+            .observe(conversion.name(), start.elapsed(), message);
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.counters.increment(conversion.name(), &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Convert the data of an event in place, returning the opcode and address of the raw
+    /// message converted from or to. Raw messages are produced in the given encoding.
+    fn convert_data(
+        &self,
+        conversion: Conversion,
+        event: &mut Event,
+        encoding: ContentType,
+    ) -> Result<(Vec<u8>, Option<u16>), ConversionError> {
+        let message = match conversion {
+            Conversion::Telemetry => {
+                self.validate(event, "RawMessage", ConversionError::InvalidMessage)?;
+                let message = event2raw(event)?;
+                let address = message.address;
+                let converted = (message.opcode_bytes().to_vec(), address);
+                // Replayed events are tracked at the time they were produced
+                let time = event.time().map_or_else(SystemTime::now, |t| (*t).into());
+                let derived = match &self.deriver {
+                    Some(deriver) => deriver.derive(&message, time),
+                    None => Vec::new(),
+                };
+                // Descriptors take precedence, so that they can override the built-in models
+                let mut state = match self.decoders.decode(&message) {
+                    Some(state) => state?,
+                    None => telemetry2json(message)?,
+                };
+                if let Some(aggregator) = &self.aggregator {
+                    aggregator.annotate(address, time, &mut state);
+                }
+                let mut output = json!({
                     "state": state,
                     "partial": true,
                 });
+                if !derived.is_empty() {
+                    output["events"] = serde_json::to_value(derived).unwrap();
+                }
+                event.set_data("application/json", output);
+                converted
+            }
+            Conversion::TelemetryEncode => {
+                let data = event2json(event)?;
+                let output = match self.decoders.encode(data) {
+                    Some(output) => output?,
+                    None => {
+                        self.validator
+                            .validate("Telemetry", data)
+                            .map_err(ConversionError::InvalidTelemetry)?;
+                        json2telemetry(data, &self.telemetry_tids)?
+                    }
+                };
+                let converted = (output.opcode_bytes().to_vec(), output.address);
+                set_raw(event, encoding, output)?;
+                converted
+            }
+            Conversion::Command => {
+                self.validate(event, "Command", ConversionError::InvalidCommand)?;
+                let output = json2command(event2json(event)?, &self.tids)?;
+                log::info!("Converted message: {output:?}");
+                let converted = (output.opcode_bytes().to_vec(), output.address);
+                set_raw(event, encoding, output)?;
+                converted
+            }
+            Conversion::CommandDecode => {
+                self.validate(event, "RawMessage", ConversionError::InvalidMessage)?;
+                let message = event2raw(event)?;
+                let output = command2json(&message)?;
                 event.set_data("application/json", output);
+                (message.opcode_bytes().to_vec(), message.address)
             }
+        };
+        Ok(message)
+    }
+
+    /// Validate the JSON data of an event against a schema of the OpenAPI document. Raw messages
+    /// in the binary encodings are validated when decoded.
+    fn validate(
+        &self,
+        event: &Event,
+        schema: &str,
+        error: fn(String) -> ConversionError,
+    ) -> Result<(), ConversionError> {
+        match event.data() {
+            Some(Data::Json(data)) => self.validator.validate(schema, data).map_err(error),
+            _ => Ok(()),
         }
     }
-    event
-}
 
-#[post("/command")]
-async fn convert_command(mut event: Event) -> Event {
-    log::info!("Received Command: {:?}", event);
-    if let Some(Data::Json(data)) = event.data() {
-        if let Some(output) = json2command(data) {
-            log::info!("Converted message: {output:?}");
-            let output = serde_json::to_value(output).unwrap();
-            event.set_data("application/json", output);
+    fn convert(
+        &self,
+        conversion: Conversion,
+        mut event: Event,
+        encoding: ContentType,
+    ) -> Either<Event, HttpResponse> {
+        log::info!("Received {} event: {:?}", conversion.name(), event);
+        match self.apply(conversion, &mut event, encoding) {
+            Ok(()) => Either::Left(event),
+            Err(e) => self.mode.respond(event, &e).into(),
         }
     }
-    event
-}
 
-fn json2command(data: &Value) -> Option<RawMessage> {
-    if let Value::Object(data) = data {
-        if let Some(Value::Number(address)) = data.get("address") {
-            if let Some(Value::Object(state)) = data.get("display") {
-                let location = state["location"].as_u64().unwrap_or(0);
-                let on = state["on"].as_bool().unwrap_or(false);
-                let set = GenericOnOffSet {
-                    on_off: if on { 1 } else { 0 },
-                    tid: 0,
-                    transition_time: None,
-                    delay: None,
-                };
-                let msg = GenericOnOffMessage::Set(set);
-
-                let mut opcode: heapless::Vec<u8, 16> = heapless::Vec::new();
-                msg.opcode().emit(&mut opcode).unwrap();
-
-                let mut parameters: heapless::Vec<u8, 386> = heapless::Vec::new();
-                msg.emit_parameters(&mut parameters).unwrap();
-                let message = RawMessage {
-                    address: Some(address.as_u64().unwrap() as u16),
-                    location: location as u16,
-                    opcode: opcode.to_vec(),
-                    parameters: parameters.to_vec(),
-                };
-                return Some(message);
+    /// Convert an event, or annotate it with the conversion error if it fails.
+    fn convert_or_annotate(
+        &self,
+        conversion: Conversion,
+        mut event: Event,
+        encoding: ContentType,
+    ) -> Result<Event, Event> {
+        match self.apply(conversion, &mut event, encoding) {
+            Ok(()) => Ok(event),
+            Err(e) => {
+                log::warn!("Failed to convert event {}: {e}", event.id());
+                Err(annotate(event, &e))
             }
         }
     }
-    None
+
+    /// Convert a batch of events. A single response can't report the failure of some of the
+    /// events, so failed events are always annotated, whatever the error mode.
+    fn convert_batch(
+        &self,
+        conversion: Conversion,
+        events: Vec<Event>,
+        encoding: ContentType,
+    ) -> HttpResponse {
+        log::info!(
+            "Received batch of {} {} events",
+            events.len(),
+            conversion.name()
+        );
+        let events: Vec<Event> = events
+            .into_iter()
+            .map(
+                |event| match self.convert_or_annotate(conversion, event, encoding) {
+                    Ok(event) | Err(event) => event,
+                },
+            )
+            .collect();
+        HttpResponse::Ok()
+            .content_type(BATCH_CONTENT_TYPE)
+            .body(serde_json::to_string(&events).unwrap())
+    }
+}
+
+/// Whether the request carries a batch of events.
+fn is_batch(ctx: &GuardContext) -> bool {
+    ctx.head()
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with(BATCH_CONTENT_TYPE))
+}
+
+#[post("/telemetry", guard = "is_batch")]
+async fn convert_telemetry_batch(
+    request: HttpRequest,
+    events: web::Json<Vec<Event>>,
+    converter: web::Data<Converter>,
+) -> HttpResponse {
+    let encoding = converter.encoding(&request);
+    converter.convert_batch(Conversion::Telemetry, events.into_inner(), encoding)
+}
+
+#[post("/telemetry")]
+async fn convert_telemetry(
+    request: HttpRequest,
+    event: Event,
+    converter: web::Data<Converter>,
+) -> Either<Event, HttpResponse> {
+    let encoding = converter.encoding(&request);
+    converter.convert(Conversion::Telemetry, event, encoding)
+}
+
+#[post("/telemetry/encode", guard = "is_batch")]
+async fn encode_telemetry_batch(
+    request: HttpRequest,
+    events: web::Json<Vec<Event>>,
+    converter: web::Data<Converter>,
+) -> HttpResponse {
+    let encoding = converter.encoding(&request);
+    converter.convert_batch(Conversion::TelemetryEncode, events.into_inner(), encoding)
+}
+
+#[post("/telemetry/encode")]
+async fn encode_telemetry(
+    request: HttpRequest,
+    event: Event,
+    converter: web::Data<Converter>,
+) -> Either<Event, HttpResponse> {
+    let encoding = converter.encoding(&request);
+    converter.convert(Conversion::TelemetryEncode, event, encoding)
+}
+
+#[post("/command", guard = "is_batch")]
+async fn convert_command_batch(
+    request: HttpRequest,
+    events: web::Json<Vec<Event>>,
+    converter: web::Data<Converter>,
+) -> HttpResponse {
+    let encoding = converter.encoding(&request);
+    converter.convert_batch(Conversion::Command, events.into_inner(), encoding)
 }
 
-fn telemetry2json(msg: RawMessage) -> Option<Value> {
-    let (opcode, _) = Opcode::split(&msg.opcode[..]).unwrap();
-    let parameters = &msg.parameters[..];
-    let location = msg.location;
+#[post("/command")]
+async fn convert_command(
+    request: HttpRequest,
+    event: Event,
+    converter: web::Data<Converter>,
+) -> Either<Event, HttpResponse> {
+    let encoding = converter.encoding(&request);
+    converter.convert(Conversion::Command, event, encoding)
+}
+
+#[post("/command/decode", guard = "is_batch")]
+async fn decode_command_batch(
+    request: HttpRequest,
+    events: web::Json<Vec<Event>>,
+    converter: web::Data<Converter>,
+) -> HttpResponse {
+    let encoding = converter.encoding(&request);
+    converter.convert_batch(Conversion::CommandDecode, events.into_inner(), encoding)
+}
+
+#[post("/command/decode")]
+async fn decode_command(
+    request: HttpRequest,
+    event: Event,
+    converter: web::Data<Converter>,
+) -> Either<Event, HttpResponse> {
+    let encoding = converter.encoding(&request);
+    converter.convert(Conversion::CommandDecode, event, encoding)
+}
 
-    if let Ok(Some(GenericOnOffMessage::Set(set))) = GenericOnOffServer::parse(&opcode, parameters)
-    {
-        return Some(json!({ "button": {"on": set.on_off == 1, "location": location }}));
+/// Read the JSON data of an event.
+fn event2json(event: &Event) -> Result<&Value, ConversionError> {
+    match event.data() {
+        Some(Data::Json(data)) => Ok(data),
+        Some(_) => Err(ConversionError::UnsupportedContentType(
+            event.datacontenttype().unwrap_or_default().to_string(),
+        )),
+        None => Err(ConversionError::NoData),
     }
+}
+
+/// The supported encoding of raw messages with the highest quality in an `Accept` header, the
+/// first one listed on a tie. Wildcards don't select any, so that the configured encoding applies.
+fn negotiate(accept: &str) -> Option<ContentType> {
+    let mut accepted: Vec<(ContentType, f32)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parameters = range.split(';');
+            let encoding = ContentType::from_mime(parameters.next()?)?;
+            let quality = parameters
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            Some((encoding, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+    // The sort is stable, so ties keep the order of the header
+    accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+    accepted.first().map(|(encoding, _)| *encoding)
+}
 
-    if let Ok(Some(GenericOnOffMessage::SetUnacknowledged(set))) =
-        GenericOnOffServer::parse(&opcode, parameters)
-    {
-        return Some(json!({ "button": {"on": set.on_off == 1, "location": location }}));
+/// Set the raw message as data of an event, in the given encoding.
+fn set_raw(
+    event: &mut Event,
+    encoding: ContentType,
+    message: RawMessage,
+) -> Result<(), ConversionError> {
+    match encoding {
+        ContentType::Json => {
+            let output = serde_json::to_value(message)
+                .map_err(|e| ConversionError::EncodingFailed(e.to_string()))?;
+            event.set_data("application/json", output);
+        }
+        encoding => {
+            let output = encoding
+                .encode(&message)
+                .map_err(|e| ConversionError::EncodingFailed(e.to_string()))?;
+            event.set_data(encoding.mime(), output);
+        }
     }
+    Ok(())
+}
 
-    if let Ok(Some(SensorMessage::Status(mut status))) = SensorClient::parse(&opcode, parameters) {
-        log::info!("Received sensor status {:?}", status);
-        // Temperature is in half degrees
-        status.data.temperature /= 2;
-        return Some(json!( {
-            "sensor": {
-                "payload": serde_json::to_value(&status.data).unwrap(),
-                "location": location,
-            }
-        }));
-    }
-
-    if let Ok(Some(GenericBatteryMessage::Status(status))) =
-        GenericBatteryClient::parse(&opcode, parameters)
-    {
-        log::info!("Received battery status {:?}", status);
-        return Some(json!( {
-            "battery": {
-                "level": status.battery_level,
-                "flags": {
-                    "presence": match status.flags.presence {
-                        GenericBatteryFlagsPresence::NotPresent => "NotPresent",
-                        GenericBatteryFlagsPresence::PresentRemovable => "PresentRemovable",
-                        GenericBatteryFlagsPresence::PresentNotRemovable => "PresentNotRemovable",
-                        GenericBatteryFlagsPresence::Unknown => "Unknown",
-                    }
-                },
-                "location": location
-            },
-        }));
+/// Read the raw message of an event, using the encoding given by its content type.
+fn event2raw(event: &Event) -> Result<RawMessage, ConversionError> {
+    let invalid = |e: CodecError| ConversionError::InvalidMessage(e.to_string());
+    match event.data().ok_or(ConversionError::NoData)? {
+        Data::Json(data) => serde_json::from_value(data.clone())
+            .map_err(|e| ConversionError::InvalidMessage(e.to_string())),
+        Data::String(data) => ContentType::Json.decode(data.as_bytes()).map_err(invalid),
+        Data::Binary(data) => {
+            let content_type = event.datacontenttype().unwrap_or_default();
+            ContentType::from_mime(content_type)
+                .ok_or_else(|| ConversionError::UnsupportedContentType(content_type.to_string()))?
+                .decode(data)
+                .map_err(invalid)
+        }
     }
+}
+
+fn json2command(data: &Value, tids: &TidTracker) -> Result<RawMessage, ConversionError> {
+    let command: Command = serde_json::from_value(data.clone())
+        .map_err(|e| ConversionError::InvalidCommand(e.to_string()))?;
+    command.encode(tids)
+}
 
-    None
+fn command2json(message: &RawMessage) -> Result<Value, ConversionError> {
+    Ok(serde_json::to_value(Command::decode(message)?).unwrap())
+}
+
+fn telemetry2json(message: RawMessage) -> Result<Value, ConversionError> {
+    Ok(serde_json::to_value(Telemetry::decode(&message)?.state).unwrap())
+}
+
+/// Encode telemetry, in the format produced by `/telemetry`, optionally with the address of the
+/// device.
+fn json2telemetry(data: &Value, tids: &TidTracker) -> Result<RawMessage, ConversionError> {
+    let telemetry: Telemetry = serde_json::from_value(data.clone())
+        .map_err(|e| ConversionError::InvalidTelemetry(e.to_string()))?;
+    telemetry.encode(tids)
 }
 
 #[get("/healthz")]
@@ -131,26 +476,195 @@ async fn health() -> HttpResponse {
     HttpResponse::Ok().into()
 }
 
+#[get("/readyz")]
+async fn readiness(converter: web::Data<Converter>) -> HttpResponse {
+    let checks = converter.readiness.to_json();
+    if converter.readiness.is_ready() {
+        HttpResponse::Ok().json(checks)
+    } else {
+        HttpResponse::ServiceUnavailable().json(checks)
+    }
+}
+
+#[get("/metrics")]
+async fn prometheus_metrics(converter: web::Data<Converter>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(converter.metrics.render())
+}
+
+#[get("/errors")]
+async fn errors(converter: web::Data<Converter>) -> HttpResponse {
+    HttpResponse::Ok().json(converter.counters.to_json())
+}
+
+#[get("/schema/raw-message")]
+async fn raw_message_schema() -> HttpResponse {
+    HttpResponse::Ok().json(RawMessage::json_schema())
+}
+
+#[get("/decoders")]
+async fn list_decoders(converter: web::Data<Converter>) -> HttpResponse {
+    HttpResponse::Ok().json(converter.decoders.descriptors())
+}
+
+#[post("/decoders")]
+async fn register_decoder(
+    descriptor: web::Json<DecoderDescriptor>,
+    converter: web::Data<Converter>,
+) -> HttpResponse {
+    match converter.decoders.register(descriptor.into_inner()) {
+        Ok(()) => HttpResponse::NoContent().into(),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[get("/schema/decoder")]
+async fn decoder_schema() -> HttpResponse {
+    HttpResponse::Ok().json(DecoderDescriptor::json_schema())
+}
+
+#[get("/openapi.json")]
+async fn openapi_document(converter: web::Data<Converter>) -> HttpResponse {
+    HttpResponse::Ok().json(converter.validator.document())
+}
+
+#[get("/schema/command")]
+async fn command_schema() -> HttpResponse {
+    HttpResponse::Ok().json(Command::json_schema())
+}
+
+/// Register the endpoints of the converter.
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(health)
+        .service(readiness)
+        .service(prometheus_metrics)
+        .service(errors)
+        .service(openapi_document)
+        .service(raw_message_schema)
+        .service(command_schema)
+        .service(decoder_schema)
+        .service(list_decoders)
+        .service(register_decoder)
+        // Batches are matched first, as the single event extractor rejects them
+        .service(convert_telemetry_batch)
+        .service(convert_telemetry)
+        .service(encode_telemetry_batch)
+        .service(encode_telemetry)
+        .service(convert_command_batch)
+        .service(convert_command)
+        .service(decode_command_batch)
+        .service(decode_command);
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
-    HttpServer::new(|| {
+    // Encoding of the converted commands and encoded telemetry, unless the request accepts
+    // another one, the gateway decodes any supported content type
+    let encoding: ContentType = std::env::var("COMMAND_ENCODING")
+        .ok()
+        .map(|e| e.parse().expect("invalid COMMAND_ENCODING"))
+        .unwrap_or_default();
+    log::info!("Encoding raw messages as {encoding}");
+
+    let mode: ErrorMode = std::env::var("CONVERSION_ERRORS")
+        .ok()
+        .map(|m| m.parse().expect("invalid CONVERSION_ERRORS"))
+        .unwrap_or_default();
+    log::info!("Reporting conversion errors as {mode:?}");
+
+    let workers: usize = std::env::var("WORKERS")
+        .ok()
+        .map(|w| w.parse().expect("invalid WORKERS"))
+        .unwrap_or(1);
+    log::info!("Running {workers} workers");
+
+    // Windows of the sensor statistics, such as "1m,5m", no statistics if unset
+    let aggregator = std::env::var("AGGREGATION_WINDOWS").ok().map(|windows| {
+        let windows = windows
+            .split(',')
+            .map(|w| parse_window(w.trim()).expect("invalid AGGREGATION_WINDOWS"))
+            .collect();
+        Aggregator::new(windows)
+    });
+
+    // Thresholds of the derived events, as a JSON object, such as "{}" for the defaults
+    let deriver = std::env::var("DERIVED_EVENTS").ok().map(|config| {
+        let config: DeriveConfig = serde_json::from_str(&config).expect("invalid DERIVED_EVENTS");
+        log::info!("Deriving events with {config:?}");
+        EventDeriver::new(config)
+    });
+
+    let converter = Converter {
+        aggregator,
+        deriver,
+        ..Converter::new(encoding, mode)
+    };
+
+    // Descriptors of vendor models, from a comma separated list of JSON or TOML files. A file
+    // that fails to load makes the converter unready, rather than failing to start.
+    let mut failures = Vec::new();
+    for path in std::env::var("DECODERS").iter().flat_map(|d| d.split(',')) {
+        match converter.decoders.load(std::path::Path::new(path)) {
+            Ok(count) => log::info!("Loaded {count} decoders from {path}"),
+            Err(e) => failures.push(format!("failed to load {path}: {e}")),
+        }
+    }
+    converter.readiness.set(
+        "decoders",
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join("; "))
+        },
+    );
+    converter.readiness.set("config", Ok(()));
+    let converter = web::Data::new(converter);
+
+    let stream =
+        stream::StreamConfig::from_env().map(|config| stream::run(converter.clone(), config));
+
+    let server = HttpServer::new(move || {
         App::new()
+            .app_data(converter.clone())
+            .app_data(web::JsonConfig::default().limit(BATCH_LIMIT))
             .wrap(actix_web::middleware::Logger::default())
-            .service(health)
-            .service(convert_telemetry)
-            .service(convert_command)
+            .configure(routes)
     })
     .bind("0.0.0.0:8080")?
-    .workers(1)
-    .run()
-    .await
+    .workers(workers)
+    .run();
+
+    // In stream mode, the HTTP server keeps serving the health and error endpoints
+    match stream {
+        Some(stream) => {
+            futures::pin_mut!(server, stream);
+            match future::select(server, stream).await {
+                future::Either::Left((result, _)) => result,
+                future::Either::Right((result, _)) => {
+                    result.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+                }
+            }
+        }
+        None => server.await,
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use btmesh_models::sensor::SensorStatus;
+    use btmesh_models::{
+        generic::{
+            battery::{
+                GenericBatteryFlags, GenericBatteryFlagsCharging, GenericBatteryFlagsIndicator,
+                GenericBatteryFlagsPresence, GenericBatteryMessage, GenericBatteryStatus,
+            },
+            onoff::{GenericOnOffMessage, Set as GenericOnOffSet},
+        },
+        sensor::SensorStatus,
+    };
+    use proptest::prelude::*;
     use sensor_model::SensorMessage;
 
     use super::*;
@@ -159,16 +673,8 @@ mod tests {
     fn test_sensor_codec() {
         let data = SensorPayload::default();
         let msg: SensorMessage = SensorMessage::Status(SensorStatus::new(data));
-        let mut opcode: heapless::Vec<u8, 16> = heapless::Vec::new();
-        msg.opcode().emit(&mut opcode).unwrap();
-        let mut parameters: heapless::Vec<u8, 386> = heapless::Vec::new();
-        msg.emit_parameters(&mut parameters).unwrap();
-        let message = RawMessage {
-            address: 0,
-            location: 0,
-            opcode: opcode.to_vec(),
-            parameters: parameters.to_vec(),
-        };
+        let mut message = RawMessage::from_message(&msg).unwrap();
+        message.address = Some(0);
 
         let data = serde_json::to_value(&message).unwrap();
 
@@ -178,4 +684,368 @@ mod tests {
         let parsed = telemetry2json(raw).unwrap();
         println!("Parsed: {:?}", parsed);
     }
+
+    #[test]
+    fn test_binary_telemetry() {
+        use cloudevents::{EventBuilder, EventBuilderV10};
+
+        let message = RawMessage::try_from(UncheckedRawMessage {
+            address: Some(0x00ab),
+            location: 0x0100,
+            opcode: vec![0x82, 0x03],
+            parameters: vec![0x01, 0x00],
+            ..Default::default()
+        })
+        .unwrap();
+        for encoding in ContentType::ALL {
+            let event = EventBuilderV10::new()
+                .id("1")
+                .source("sensor/00ab")
+                .ty("io.drogue.event.v1")
+                .data(encoding.mime(), encoding.encode(&message).unwrap())
+                .build()
+                .unwrap();
+            assert_eq!(event2raw(&event), Ok(message.clone()), "{encoding}");
+        }
+    }
+
+    #[test]
+    fn test_conversion_errors() {
+        let unknown = RawMessage::try_from(UncheckedRawMessage {
+            opcode: vec![0x82, 0xff],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            telemetry2json(unknown),
+            Err(ConversionError::UnsupportedMessage(vec![0x82, 0xff]))
+        );
+
+        let malformed = RawMessage::try_from(UncheckedRawMessage {
+            opcode: vec![0x82, 0x03],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(matches!(
+            telemetry2json(malformed),
+            Err(ConversionError::MalformedMessage(_))
+        ));
+
+        let tids = TidTracker::default();
+        assert!(matches!(
+            json2command(&json!({"display": {"on": true}}), &tids),
+            Err(ConversionError::InvalidCommand(_))
+        ));
+        assert!(matches!(
+            json2command(&json!({"address": 171, "unknown": {}}), &tids),
+            Err(ConversionError::InvalidCommand(_))
+        ));
+
+        let problem = ConversionError::NoData.problem();
+        assert_eq!(problem["status"], json!(400));
+        assert_eq!(
+            problem["type"],
+            json!("urn:eclipsecon:model-converter:no-data")
+        );
+
+        let problem = ConversionError::EncodingFailed("too large".to_string()).problem();
+        assert_eq!(problem["status"], json!(500));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("application/cbor"), Some(ContentType::Cbor));
+        assert_eq!(
+            negotiate("application/json, application/x-postcard"),
+            Some(ContentType::Json)
+        );
+        assert_eq!(
+            negotiate("application/json;q=0.5, application/x-postcard"),
+            Some(ContentType::Postcard)
+        );
+        assert_eq!(
+            negotiate("application/cbor;q=0, application/json;q=0.1"),
+            Some(ContentType::Json)
+        );
+        assert_eq!(negotiate("*/*"), None);
+        assert_eq!(negotiate("text/plain, application/*"), None);
+    }
+
+    #[test]
+    fn test_error_counters() {
+        let counters = ErrorCounters::default();
+        counters.increment("telemetry", &ConversionError::NoData);
+        counters.increment("telemetry", &ConversionError::NoData);
+        counters.increment("command", &ConversionError::Unconverted);
+
+        assert_eq!(
+            counters.to_json(),
+            json!({"telemetry": {"no-data": 2}, "command": {"unconverted": 1}})
+        );
+    }
+
+    #[test]
+    fn test_partial_sensor_payload() {
+        let mut data = SensorPayload::default();
+        data.try_decode(TEMPERATURE.id, &[45]).unwrap();
+        data.try_decode(NOISE.id, &[7]).unwrap();
+
+        assert_eq!(sensor2json(data), json!({"temperature": 22.5, "noise": 7}));
+    }
+
+    #[test]
+    fn test_sensor_units() {
+        let mut data = SensorPayload::default();
+        data.try_decode(ACCELERATION.id, &[0xE8, 0x03, 0x0C, 0xFE, 0x00, 0x00])
+            .unwrap();
+
+        let json = sensor2json(data);
+        let acceleration = |axis: &str| json["acceleration"][axis].as_f64().unwrap();
+        assert!((acceleration("x") - 9.80665).abs() < 1e-9);
+        assert!((acceleration("y") + 4.903325).abs() < 1e-9);
+        assert_eq!(acceleration("z"), 0.0);
+        assert!(json.get("temperature").is_none());
+    }
+
+    #[test]
+    fn test_command_roundtrip() {
+        let tids = TidTracker::default();
+        for command in [
+            json!({"address": 171, "display": {"on": true, "location": 0, "transition_time": 1500, "delay": 100, "acknowledged": true}}),
+            json!({"address": 171, "display": {"on": false, "location": 0, "acknowledged": false}}),
+            json!({"address": 171, "get": {"model": "battery", "location": 0}}),
+            json!({"address": 171, "get": {"model": "sensor", "location": 0, "property": "noise"}}),
+            json!({
+                "address": 171,
+                "sensor_cadence": {
+                    "property": "noise",
+                    "location": 0,
+                    "period_divisor": 2,
+                    "trigger": {"percent": {"down": 500, "up": 1000}},
+                    "min_interval": 10,
+                    "fast_low": 100,
+                    "fast_high": 255,
+                    "acknowledged": true,
+                }
+            }),
+        ] {
+            let message = json2command(&command, &tids).unwrap();
+            assert_eq!(command2json(&message), Ok(command));
+        }
+
+        // Telemetry is not a command
+        let status = SensorMessage::Status(SensorStatus::new(SensorPayload::default()));
+        let mut message = RawMessage::from_message(&status).unwrap();
+        message.address = Some(171);
+        assert_eq!(command2json(&message), Err(ConversionError::Unconverted));
+    }
+
+    #[test]
+    fn test_invalid_telemetry() {
+        let tids = TidTracker::default();
+        assert!(matches!(
+            json2telemetry(
+                &json!({"state": {"sensor": {"payload": {"humidity": 40}, "location": 256}}}),
+                &tids
+            ),
+            Err(ConversionError::InvalidTelemetry(_))
+        ));
+        assert!(matches!(
+            json2telemetry(&json!({"state": {"unknown": {}}}), &tids),
+            Err(ConversionError::InvalidTelemetry(_))
+        ));
+    }
+
+    #[test]
+    fn test_telemetry_tids() {
+        use cloudevents::{EventBuilder, EventBuilderV10};
+
+        let converter = Converter::new(ContentType::Json, ErrorMode::Http);
+        let convert = |conversion, data: Value| {
+            let mut event = EventBuilderV10::new()
+                .id("1")
+                .source("tid-test")
+                .ty("io.drogue.event.v1")
+                .data("application/json", data)
+                .build()
+                .unwrap();
+            converter
+                .apply(conversion, &mut event, ContentType::Json)
+                .unwrap();
+            event2raw(&event).unwrap()
+        };
+
+        // Encoding button presses leaves the transactions of the commands alone
+        let press = json!({"state": {"button": {"on": true, "location": 269}}, "address": 171});
+        convert(Conversion::TelemetryEncode, press.clone());
+        convert(Conversion::TelemetryEncode, press);
+        let command = json!({"address": 171, "display": {"on": true}});
+        assert_eq!(
+            convert(Conversion::Command, command.clone()),
+            json2command(&command, &TidTracker::default()).unwrap()
+        );
+    }
+
+    fn telemetry_roundtrip(message: RawMessage, address: u16) -> Result<(), TestCaseError> {
+        let mut message = message;
+        message.address = Some(address);
+        message.location = 0x0100;
+        let state = telemetry2json(message.clone()).unwrap();
+        let encoded = json2telemetry(
+            &json!({"state": state, "partial": true, "address": address}),
+            &TidTracker::default(),
+        );
+        prop_assert_eq!(encoded, Ok(message));
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn test_sensor_roundtrip(address: u16, temperature: i8, x: i16, y: i16, z: i16, noise: u8) {
+            let data = SensorPayload {
+                temperature: HalfDegreesCelsius(temperature),
+                acceleration: Acceleration {
+                    x: MilliG(x),
+                    y: MilliG(y),
+                    z: MilliG(z),
+                },
+                noise: NoiseLevel(noise),
+                present: PropertySet::all(),
+            };
+            let status = SensorMessage::Status(SensorStatus::new(data));
+            telemetry_roundtrip(RawMessage::from_message(&status).unwrap(), address)?;
+        }
+
+        #[test]
+        fn test_battery_roundtrip(address: u16, level: u8, removable: bool) {
+            let status = GenericBatteryMessage::Status(GenericBatteryStatus::new(
+                level,
+                0,
+                0,
+                GenericBatteryFlags {
+                    presence: if removable {
+                        GenericBatteryFlagsPresence::PresentRemovable
+                    } else {
+                        GenericBatteryFlagsPresence::PresentNotRemovable
+                    },
+                    indicator: GenericBatteryFlagsIndicator::Unknown,
+                    charging: GenericBatteryFlagsCharging::Unknown,
+                },
+            ));
+            telemetry_roundtrip(RawMessage::from_message(&status).unwrap(), address)?;
+        }
+
+        #[test]
+        fn test_button_roundtrip(address: u16, on: bool) {
+            // The first transaction of a device
+            let set = GenericOnOffMessage::Set(GenericOnOffSet {
+                on_off: if on { 1 } else { 0 },
+                tid: 0,
+                transition_time: None,
+                delay: None,
+            });
+            telemetry_roundtrip(RawMessage::from_message(&set).unwrap(), address)?;
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_batch() {
+        use actix_web::test;
+        use cloudevents::{event::ExtensionValue, EventBuilder, EventBuilderV10};
+
+        let converter = web::Data::new(Converter::new(ContentType::Json, ErrorMode::Http));
+        let app = test::init_service(
+            App::new()
+                .app_data(converter.clone())
+                .service(convert_telemetry_batch)
+                .service(convert_telemetry),
+        )
+        .await;
+
+        let status = SensorMessage::Status(SensorStatus::new(SensorPayload::default()));
+        let message = RawMessage::from_message(&status).unwrap();
+        let event = |id: &str| {
+            EventBuilderV10::new()
+                .id(id)
+                .source("sensor/00ab")
+                .ty("io.drogue.event.v1")
+        };
+        let events = vec![
+            event("1")
+                .data("application/json", serde_json::to_value(&message).unwrap())
+                .build()
+                .unwrap(),
+            event("2").build().unwrap(),
+        ];
+
+        let request = test::TestRequest::post()
+            .uri("/telemetry")
+            .insert_header((header::CONTENT_TYPE, BATCH_CONTENT_TYPE))
+            .set_payload(serde_json::to_vec(&events).unwrap())
+            .to_request();
+        let converted: Vec<Event> = test::call_and_read_body_json(&app, request).await;
+
+        assert_eq!(converted.len(), 2);
+        assert!(converted[0].extension("conversionerror").is_none());
+        assert!(
+            matches!(converted[0].data(), Some(Data::Json(data)) if data["state"]["sensor"].is_object())
+        );
+        // Failed events are annotated, even in the HTTP error mode
+        assert!(matches!(
+            converted[1].extension("conversionerror"),
+            Some(ExtensionValue::String(reason)) if reason == "no-data"
+        ));
+        assert_eq!(
+            converter.counters.to_json(),
+            json!({"telemetry": {"no-data": 1}})
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_accept() {
+        use actix_web::test;
+
+        let converter = web::Data::new(Converter::new(ContentType::Json, ErrorMode::Http));
+        let app = test::init_service(
+            App::new()
+                .app_data(converter.clone())
+                .service(convert_command),
+        )
+        .await;
+        let request = |accept: Option<&str>| {
+            let mut request = test::TestRequest::post()
+                .uri("/command")
+                .insert_header(("ce-specversion", "1.0"))
+                .insert_header(("ce-id", "1"))
+                .insert_header(("ce-source", "accept-test"))
+                .insert_header(("ce-type", "io.drogue.command.v1"));
+            if let Some(accept) = accept {
+                request = request.insert_header((header::ACCEPT, accept));
+            }
+            request
+                .set_json(&json!({"address": 171, "display": {"on": true}}))
+                .to_request()
+        };
+
+        // The configured encoding, unless the request accepts another one
+        for (accept, encoding) in [
+            (None, ContentType::Json),
+            (Some("*/*"), ContentType::Json),
+            (Some("application/cbor"), ContentType::Cbor),
+            (
+                Some("application/json;q=0.2, application/x-postcard"),
+                ContentType::Postcard,
+            ),
+        ] {
+            let response = test::call_service(&app, request(accept)).await;
+            assert!(response.status().is_success(), "{accept:?}");
+            assert_eq!(
+                response.headers().get(header::CONTENT_TYPE).unwrap(),
+                encoding.mime(),
+                "{accept:?}"
+            );
+            let message = encoding.decode(&test::read_body(response).await).unwrap();
+            assert_eq!(message.address, Some(171), "{accept:?}");
+        }
+    }
 }
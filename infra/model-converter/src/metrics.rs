@@ -0,0 +1,238 @@
+//! Prometheus metrics of the conversions, and readiness of the converter.
+use crate::error::ErrorCounters;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+/// Buckets of the conversion latency, in seconds. Conversions take microseconds, unless
+/// something is wrong.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.000_01, 0.000_025, 0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01,
+];
+
+/// How long the last seen time of a device is exported after its last telemetry, so that the
+/// devices which left the mesh don't accumulate.
+const DEVICE_EXPIRY: Duration = Duration::from_secs(24 * 3600);
+
+pub struct Metrics {
+    registry: Registry,
+    conversions: IntCounterVec,
+    latency: HistogramVec,
+    last_seen: GaugeVec,
+    /// Time of the last telemetry of the devices exported in `last_seen`.
+    devices: Mutex<HashMap<u16, SystemTime>>,
+}
+
+impl Metrics {
+    /// Create the metrics, exporting the error counters with them.
+    pub fn new(counters: &ErrorCounters) -> Self {
+        let registry = Registry::new();
+        let conversions = IntCounterVec::new(
+            Opts::new(
+                "model_converter_conversions_total",
+                "Number of successful conversions, per direction and opcode of the raw message",
+            ),
+            &["direction", "opcode"],
+        )
+        .unwrap();
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "model_converter_conversion_duration_seconds",
+                "Duration of the conversions, successful or not, per direction",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["direction"],
+        )
+        .unwrap();
+        let last_seen = GaugeVec::new(
+            Opts::new(
+                "model_converter_device_last_seen_seconds",
+                "Time of the last telemetry converted for a device, in seconds since the epoch",
+            ),
+            &["address"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(conversions.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        registry.register(Box::new(last_seen.clone())).unwrap();
+        counters.register(&registry).unwrap();
+        Self {
+            registry,
+            conversions,
+            latency,
+            last_seen,
+            devices: Default::default(),
+        }
+    }
+
+    /// Record a conversion. `message` is the opcode and address of the raw message converted
+    /// from or to, if the conversion succeeded.
+    pub fn observe(
+        &self,
+        direction: &str,
+        duration: Duration,
+        message: Option<(&[u8], Option<u16>)>,
+    ) {
+        self.latency
+            .with_label_values(&[direction])
+            .observe(duration.as_secs_f64());
+        if let Some((opcode, address)) = message {
+            self.conversions
+                .with_label_values(&[direction, &hex::encode(opcode)])
+                .inc();
+            if let (Some(address), "telemetry") = (address, direction) {
+                self.seen(address, SystemTime::now());
+            }
+        }
+    }
+
+    /// Record the telemetry of a device, and stop exporting the devices idle for too long.
+    fn seen(&self, address: u16, time: SystemTime) {
+        let label = |address: u16| format!("{address:04x}");
+        let mut devices = self.devices.lock().unwrap();
+        devices.insert(address, time);
+        devices.retain(|address, seen| {
+            let idle = time
+                .duration_since(*seen)
+                .map_or(false, |age| age > DEVICE_EXPIRY);
+            if idle {
+                let _ = self.last_seen.remove_label_values(&[&label(*address)]);
+            }
+            !idle
+        });
+        let since_epoch = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_seen
+            .with_label_values(&[&label(address)])
+            .set(since_epoch.as_secs_f64());
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+/// Whether the converter can convert events: the result of each of its readiness checks.
+#[derive(Default)]
+pub struct Readiness {
+    checks: RwLock<BTreeMap<&'static str, Result<(), String>>>,
+}
+
+impl Readiness {
+    pub fn set(&self, check: &'static str, result: Result<(), String>) {
+        if let Err(e) = &result {
+            log::warn!("Not ready, {check} failed: {e}");
+        }
+        self.checks.write().unwrap().insert(check, result);
+    }
+
+    /// Ready once all checks passed. No checks at all means the configuration is still being
+    /// loaded.
+    pub fn is_ready(&self) -> bool {
+        let checks = self.checks.read().unwrap();
+        !checks.is_empty() && checks.values().all(Result::is_ok)
+    }
+
+    /// The checks as a JSON object, `ok` or the reason of the failure.
+    pub fn to_json(&self) -> Value {
+        let checks = self.checks.read().unwrap();
+        let mut output = json!({});
+        for (check, result) in checks.iter() {
+            output[*check] = match result {
+                Ok(()) => json!("ok"),
+                Err(e) => json!(e),
+            };
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ConversionError;
+
+    #[test]
+    fn test_metrics() {
+        let counters = ErrorCounters::default();
+        let metrics = Metrics::new(&counters);
+        let duration = Duration::from_micros(30);
+        metrics.observe(
+            "telemetry",
+            duration,
+            Some((&[0x82, 0x03][..], Some(0x00ab))),
+        );
+        metrics.observe(
+            "telemetry",
+            duration,
+            Some((&[0x82, 0x03][..], Some(0x00ac))),
+        );
+        metrics.observe("command", duration, Some((&[0x82, 0x02][..], Some(0x00ab))));
+        metrics.observe("command", duration, None);
+        counters.increment("command", &ConversionError::NoData);
+
+        let text = metrics.render();
+        assert!(text.contains(
+            r#"model_converter_conversions_total{direction="telemetry",opcode="8203"} 2"#
+        ));
+        assert!(text
+            .contains(r#"model_converter_conversions_total{direction="command",opcode="8202"} 1"#));
+        assert!(text.contains(
+            r#"model_converter_conversion_duration_seconds_count{direction="command"} 2"#
+        ));
+        assert!(text.contains(
+            r#"model_converter_conversion_errors_total{direction="command",reason="no-data"} 1"#
+        ));
+        // Commands are sent to devices, they don't tell whether they are alive
+        assert!(text.contains(r#"model_converter_device_last_seen_seconds{address="00ac"}"#));
+        assert_eq!(
+            text.matches("model_converter_device_last_seen_seconds{")
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_device_expiry() {
+        let metrics = Metrics::new(&ErrorCounters::default());
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_660_000_000);
+        metrics.seen(0x00ab, start);
+        metrics.seen(0x00ac, start + DEVICE_EXPIRY);
+        let text = metrics.render();
+        assert!(text.contains(r#"model_converter_device_last_seen_seconds{address="00ab"}"#));
+
+        metrics.seen(0x00ac, start + DEVICE_EXPIRY + Duration::from_secs(1));
+        let text = metrics.render();
+        assert!(!text.contains(r#"model_converter_device_last_seen_seconds{address="00ab"}"#));
+        assert!(text.contains(r#"model_converter_device_last_seen_seconds{address="00ac"}"#));
+    }
+
+    #[test]
+    fn test_readiness() {
+        let readiness = Readiness::default();
+        assert!(!readiness.is_ready());
+
+        readiness.set("config", Ok(()));
+        assert!(readiness.is_ready());
+
+        readiness.set("decoders", Err("failed to load decoders.toml".to_string()));
+        assert!(!readiness.is_ready());
+        assert_eq!(
+            readiness.to_json(),
+            json!({"config": "ok", "decoders": "failed to load decoders.toml"})
+        );
+    }
+}
@@ -0,0 +1,564 @@
+//! OpenAPI document of the HTTP interface, and validation of the converted data against it.
+//!
+//! The schemas of the document are generated from the types used by the conversions, so the
+//! document can't drift from the implementation. Events are exchanged in the binary content
+//! mode of CloudEvents: the attributes are `ce-` headers, and the body is the data of the event.
+use crate::{
+    command::Command, derive::DerivedEvent, telemetry::Telemetry, vendor::DecoderDescriptor,
+};
+use jsonschema::JSONSchema;
+use schemars::schema::RootSchema;
+use sensor_model::{ContentType, RawMessage};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// Prefix of the references to the schemas of the document.
+const COMPONENTS: &str = "#/components/schemas/";
+
+/// The schemas validating the data of the conversions, by name in the document.
+const VALIDATED: &[&str] = &["RawMessage", "Command", "Telemetry"];
+
+fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("{COMPONENTS}{name}") })
+}
+
+/// Move a generated schema, and the schemas it refers to, to the components of the document.
+fn add_schema(schemas: &mut Map<String, Value>, name: &str, root: RootSchema) {
+    let mut value = serde_json::to_value(root).unwrap();
+    let root = value.as_object_mut().unwrap();
+    root.remove("$schema");
+    if let Some(Value::Object(definitions)) = root.remove("definitions") {
+        schemas.extend(definitions);
+    }
+    schemas.insert(name.to_string(), value);
+}
+
+/// Rewrite the references to the definitions of the generated schemas, and open the variants
+/// of enums flattened in a struct: schemars closes the variants of externally tagged enums,
+/// which would reject the fields of the struct.
+fn fix_schema(schema: &mut Value) {
+    match schema {
+        Value::Object(object) => {
+            if let Some(Value::String(reference)) = object.get_mut("$ref") {
+                if let Some(name) = reference.strip_prefix("#/definitions/") {
+                    *reference = format!("{COMPONENTS}{name}");
+                }
+            }
+            if object.contains_key("properties") {
+                for key in ["oneOf", "anyOf"] {
+                    if let Some(Value::Array(variants)) = object.get_mut(key) {
+                        for variant in variants.iter_mut().filter_map(Value::as_object_mut) {
+                            variant.remove("additionalProperties");
+                        }
+                    }
+                }
+            }
+            object.values_mut().for_each(fix_schema);
+        }
+        Value::Array(values) => values.iter_mut().for_each(fix_schema),
+        _ => {}
+    }
+}
+
+/// Examples of each conversion: path, request and response data.
+pub fn examples() -> Vec<(&'static str, Value, Value)> {
+    vec![
+        (
+            "/telemetry",
+            json!({"address": 171, "location": 256, "opcode": [130, 3], "parameters": [1, 0]}),
+            json!({"state": {"button": {"on": true, "location": 256}}, "partial": true}),
+        ),
+        (
+            "/telemetry/encode",
+            json!({"state": {"button": {"on": true, "location": 256}}, "address": 172}),
+            json!({"version": 1, "address": 172, "location": 256, "opcode": [130, 2], "parameters": [1, 0]}),
+        ),
+        (
+            "/command",
+            json!({"address": 171, "display": {"on": true}}),
+            json!({"version": 1, "address": 171, "location": 0, "opcode": [130, 2], "parameters": [1, 0]}),
+        ),
+        (
+            "/command/decode",
+            json!({"address": 171, "location": 0, "opcode": [130, 2], "parameters": [1, 0]}),
+            json!({"address": 171, "display": {"on": true, "location": 0, "acknowledged": true}}),
+        ),
+    ]
+}
+
+/// Request body of a conversion: the data in JSON, or a raw message in any of its encodings.
+fn request_body(schema_name: &str, example: &Value) -> Value {
+    let mut content = Map::new();
+    content.insert(
+        "application/json".to_string(),
+        json!({"schema": schema(schema_name), "example": example}),
+    );
+    if schema_name == "RawMessage" {
+        for encoding in ContentType::ALL
+            .into_iter()
+            .filter(|e| *e != ContentType::Json)
+        {
+            content.insert(
+                encoding.mime().to_string(),
+                json!({"schema": {"description": format!("Raw message encoded as {encoding}")}}),
+            );
+        }
+    }
+    content.insert(
+        crate::BATCH_CONTENT_TYPE.to_string(),
+        json!({"schema": {"type": "array", "items": schema("CloudEvent")}}),
+    );
+    json!({ "required": true, "content": content })
+}
+
+fn conversion(
+    summary: &str,
+    request: &str,
+    response: &Value,
+    example: &(&str, Value, Value),
+) -> Value {
+    let (_, request_example, response_example) = example;
+    // Raw messages are produced in the encoding negotiated with the `Accept` header
+    let produces_raw = *response == schema("RawMessage");
+    let mut parameters = vec!["ce-specversion", "ce-id", "ce-source", "ce-type"];
+    let mut response_content = Map::new();
+    response_content.insert(
+        "application/json".to_string(),
+        json!({"schema": response, "example": response_example}),
+    );
+    if produces_raw {
+        parameters.push("accept");
+        for encoding in ContentType::ALL
+            .into_iter()
+            .filter(|e| *e != ContentType::Json)
+        {
+            response_content.insert(
+                encoding.mime().to_string(),
+                json!({"schema": {"description": format!("Raw message encoded as {encoding}")}}),
+            );
+        }
+    }
+    response_content.insert(
+        crate::BATCH_CONTENT_TYPE.to_string(),
+        json!({"schema": {"type": "array", "items": schema("CloudEvent")}}),
+    );
+    let mut responses = json!({
+        "200": {
+            "description": concat!(
+                "The converted event, or the unconverted event annotated with the ",
+                "conversion error if `CONVERSION_ERRORS` is `annotate`. ",
+                "The events in the same order for a batch.",
+            ),
+            "content": response_content,
+        },
+        "400": {
+            "description": "The event can't be converted, if `CONVERSION_ERRORS` is `http`.",
+            "content": {"application/problem+json": {"schema": schema("Problem")}},
+        },
+    });
+    if produces_raw {
+        responses["500"] = json!({
+            "description": concat!(
+                "The raw message can't be written in the negotiated encoding, if ",
+                "`CONVERSION_ERRORS` is `http`.",
+            ),
+            "content": {"application/problem+json": {"schema": schema("Problem")}},
+        });
+    }
+    json!({
+        "post": {
+            "summary": summary,
+            "parameters": parameters
+                .into_iter()
+                .map(|name| json!({"$ref": format!("#/components/parameters/{name}")}))
+                .collect::<Vec<_>>(),
+            "requestBody": request_body(request, request_example),
+            "responses": responses,
+        }
+    })
+}
+
+fn get(summary: &str, description: &str, schema: Option<Value>) -> Value {
+    let mut response = json!({ "description": description });
+    if let Some(schema) = schema {
+        response["content"] = json!({"application/json": {"schema": schema}});
+    }
+    json!({"get": {"summary": summary, "responses": {"200": response}}})
+}
+
+fn header(name: &str, description: &str, example: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "header",
+        "required": true,
+        "description": description,
+        "schema": {"type": "string"},
+        "example": example,
+    })
+}
+
+/// Generate the OpenAPI document.
+pub fn document() -> Value {
+    let mut schemas = Map::new();
+    add_schema(&mut schemas, "RawMessage", RawMessage::json_schema());
+    add_schema(&mut schemas, "Command", Command::json_schema());
+    add_schema(&mut schemas, "Telemetry", schemars::schema_for!(Telemetry));
+    add_schema(
+        &mut schemas,
+        "DerivedEvent",
+        schemars::schema_for!(DerivedEvent),
+    );
+    add_schema(
+        &mut schemas,
+        "DecoderDescriptor",
+        DecoderDescriptor::json_schema(),
+    );
+    schemas.insert(
+        "ConvertedTelemetry".to_string(),
+        json!({
+            "type": "object",
+            "required": ["state", "partial"],
+            "properties": {
+                "state": {
+                    "description": concat!(
+                        "The state reported by the message, by model or name of the descriptor ",
+                        "of a vendor model, and the `sensor.stats` if statistics are enabled.",
+                    ),
+                    "type": "object",
+                },
+                "partial": {"type": "boolean"},
+                "events": {"type": "array", "items": schema("DerivedEvent")},
+            },
+        }),
+    );
+    schemas.insert(
+        "CloudEvent".to_string(),
+        json!({
+            "description": "An event in the structured JSON format of CloudEvents.",
+            "type": "object",
+            "required": ["specversion", "id", "source", "type"],
+        }),
+    );
+    schemas.insert(
+        "Problem".to_string(),
+        json!({
+            "description": "RFC 7807 problem details.",
+            "type": "object",
+            "required": ["type", "title", "status", "detail"],
+            "properties": {
+                "type": {"type": "string"},
+                "title": {"type": "string"},
+                "status": {"type": "integer"},
+                "detail": {"type": "string"},
+            },
+        }),
+    );
+
+    // Each check is either "ok" or the reason of its failure
+    let checks = json!({"type": "object", "additionalProperties": {"type": "string"}});
+    let examples = examples();
+    let example = |path: &str| examples.iter().find(|(p, _, _)| *p == path).unwrap();
+    let mut document = json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "model-converter",
+            "description": "Conversion between the raw mesh messages of the gateway and JSON.",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/telemetry": conversion(
+                "Convert a raw message to the state it reports",
+                "RawMessage",
+                &schema("ConvertedTelemetry"),
+                example("/telemetry"),
+            ),
+            "/telemetry/encode": conversion(
+                "Convert the state produced by /telemetry back to a raw message",
+                "Telemetry",
+                &schema("RawMessage"),
+                example("/telemetry/encode"),
+            ),
+            "/command": conversion(
+                "Convert a command to a raw message",
+                "Command",
+                &schema("RawMessage"),
+                example("/command"),
+            ),
+            "/command/decode": conversion(
+                "Convert a raw message back to a command",
+                "RawMessage",
+                &schema("Command"),
+                example("/command/decode"),
+            ),
+            "/healthz": get("Liveness of the converter", "The converter is running.", None),
+            "/readyz": {
+                "get": {
+                    "summary": "Readiness of the converter, with the result of each check",
+                    "responses": {
+                        "200": {
+                            "description": "All checks passed.",
+                            "content": {"application/json": {"schema": checks.clone()}},
+                        },
+                        "503": {
+                            "description": "A check failed, or the configuration is being loaded.",
+                            "content": {"application/json": {"schema": checks}},
+                        },
+                    },
+                },
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Prometheus metrics of the conversions",
+                    "responses": {
+                        "200": {
+                            "description": "The metrics, in the Prometheus text format.",
+                            "content": {"text/plain": {"schema": {"type": "string"}}},
+                        },
+                    },
+                },
+            },
+            "/errors": get(
+                "Number of conversion errors, by direction and reason",
+                "The error counters.",
+                Some(json!({"type": "object"})),
+            ),
+            "/decoders": {
+                "get": get(
+                    "List the descriptors of vendor models",
+                    "The registered descriptors.",
+                    Some(json!({"type": "array", "items": schema("DecoderDescriptor")})),
+                )["get"],
+                "post": {
+                    "summary": "Register the descriptor of a vendor model",
+                    "requestBody": {
+                        "required": true,
+                        "content": {"application/json": {"schema": schema("DecoderDescriptor")}},
+                    },
+                    "responses": {
+                        "204": {"description": "The descriptor is registered."},
+                        "400": {"description": "The descriptor is invalid."},
+                    },
+                },
+            },
+            "/schema/raw-message": get("JSON schema of a raw message", "The JSON schema.", None),
+            "/schema/command": get("JSON schema of a command", "The JSON schema.", None),
+            "/schema/decoder": get(
+                "JSON schema of a vendor model descriptor",
+                "The JSON schema.",
+                None,
+            ),
+            "/openapi.json": get("This document", "The OpenAPI document.", None),
+        },
+        "components": {
+            "schemas": schemas,
+            "parameters": {
+                "ce-specversion": header("ce-specversion", "Version of CloudEvents.", "1.0"),
+                "ce-id": header("ce-id", "Identifier of the event.", "1"),
+                "ce-source": header("ce-source", "Source of the event.", "drogue://eclipsecon"),
+                "ce-type": header("ce-type", "Type of the event.", "io.drogue.event.v1"),
+                "accept": {
+                    "name": "accept",
+                    "in": "header",
+                    "required": false,
+                    "description": concat!(
+                        "Encoding of the raw message produced, the configured one if none of ",
+                        "the supported content types is accepted.",
+                    ),
+                    "schema": {"type": "string"},
+                    "example": "application/json",
+                },
+            },
+        },
+    });
+    fix_schema(&mut document);
+    document
+}
+
+/// Validation of JSON data against the schemas of the OpenAPI document.
+pub struct Validator {
+    document: Value,
+    schemas: HashMap<&'static str, JSONSchema>,
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        let document = document();
+        let schemas = VALIDATED
+            .iter()
+            .map(|name| (*name, compile(&document, &schema(name))))
+            .collect();
+        Self { document, schemas }
+    }
+}
+
+/// Compile a schema of the document, with its references to the other schemas.
+pub fn compile(document: &Value, schema: &Value) -> JSONSchema {
+    let mut root = schema.clone();
+    root["components"] = document["components"].clone();
+    JSONSchema::compile(&root).expect("invalid schema in the OpenAPI document")
+}
+
+impl Validator {
+    pub fn document(&self) -> &Value {
+        &self.document
+    }
+
+    /// Validate data against a schema of the document, describing all violations if invalid.
+    pub fn validate(&self, name: &str, data: &Value) -> Result<(), String> {
+        let schema = &self.schemas[name];
+        schema.validate(data).map_err(|errors| {
+            errors
+                .map(|e| format!("{}: {e}", e.instance_path))
+                .collect::<Vec<_>>()
+                .join("; ")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::ErrorMode, Converter};
+    use actix_web::{http::StatusCode, test, web, App};
+
+    #[test]
+    fn test_validation() {
+        let validator = Validator::default();
+        assert_eq!(
+            validator.validate("Command", &json!({"address": 171, "display": {"on": true}})),
+            Ok(())
+        );
+        let error = validator
+            .validate("Command", &json!({"address": "171", "display": {}}))
+            .unwrap_err();
+        assert!(error.contains("/address"), "{error}");
+        assert!(validator
+            .validate("RawMessage", &json!({"location": 0, "opcode": [130, 2]}))
+            .is_err());
+        assert!(validator
+            .validate(
+                "Telemetry",
+                &json!({"state": {"battery": {"level": 80, "flags": {"presence": "Unknown"}, "location": 0}}})
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_references() {
+        // All references of the document resolve to one of its schemas
+        fn check(value: &Value, schemas: &Map<String, Value>) {
+            match value {
+                Value::Object(object) => {
+                    if let Some(Value::String(reference)) = object.get("$ref") {
+                        if let Some(name) = reference.strip_prefix(COMPONENTS) {
+                            assert!(schemas.contains_key(name), "{reference}");
+                        }
+                    }
+                    object.values().for_each(|v| check(v, schemas));
+                }
+                Value::Array(values) => values.iter().for_each(|v| check(v, schemas)),
+                _ => {}
+            }
+        }
+        let document = document();
+        check(
+            &document,
+            document["components"]["schemas"].as_object().unwrap(),
+        );
+    }
+
+    /// Every route of the converter is documented, with its method.
+    #[test]
+    fn test_routes() {
+        let document = document();
+        let mut routes = 0;
+        for line in include_str!("main.rs").lines() {
+            for method in ["get", "post"] {
+                let route = line.trim().strip_prefix(&format!("#[{method}(\""));
+                if let Some(path) = route.and_then(|r| r.split('"').next()) {
+                    assert!(
+                        document["paths"][path][method].is_object(),
+                        "{method} {path} is not documented"
+                    );
+                    routes += 1;
+                }
+            }
+        }
+        assert!(routes > 0);
+    }
+
+    /// Post every example of the document to the converter, and check the response against the
+    /// example and the schema of the response.
+    #[actix_web::test]
+    async fn test_contract() {
+        let converter = web::Data::new(Converter::new(ContentType::Json, ErrorMode::Http));
+        let app = test::init_service(
+            App::new()
+                .app_data(converter.clone())
+                .configure(crate::routes),
+        )
+        .await;
+
+        let response = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/openapi.json").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let document: Value = test::read_body_json(response).await;
+        assert_eq!(&document, converter.validator.document());
+
+        for (path, request, expected) in examples() {
+            let operation = &document["paths"][path]["post"];
+            let mut builder = test::TestRequest::post()
+                .uri(path)
+                .insert_header(("content-type", "application/json"));
+            for parameter in operation["parameters"].as_array().unwrap() {
+                let name = parameter["$ref"]
+                    .as_str()
+                    .unwrap()
+                    .rsplit('/')
+                    .next()
+                    .unwrap();
+                let parameter = &document["components"]["parameters"][name];
+                builder = builder.insert_header((name, parameter["example"].as_str().unwrap()));
+            }
+            let content = &operation["requestBody"]["content"]["application/json"];
+            assert_eq!(content["example"], request, "{path}");
+            assert!(
+                compile(&document, &content["schema"]).is_valid(&request),
+                "{path}"
+            );
+
+            let response = test::call_service(&app, builder.set_json(&request).to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+            let data: Value = test::read_body_json(response).await;
+            assert_eq!(data, expected, "{path}");
+
+            let content = &operation["responses"]["200"]["content"]["application/json"];
+            assert_eq!(content["example"], expected, "{path}");
+            assert!(
+                compile(&document, &content["schema"]).is_valid(&data),
+                "{path}"
+            );
+        }
+
+        // Invalid data is rejected with the violations of the schema
+        let request = test::TestRequest::post()
+            .uri("/command")
+            .insert_header(("ce-specversion", "1.0"))
+            .insert_header(("ce-id", "2"))
+            .insert_header(("ce-source", "contract-test"))
+            .insert_header(("ce-type", "io.drogue.command.v1"))
+            .set_json(&json!({"address": 171, "display": {"on": "yes"}}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let problem: Value = test::read_body_json(response).await;
+        assert_eq!(
+            problem["type"],
+            json!("urn:eclipsecon:model-converter:invalid-command")
+        );
+        assert!(!problem["detail"].as_str().unwrap().is_empty());
+    }
+}
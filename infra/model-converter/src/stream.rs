@@ -0,0 +1,276 @@
+//! Stream processing mode: events are consumed from a topic of a Kafka cluster, and the converted
+//! events are published to another topic.
+//!
+//! The offset of an event is only committed once its conversion has been delivered, so events are
+//! converted at least once, even across restarts. There is no MQTT mode: the MQTT client
+//! acknowledges a message as soon as it is received, which would lose the events being converted
+//! when the converter stops.
+use crate::{error::ConversionError, Converter};
+use actix_web::web;
+use cloudevents::{
+    binding::rdkafka::{FutureRecordExt, MessageExt, MessageRecord},
+    AttributesReader, Event,
+};
+use rdkafka::{
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+    ClientConfig, Message,
+};
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Broker {
+    Kafka,
+}
+
+impl FromStr for Broker {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kafka" => Ok(Self::Kafka),
+            _ => Err(format!("unsupported stream broker '{s}'")),
+        }
+    }
+}
+
+impl fmt::Display for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kafka => write!(f, "kafka"),
+        }
+    }
+}
+
+pub struct StreamConfig {
+    pub broker: Broker,
+    /// Kafka bootstrap servers.
+    pub servers: String,
+    pub input_topic: String,
+    pub output_topic: String,
+    /// Topic of the events that could not be converted, the output topic if unset.
+    pub error_topic: Option<String>,
+    pub conversion: Conversion,
+    /// Kafka consumer group.
+    pub group: String,
+}
+
+impl StreamConfig {
+    /// Read the configuration from the environment, if the `STREAM` variable selects a broker.
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok();
+        let required = |name: &str| var(name).unwrap_or_else(|| panic!("{name} is required"));
+        let broker = var("STREAM")?.parse().expect("invalid STREAM");
+        Some(Self {
+            broker,
+            servers: required("STREAM_SERVERS"),
+            input_topic: required("STREAM_INPUT_TOPIC"),
+            output_topic: required("STREAM_OUTPUT_TOPIC"),
+            error_topic: var("STREAM_ERROR_TOPIC"),
+            conversion: var("STREAM_CONVERSION")
+                .map(|c| c.parse().expect("invalid STREAM_CONVERSION"))
+                .unwrap_or(Conversion::Telemetry),
+            group: var("STREAM_GROUP").unwrap_or_else(|| "model-converter".to_string()),
+        })
+    }
+
+    /// Convert an event, returning the topic to publish the result to. Raw messages are
+    /// produced in the configured encoding, as there is no request to negotiate it.
+    fn convert(&self, converter: &Converter, event: Event) -> (&str, Event) {
+        match converter.convert_or_annotate(self.conversion, event, converter.encoding) {
+            Ok(event) => (&self.output_topic, event),
+            Err(event) => (
+                self.error_topic.as_deref().unwrap_or(&self.output_topic),
+                event,
+            ),
+        }
+    }
+}
+
+/// Run the stream processor until the connection to the broker fails.
+pub async fn run(converter: web::Data<Converter>, config: StreamConfig) -> anyhow::Result<()> {
+    log::info!(
+        "Converting {} events from {} topic {} to {}",
+        config.conversion.name(),
+        config.broker,
+        config.input_topic,
+        config.output_topic
+    );
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &config.servers)
+        .set("group.id", &config.group)
+        // Offsets are committed once the converted event is delivered
+        .set("enable.auto.commit", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()?;
+    consumer.subscribe(&[&config.input_topic])?;
+    converter.readiness.set("stream", Ok(()));
+
+    let producer: FutureProducer = ClientConfig::new()
+        .set("bootstrap.servers", &config.servers)
+        .set("acks", "all")
+        .create()?;
+
+    loop {
+        let message = consumer.recv().await?;
+        let event = match message.to_event() {
+            Ok(event) => event,
+            Err(e) => {
+                log::warn!("Discarding message which is not a CloudEvent: {e}");
+                let error = ConversionError::InvalidMessage(e.to_string());
+                converter
+                    .counters
+                    .increment(config.conversion.name(), &error);
+                consumer.commit_message(&message, CommitMode::Async)?;
+                continue;
+            }
+        };
+        // Keep the partitioning of the input topic
+        let key = message
+            .key()
+            .map(<[u8]>::to_vec)
+            .unwrap_or_else(|| event.id().as_bytes().to_vec());
+        let (topic, event) = config.convert(&converter, event);
+
+        let record = MessageRecord::from_event(event)?;
+        producer
+            .send(
+                FutureRecord::to(topic).key(&key).message_record(&record),
+                Timeout::Never,
+            )
+            .await
+            .map_err(|(e, _)| e)?;
+        consumer.commit_message(&message, CommitMode::Async)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorMode;
+    use actix_web::rt::time::{sleep, timeout};
+    use cloudevents::{EventBuilder, EventBuilderV10};
+    use rdkafka::{
+        admin::{AdminClient, AdminOptions, NewTopic, TopicReplication},
+        client::DefaultClientContext,
+        consumer::BaseConsumer,
+        Offset, TopicPartitionList,
+    };
+    use sensor_model::ContentType;
+    use serde_json::json;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn config(servers: &str, suffix: &str) -> StreamConfig {
+        StreamConfig {
+            broker: Broker::Kafka,
+            servers: servers.to_string(),
+            input_topic: format!("commands{suffix}"),
+            output_topic: format!("mesh-commands{suffix}"),
+            error_topic: Some(format!("errors{suffix}")),
+            conversion: "command".parse().unwrap(),
+            group: format!("model-converter{suffix}"),
+        }
+    }
+
+    fn event(data: serde_json::Value) -> Event {
+        EventBuilderV10::new()
+            .id("1")
+            .source("app")
+            .ty("io.drogue.command.v1")
+            .data("application/json", data)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_routing() {
+        let converter = Converter::new(ContentType::Json, ErrorMode::Http);
+        let config = config("localhost:9092", "");
+
+        let command = json!({"address": 171, "display": {"on": true}});
+        let (topic, _) = config.convert(&converter, event(command));
+        assert_eq!(topic, "mesh-commands");
+
+        let (topic, event) = config.convert(&converter, event(json!({"display": {}})));
+        assert_eq!(topic, "errors");
+        assert!(event.extension("conversionerror").is_some());
+    }
+
+    /// Convert an event through a broker, such as the Redpanda container of the README:
+    /// `STREAM_TEST_SERVERS=localhost:9092 cargo test -- --ignored test_kafka`
+    #[actix_web::test]
+    #[ignore]
+    async fn test_kafka() {
+        let servers =
+            std::env::var("STREAM_TEST_SERVERS").unwrap_or_else(|_| "localhost:9092".to_string());
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let config = config(&servers, &format!("-{nanos:x}"));
+        let client = |group: &str| {
+            let mut client = ClientConfig::new();
+            client
+                .set("bootstrap.servers", &servers)
+                .set("group.id", group)
+                .set("auto.offset.reset", "earliest");
+            client
+        };
+
+        let admin: AdminClient<DefaultClientContext> = client("admin").create().unwrap();
+        let topics: Vec<_> = [&config.input_topic, &config.output_topic]
+            .into_iter()
+            .map(|topic| NewTopic::new(topic, 1, TopicReplication::Fixed(1)))
+            .collect();
+        admin
+            .create_topics(&topics, &AdminOptions::new())
+            .await
+            .unwrap();
+
+        let producer: FutureProducer = client("producer").create().unwrap();
+        let command = json!({"address": 171, "display": {"on": true}});
+        let record = MessageRecord::from_event(event(command)).unwrap();
+        producer
+            .send(
+                FutureRecord::to(&config.input_topic)
+                    .key("1")
+                    .message_record(&record),
+                Timeout::Never,
+            )
+            .await
+            .unwrap();
+
+        let input_topic = config.input_topic.clone();
+        let output: StreamConsumer = client("reader").create().unwrap();
+        output.subscribe(&[&config.output_topic]).unwrap();
+        let group = config.group.clone();
+        let converter = web::Data::new(Converter::new(ContentType::Json, ErrorMode::Http));
+        actix_web::rt::spawn(run(converter, config));
+
+        let message = timeout(Duration::from_secs(30), output.recv())
+            .await
+            .expect("no converted event")
+            .unwrap();
+        let converted = message.to_event().unwrap();
+        assert_eq!(converted.id(), "1");
+        assert!(converted.extension("conversionerror").is_none());
+        assert_eq!(crate::event2raw(&converted).unwrap().address, Some(171));
+
+        // The offset of the event is committed once its conversion is delivered
+        let committed: BaseConsumer = client(&group).create().unwrap();
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition(&input_topic, 0);
+        for _ in 0..30 {
+            let offsets = committed
+                .committed_offsets(partitions.clone(), Timeout::After(Duration::from_secs(5)))
+                .unwrap();
+            let offset = offsets.find_partition(&input_topic, 0).unwrap().offset();
+            if offset == Offset::Offset(1) {
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        panic!("the offset of the event was not committed");
+    }
+}
@@ -0,0 +1,209 @@
+//! JSON representation of the telemetry published by the micro:bit.
+use crate::{
+    command::TidTracker,
+    convert::{parse, MeshCodec},
+    error::ConversionError,
+};
+use btmesh_models::{
+    generic::{
+        battery::{
+            GenericBatteryFlags, GenericBatteryFlagsCharging, GenericBatteryFlagsIndicator,
+            GenericBatteryFlagsPresence, GenericBatteryMessage, GenericBatteryStatus,
+        },
+        onoff::{GenericOnOffMessage, Set as GenericOnOffSet},
+    },
+    sensor::{SensorMessage, SensorStatus},
+    Message,
+};
+use schemars::JsonSchema;
+use sensor_model::{MeshEvent, PropertySet, RawMessage, SensorPayload, PROPERTIES};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The state reported by a telemetry message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Telemetry {
+    /// Unicast address of the device. Not part of the converted telemetry, which Drogue Cloud
+    /// already attributes to the device, but used when encoding it.
+    #[serde(default, skip_serializing)]
+    pub address: Option<u16>,
+    pub state: TelemetryState,
+}
+
+/// Names of the states of the built-in models, the keys of a [`TelemetryState`].
+pub const BUILTIN_STATES: [&str; 3] = ["button", "sensor", "battery"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TelemetryState {
+    Button {
+        on: bool,
+        location: u16,
+    },
+    Sensor {
+        /// The properties carried by the status, in SI units.
+        payload: Value,
+        location: u16,
+    },
+    Battery {
+        level: u8,
+        flags: BatteryFlags,
+        location: u16,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct BatteryFlags {
+    pub presence: BatteryPresence,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum BatteryPresence {
+    NotPresent,
+    PresentRemovable,
+    PresentNotRemovable,
+    Unknown,
+}
+
+impl From<&GenericBatteryFlagsPresence> for BatteryPresence {
+    fn from(presence: &GenericBatteryFlagsPresence) -> Self {
+        match presence {
+            GenericBatteryFlagsPresence::NotPresent => Self::NotPresent,
+            GenericBatteryFlagsPresence::PresentRemovable => Self::PresentRemovable,
+            GenericBatteryFlagsPresence::PresentNotRemovable => Self::PresentNotRemovable,
+            GenericBatteryFlagsPresence::Unknown => Self::Unknown,
+        }
+    }
+}
+
+impl From<BatteryPresence> for GenericBatteryFlagsPresence {
+    fn from(presence: BatteryPresence) -> Self {
+        match presence {
+            BatteryPresence::NotPresent => Self::NotPresent,
+            BatteryPresence::PresentRemovable => Self::PresentRemovable,
+            BatteryPresence::PresentNotRemovable => Self::PresentNotRemovable,
+            BatteryPresence::Unknown => Self::Unknown,
+        }
+    }
+}
+
+impl MeshCodec for Telemetry {
+    fn decode(message: &RawMessage) -> Result<Self, ConversionError> {
+        let location = message.location;
+        let state = match parse(message)? {
+            MeshEvent::OnOff(
+                GenericOnOffMessage::Set(set) | GenericOnOffMessage::SetUnacknowledged(set),
+            ) => TelemetryState::Button {
+                on: set.on_off == 1,
+                location,
+            },
+            MeshEvent::Sensor(SensorMessage::Status(status)) => {
+                log::info!("Received sensor status {:?}", status);
+                TelemetryState::Sensor {
+                    payload: sensor2json(status.data),
+                    location,
+                }
+            }
+            MeshEvent::Battery(GenericBatteryMessage::Status(status)) => {
+                log::info!("Received battery status {:?}", status);
+                TelemetryState::Battery {
+                    level: status.battery_level,
+                    flags: BatteryFlags {
+                        presence: (&status.flags.presence).into(),
+                    },
+                    location,
+                }
+            }
+            _ => return Err(ConversionError::Unconverted),
+        };
+        Ok(Self {
+            address: message.address,
+            state,
+        })
+    }
+
+    /// Convert to a raw message. Button presses are new transactions of the device, and a
+    /// sensor status carries all properties, with the ones missing from the payload set to zero.
+    fn encode(&self, tids: &TidTracker) -> Result<RawMessage, ConversionError> {
+        let (location, mut message) = match &self.state {
+            TelemetryState::Button { on, location } => {
+                let set = GenericOnOffSet {
+                    on_off: if *on { 1 } else { 0 },
+                    tid: tids.next(self.address.unwrap_or_default()),
+                    transition_time: None,
+                    delay: None,
+                };
+                (*location, raw(&GenericOnOffMessage::Set(set))?)
+            }
+            TelemetryState::Sensor { payload, location } => {
+                let status = SensorStatus::new(json2sensor(payload)?);
+                (*location, raw(&SensorMessage::Status(status))?)
+            }
+            TelemetryState::Battery {
+                level,
+                flags,
+                location,
+            } => {
+                let status = GenericBatteryStatus::new(
+                    *level,
+                    0,
+                    0,
+                    GenericBatteryFlags {
+                        presence: flags.presence.into(),
+                        indicator: GenericBatteryFlagsIndicator::Unknown,
+                        charging: GenericBatteryFlagsCharging::Unknown,
+                    },
+                );
+                (*location, raw(&GenericBatteryMessage::Status(status))?)
+            }
+        };
+        message.address = self.address;
+        message.location = location;
+        Ok(message)
+    }
+}
+
+fn invalid(e: &str) -> ConversionError {
+    ConversionError::InvalidTelemetry(e.to_string())
+}
+
+fn raw<M: Message>(message: &M) -> Result<RawMessage, ConversionError> {
+    RawMessage::from_message(message).map_err(|e| invalid(&e.to_string()))
+}
+
+/// Convert a sensor payload to JSON, only including the properties present in the frame.
+pub fn sensor2json(data: SensorPayload) -> Value {
+    match serde_json::to_value(&data).unwrap() {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .filter(|(name, _)| data.present.iter().any(|p| p.name == name))
+                .collect(),
+        ),
+        payload => payload,
+    }
+}
+
+/// Convert a JSON sensor payload back, marking the properties it contains as present.
+pub fn json2sensor(payload: &Value) -> Result<SensorPayload, ConversionError> {
+    let payload = payload
+        .as_object()
+        .ok_or_else(|| invalid("expected an object as sensor payload"))?;
+    let mut fields = match serde_json::to_value(SensorPayload::default()) {
+        Ok(Value::Object(fields)) => fields,
+        _ => return Err(invalid("the sensor payload has no fields to fill")),
+    };
+    let mut present = PropertySet::empty();
+    for (name, value) in payload {
+        let property = PROPERTIES
+            .iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| invalid(&format!("unknown sensor property '{name}'")))?;
+        fields.insert(name.clone(), value.clone());
+        present.insert(property.id);
+    }
+    let mut data: SensorPayload =
+        serde_json::from_value(Value::Object(fields)).map_err(|e| invalid(&e.to_string()))?;
+    data.present = present;
+    Ok(data)
+}
@@ -0,0 +1,675 @@
+//! Decoders of vendor models, described by descriptors instead of code.
+//!
+//! A descriptor maps the parameters of the messages with a given opcode to JSON fields, so that
+//! a model added to the firmware can be converted without changing the converter. Descriptors
+//! are loaded from JSON or TOML files at startup, and can be registered at runtime.
+use crate::{error::ConversionError, telemetry::BUILTIN_STATES};
+use schemars::{schema::RootSchema, JsonSchema};
+use sensor_model::{RawMessage, UncheckedRawMessage, MAX_PARAMETERS_LEN};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, fmt, path::Path, sync::RwLock};
+
+/// Description of the parameters of a message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DecoderDescriptor {
+    /// Name of the message, used as key of the converted state.
+    pub name: String,
+    /// Company identifier of a vendor model. The opcode is then the 6 bit vendor opcode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub company: Option<u16>,
+    /// The opcode, a one or two octet SIG opcode if there is no company identifier.
+    pub opcode: u16,
+    pub fields: Vec<FieldDescriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct FieldDescriptor {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: FieldType,
+    /// Offset of the field in the parameters, right after the previous field if omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    /// Length of `bytes` and `string` fields, the rest of the parameters if omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    /// Multiplier converting the value of a numeric field.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<f64>,
+    /// Byte order of numeric fields, little endian as the mesh messages by default.
+    #[serde(default)]
+    pub big_endian: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    Bool,
+    /// Converted to a hex string.
+    Bytes,
+    /// UTF-8 text, trailing NUL characters are removed.
+    String,
+}
+
+impl FieldType {
+    /// Size of the field, `None` if it is variable.
+    fn size(&self) -> Option<usize> {
+        match self {
+            Self::U8 | Self::I8 | Self::Bool => Some(1),
+            Self::U16 | Self::I16 => Some(2),
+            Self::U32 | Self::I32 | Self::F32 => Some(4),
+            Self::Bytes | Self::String => None,
+        }
+    }
+}
+
+/// A descriptor file, with a `decoder` array of descriptors.
+#[derive(Deserialize)]
+struct DescriptorFile {
+    #[serde(alias = "decoders")]
+    decoder: Vec<DecoderDescriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DescriptorError {
+    Io(String),
+    Parse(String),
+    /// The descriptor is inconsistent.
+    Invalid {
+        name: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read descriptors: {e}"),
+            Self::Parse(e) => write!(f, "failed to parse descriptors: {e}"),
+            Self::Invalid { name, reason } => write!(f, "invalid descriptor '{name}': {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for DescriptorError {}
+
+/// Position of a field in the parameters.
+struct Span {
+    start: usize,
+    /// `None` for a variable length field, which spans the rest of the parameters.
+    end: Option<usize>,
+}
+
+impl Span {
+    fn overlaps(&self, other: &Span) -> bool {
+        let before = |a: &Span, b: &Span| a.end.map_or(false, |end| end <= b.start);
+        !before(self, other) && !before(other, self)
+    }
+}
+
+impl DecoderDescriptor {
+    pub fn json_schema() -> RootSchema {
+        schemars::schema_for!(DecoderDescriptor)
+    }
+
+    fn invalid(&self, reason: &str) -> DescriptorError {
+        DescriptorError::Invalid {
+            name: self.name.clone(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// The opcode, as carried in raw messages.
+    pub fn opcode_bytes(&self) -> Result<Vec<u8>, DescriptorError> {
+        match (self.company, self.opcode) {
+            (Some(company), opcode @ 0..=0x3F) => {
+                let company = company.to_le_bytes();
+                Ok(vec![0xC0 | opcode as u8, company[0], company[1]])
+            }
+            (Some(_), _) => Err(self.invalid("vendor opcodes are 6 bit")),
+            (None, opcode @ 0..=0x7E) => Ok(vec![opcode as u8]),
+            (None, opcode @ 0x8000..=0xBFFF) => Ok(opcode.to_be_bytes().to_vec()),
+            (None, _) => Err(self.invalid("not a one or two octet opcode")),
+        }
+    }
+
+    /// Positions of the fields, checking they are consistent and don't overlap.
+    fn spans(&self) -> Result<Vec<Span>, DescriptorError> {
+        let mut spans = Vec::with_capacity(self.fields.len());
+        let mut next = Some(0);
+        for (i, field) in self.fields.iter().enumerate() {
+            if self.fields[..i].iter().any(|f| f.name == field.name) {
+                return Err(self.invalid(&format!("duplicate field '{}'", field.name)));
+            }
+            if field.name == "location" {
+                return Err(self.invalid("'location' is a reserved field name"));
+            }
+            let start = field
+                .offset
+                .or(next)
+                .ok_or_else(|| self.invalid(&format!("'{}' needs an offset", field.name)))?;
+            let out_of_range = || self.invalid(&format!("'{}' is out of range", field.name));
+            if start > MAX_PARAMETERS_LEN {
+                return Err(out_of_range());
+            }
+            let end = match (field.ty.size(), field.length) {
+                (Some(_), Some(_)) => {
+                    return Err(self.invalid(&format!("'{}' has a fixed length", field.name)))
+                }
+                (Some(size), None) | (None, Some(size)) => Some(
+                    start
+                        .checked_add(size)
+                        .filter(|end| *end <= MAX_PARAMETERS_LEN)
+                        .ok_or_else(out_of_range)?,
+                ),
+                (None, None) => None,
+            };
+            if field.scale.is_some()
+                && matches!(
+                    field.ty,
+                    FieldType::Bool | FieldType::Bytes | FieldType::String
+                )
+            {
+                return Err(self.invalid(&format!("'{}' is not numeric", field.name)));
+            }
+            let span = Span { start, end };
+            if let Some(other) = spans.iter().position(|s| span.overlaps(s)) {
+                return Err(self.invalid(&format!(
+                    "'{}' overlaps '{}'",
+                    field.name, self.fields[other].name
+                )));
+            }
+            spans.push(span);
+            next = end;
+        }
+        Ok(spans)
+    }
+
+    fn validate(&self) -> Result<Vec<u8>, DescriptorError> {
+        if self.name.is_empty() {
+            return Err(self.invalid("the name is empty"));
+        }
+        if BUILTIN_STATES.contains(&self.name.as_str()) {
+            return Err(self.invalid("the name is the one of a built-in model"));
+        }
+        self.spans()?;
+        self.opcode_bytes()
+    }
+
+    /// Convert the parameters of a message to a JSON object.
+    fn decode(&self, parameters: &[u8]) -> Result<Map<String, Value>, ConversionError> {
+        let spans = self.spans().map_err(|e| malformed(&e.to_string()))?;
+        let mut fields = Map::new();
+        for (field, span) in self.fields.iter().zip(spans) {
+            let end = span.end.unwrap_or(parameters.len());
+            let data = parameters.get(span.start..end).ok_or_else(|| {
+                malformed(&format!(
+                    "'{}' needs {end} bytes, got {}",
+                    field.name,
+                    parameters.len()
+                ))
+            })?;
+            fields.insert(field.name.clone(), field.decode(data)?);
+        }
+        Ok(fields)
+    }
+
+    /// Convert a JSON object to the parameters of a message.
+    fn encode(&self, fields: &Map<String, Value>) -> Result<Vec<u8>, ConversionError> {
+        let spans = self.spans().map_err(|e| invalid(&e.to_string()))?;
+        let mut parameters = Vec::new();
+        for (field, span) in self.fields.iter().zip(spans) {
+            let value = fields
+                .get(&field.name)
+                .ok_or_else(|| invalid(&format!("missing field '{}'", field.name)))?;
+            let data = field.encode(value)?;
+            let end = span
+                .start
+                .checked_add(data.len())
+                .filter(|end| *end <= MAX_PARAMETERS_LEN)
+                .ok_or_else(|| invalid(&format!("'{}' is out of range", field.name)))?;
+            if parameters.len() < end {
+                parameters.resize(end, 0);
+            }
+            parameters[span.start..end].copy_from_slice(&data);
+        }
+        Ok(parameters)
+    }
+}
+
+fn malformed(e: &str) -> ConversionError {
+    ConversionError::MalformedMessage(e.to_string())
+}
+
+fn invalid(e: &str) -> ConversionError {
+    ConversionError::InvalidTelemetry(e.to_string())
+}
+
+impl FieldDescriptor {
+    fn decode(&self, data: &[u8]) -> Result<Value, ConversionError> {
+        macro_rules! number {
+            ($ty:ty) => {{
+                let bytes = data.try_into().unwrap();
+                let value = if self.big_endian {
+                    <$ty>::from_be_bytes(bytes)
+                } else {
+                    <$ty>::from_le_bytes(bytes)
+                };
+                match self.scale {
+                    Some(scale) => json!(value as f64 * scale),
+                    None => json!(value),
+                }
+            }};
+        }
+        Ok(match self.ty {
+            FieldType::U8 => number!(u8),
+            FieldType::I8 => number!(i8),
+            FieldType::U16 => number!(u16),
+            FieldType::I16 => number!(i16),
+            FieldType::U32 => number!(u32),
+            FieldType::I32 => number!(i32),
+            FieldType::F32 => number!(f32),
+            FieldType::Bool => json!(data[0] != 0),
+            FieldType::Bytes => json!(hex::encode(data)),
+            FieldType::String => {
+                let text = std::str::from_utf8(data)
+                    .map_err(|e| malformed(&format!("'{}' is not UTF-8: {e}", self.name)))?;
+                json!(text.trim_end_matches('\0'))
+            }
+        })
+    }
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, ConversionError> {
+        let expected = |what: &str| invalid(&format!("expected {what} for '{}'", self.name));
+        macro_rules! number {
+            ($ty:ty) => {{
+                let value = value.as_f64().ok_or_else(|| expected("a number"))?;
+                let value = value / self.scale.unwrap_or(1.0);
+                let rounded = value.round();
+                if rounded < <$ty>::MIN as f64 || rounded > <$ty>::MAX as f64 {
+                    return Err(expected("a value in range"));
+                }
+                let value = rounded as $ty;
+                if self.big_endian {
+                    value.to_be_bytes().to_vec()
+                } else {
+                    value.to_le_bytes().to_vec()
+                }
+            }};
+        }
+        let data = match self.ty {
+            FieldType::U8 => number!(u8),
+            FieldType::I8 => number!(i8),
+            FieldType::U16 => number!(u16),
+            FieldType::I16 => number!(i16),
+            FieldType::U32 => number!(u32),
+            FieldType::I32 => number!(i32),
+            FieldType::F32 => {
+                let value = value.as_f64().ok_or_else(|| expected("a number"))?;
+                let value = (value / self.scale.unwrap_or(1.0)) as f32;
+                if self.big_endian {
+                    value.to_be_bytes().to_vec()
+                } else {
+                    value.to_le_bytes().to_vec()
+                }
+            }
+            FieldType::Bool => {
+                vec![value.as_bool().ok_or_else(|| expected("a boolean"))? as u8]
+            }
+            FieldType::Bytes => value
+                .as_str()
+                .and_then(|v| hex::decode(v).ok())
+                .ok_or_else(|| expected("a hex string"))?,
+            FieldType::String => value
+                .as_str()
+                .ok_or_else(|| expected("a string"))?
+                .as_bytes()
+                .to_vec(),
+        };
+        match self.length {
+            Some(length) if data.len() > length => {
+                Err(expected(&format!("at most {length} bytes")))
+            }
+            // Fixed length text and bytes are padded with zeros
+            Some(length) => {
+                let mut data = data;
+                data.resize(length, 0);
+                Ok(data)
+            }
+            None => Ok(data),
+        }
+    }
+}
+
+/// The registered descriptors, keyed by opcode.
+#[derive(Default)]
+pub struct DecoderRegistry {
+    decoders: RwLock<HashMap<Vec<u8>, DecoderDescriptor>>,
+}
+
+impl DecoderRegistry {
+    /// Register a descriptor, replacing any descriptor of the same opcode. Names identify the
+    /// descriptor of a state to encode, so they must be unique.
+    pub fn register(&self, descriptor: DecoderDescriptor) -> Result<(), DescriptorError> {
+        let opcode = descriptor.validate()?;
+        let mut decoders = self.decoders.write().unwrap();
+        if let Some((other, _)) = decoders
+            .iter()
+            .find(|(o, d)| d.name == descriptor.name && **o != opcode)
+        {
+            return Err(descriptor.invalid(&format!(
+                "the name is used by the decoder of opcode {other:02x?}"
+            )));
+        }
+        log::info!(
+            "Registering decoder '{}' for opcode {opcode:02x?}",
+            descriptor.name
+        );
+        decoders.insert(opcode, descriptor);
+        Ok(())
+    }
+
+    /// Register the descriptors of a JSON or TOML file, according to its extension.
+    pub fn load(&self, path: &Path) -> Result<usize, DescriptorError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| DescriptorError::Io(e.to_string()))?;
+        let file: DescriptorFile = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => {
+                toml::from_str(&content).map_err(|e| DescriptorError::Parse(e.to_string()))?
+            }
+            _ => {
+                serde_json::from_str(&content).map_err(|e| DescriptorError::Parse(e.to_string()))?
+            }
+        };
+        let count = file.decoder.len();
+        for descriptor in file.decoder {
+            self.register(descriptor)?;
+        }
+        Ok(count)
+    }
+
+    pub fn descriptors(&self) -> Vec<DecoderDescriptor> {
+        let mut descriptors: Vec<_> = self.decoders.read().unwrap().values().cloned().collect();
+        descriptors.sort_by(|a, b| a.name.cmp(&b.name));
+        descriptors
+    }
+
+    /// Convert a message to the state reported as telemetry, `None` if no descriptor matches
+    /// its opcode.
+    pub fn decode(&self, message: &RawMessage) -> Option<Result<Value, ConversionError>> {
+        let decoders = self.decoders.read().unwrap();
+        let descriptor = decoders.get(message.opcode_bytes())?;
+        Some(descriptor.decode(message.parameters()).map(|mut fields| {
+            fields.insert("location".to_string(), json!(message.location));
+            let mut state = Map::new();
+            state.insert(descriptor.name.clone(), Value::Object(fields));
+            Value::Object(state)
+        }))
+    }
+
+    /// Convert telemetry in the format produced by `/telemetry` back to a message, `None` if the
+    /// state is not one of a registered descriptor.
+    pub fn encode(&self, data: &Value) -> Option<Result<RawMessage, ConversionError>> {
+        let (name, fields) = match data.get("state").and_then(Value::as_object) {
+            Some(state) if state.len() == 1 => state.iter().next()?,
+            _ => return None,
+        };
+        let decoders = self.decoders.read().unwrap();
+        let (opcode, descriptor) = decoders.iter().find(|(_, d)| d.name == *name)?;
+        Some(
+            fields
+                .as_object()
+                .ok_or_else(|| invalid(&format!("expected an object for '{name}'")))
+                .and_then(|fields| {
+                    let location = fields.get("location").and_then(Value::as_u64).unwrap_or(0);
+                    RawMessage::try_from(UncheckedRawMessage {
+                        address: data
+                            .get("address")
+                            .and_then(Value::as_u64)
+                            .map(|a| a as u16),
+                        location: location as u16,
+                        opcode: opcode.clone(),
+                        parameters: descriptor.encode(fields)?,
+                        ..Default::default()
+                    })
+                    .map_err(|e| invalid(&e.to_string()))
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTORS: &str = r#"
+        [[decoder]]
+        name = "thermostat"
+        company = 0x0003
+        opcode = 0x01
+
+        [[decoder.fields]]
+        name = "setpoint"
+        type = "i16"
+        scale = 0.1
+
+        [[decoder.fields]]
+        name = "heating"
+        type = "bool"
+
+        [[decoder.fields]]
+        name = "label"
+        type = "string"
+        length = 8
+    "#;
+
+    fn registry() -> DecoderRegistry {
+        let file: DescriptorFile = toml::from_str(DESCRIPTORS).unwrap();
+        let registry = DecoderRegistry::default();
+        for descriptor in file.decoder {
+            registry.register(descriptor).unwrap();
+        }
+        registry
+    }
+
+    #[test]
+    fn test_vendor_decoder() {
+        let registry = registry();
+        let message = RawMessage::try_from(UncheckedRawMessage {
+            address: Some(0x00ab),
+            location: 0x0100,
+            opcode: vec![0xC1, 0x03, 0x00],
+            parameters: vec![0xd7, 0x00, 0x01, b'l', b'i', b'v', b'i', b'n', b'g', 0, 0],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let state = registry.decode(&message).unwrap().unwrap();
+        assert_eq!(state["thermostat"]["heating"], json!(true));
+        assert_eq!(state["thermostat"]["label"], json!("living"));
+        assert_eq!(state["thermostat"]["location"], json!(0x0100));
+        assert!((state["thermostat"]["setpoint"].as_f64().unwrap() - 21.5).abs() < 1e-9);
+
+        let encoded = registry.encode(&json!({"state": state, "address": 0x00ab}));
+        assert_eq!(encoded, Some(Ok(message)));
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let registry = registry();
+        let message = RawMessage::try_from(UncheckedRawMessage {
+            opcode: vec![0xC1, 0x04, 0x00],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(registry.decode(&message).is_none());
+        assert!(registry
+            .encode(&json!({"state": {"button": {"on": true, "location": 0}}}))
+            .is_none());
+    }
+
+    #[test]
+    fn test_truncated_message() {
+        let registry = registry();
+        let message = RawMessage::try_from(UncheckedRawMessage {
+            opcode: vec![0xC1, 0x03, 0x00],
+            parameters: vec![0xd7, 0x00],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(matches!(
+            registry.decode(&message),
+            Some(Err(ConversionError::MalformedMessage(_)))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_descriptors() {
+        let descriptor = |company, opcode, fields: Value| -> DecoderDescriptor {
+            serde_json::from_value(json!({
+                "name": "test",
+                "company": company,
+                "opcode": opcode,
+                "fields": fields,
+            }))
+            .unwrap()
+        };
+        let registry = DecoderRegistry::default();
+        assert!(registry
+            .register(descriptor(json!(3), 0x40, json!([])))
+            .is_err());
+        assert!(registry
+            .register(descriptor(json!(null), 0x8000, json!([])))
+            .is_ok());
+        assert!(registry
+            .register(descriptor(json!(null), 0x7F, json!([])))
+            .is_err());
+        // A field following a variable length field needs an offset
+        assert!(registry
+            .register(descriptor(
+                json!(3),
+                1,
+                json!([{"name": "data", "type": "bytes"}, {"name": "crc", "type": "u8"}])
+            ))
+            .is_err());
+        assert!(registry
+            .register(descriptor(
+                json!(3),
+                1,
+                json!([{"name": "a", "type": "u8"}, {"name": "a", "type": "u8"}])
+            ))
+            .is_err());
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let registry = registry();
+        let mut descriptor = registry.descriptors().remove(0);
+        // Replacing the descriptor of the same opcode keeps its name
+        descriptor.fields.pop();
+        assert!(registry.register(descriptor.clone()).is_ok());
+
+        descriptor.opcode = 0x02;
+        let error = registry.register(descriptor).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid descriptor 'thermostat': the name is used by the decoder of opcode [c1, 03, 00]"
+        );
+        assert_eq!(registry.descriptors().len(), 1);
+    }
+
+    #[test]
+    fn test_builtin_names() {
+        let registry = DecoderRegistry::default();
+        for name in BUILTIN_STATES {
+            let descriptor = DecoderDescriptor {
+                name: name.to_string(),
+                company: Some(3),
+                opcode: 0x01,
+                fields: Vec::new(),
+            };
+            assert!(registry.register(descriptor).is_err(), "{name}");
+        }
+        assert!(registry.descriptors().is_empty());
+    }
+
+    #[test]
+    fn test_overlapping_fields() {
+        let descriptor = |fields: Value| -> DecoderDescriptor {
+            serde_json::from_value(json!({
+                "name": "test",
+                "company": 3,
+                "opcode": 1,
+                "fields": fields,
+            }))
+            .unwrap()
+        };
+        let registry = DecoderRegistry::default();
+        let error = registry
+            .register(descriptor(json!([
+                {"name": "a", "type": "u16"},
+                {"name": "b", "type": "u8", "offset": 1}
+            ])))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid descriptor 'test': 'b' overlaps 'a'"
+        );
+        // A variable length field spans the rest of the parameters
+        assert!(registry
+            .register(descriptor(json!([
+                {"name": "data", "type": "bytes", "offset": 1},
+                {"name": "crc", "type": "u8", "offset": 4}
+            ])))
+            .is_err());
+        assert!(registry
+            .register(descriptor(json!([
+                {"name": "crc", "type": "u8", "offset": 4},
+                {"name": "a", "type": "u16", "offset": 2},
+                {"name": "data", "type": "bytes", "offset": 5}
+            ])))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let descriptor = |fields: Value| -> DecoderDescriptor {
+            serde_json::from_value(json!({
+                "name": "test",
+                "company": 3,
+                "opcode": 1,
+                "fields": fields,
+            }))
+            .unwrap()
+        };
+        let registry = DecoderRegistry::default();
+        for field in [
+            json!({"name": "a", "type": "u8", "offset": usize::MAX}),
+            json!({"name": "a", "type": "bytes", "offset": usize::MAX}),
+            json!({"name": "a", "type": "bytes", "length": usize::MAX}),
+        ] {
+            let error = registry.register(descriptor(json!([field]))).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "invalid descriptor 'test': 'a' is out of range"
+            );
+        }
+
+        // Variable length data can't extend past the largest parameters
+        registry
+            .register(descriptor(json!([
+                {"name": "data", "type": "bytes", "offset": MAX_PARAMETERS_LEN - 2}
+            ])))
+            .unwrap();
+        let encoded = registry.encode(&json!({"state": {"test": {"data": "00112233"}}}));
+        assert_eq!(encoded, Some(Err(invalid("'data' is out of range"))));
+    }
+}
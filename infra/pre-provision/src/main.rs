@@ -12,9 +12,7 @@ use anyhow::Result;
 use btmesh_common::{
     address::{LabelUuid, UnicastAddress},
     crypto::{application::ApplicationKey, device::DeviceKey, network::NetworkKey},
-    location::{FRONT, LEFT, RIGHT},
-    CompanyIdentifier, Composition, ElementDescriptor, IvIndex, IvUpdateFlag, ModelIdentifier,
-    ProductIdentifier, VersionIdentifier,
+    IvIndex, IvUpdateFlag, ModelIdentifier,
 };
 use btmesh_driver::{
     stack::provisioned::{
@@ -24,11 +22,15 @@ use btmesh_driver::{
     },
     storage::ProvisionedConfiguration,
 };
-use btmesh_models::foundation::configuration::{
-    model_publication::{
-        PublicationDetails, PublishAddress, PublishPeriod, PublishRetransmit, Resolution,
+use btmesh_models::{
+    foundation::configuration::{
+        model_publication::{
+            PublicationDetails, PublishAddress, PublishPeriod, PublishRetransmit, Resolution,
+        },
+        AppKeyIndex, NetKeyIndex,
     },
-    AppKeyIndex, NetKeyIndex,
+    generic::{battery::GENERIC_BATTERY_SERVER, onoff::GENERIC_ONOFF_SERVER},
+    sensor::SENSOR_SETUP_SERVER,
 };
 use probe_rs_cli_util::{
     clap,
@@ -37,6 +39,7 @@ use probe_rs_cli_util::{
     flash::run_flash_download,
 };
 use rand::rngs::OsRng;
+use sensor_model::MicrobitComposition;
 use std::path::Path;
 
 #[derive(clap::Parser)]
@@ -120,97 +123,39 @@ fn main() -> Result<()> {
 
             let network_state = NetworkState::new(IvIndex::new(0), IvUpdateFlag::parse(0));
 
-            let mut front = ElementDescriptor::new(FRONT);
-            front.add_model(ModelIdentifier::SIG(0x1000));
-            front.add_model(ModelIdentifier::SIG(0x100C));
-            front.add_model(ModelIdentifier::SIG(0x1101));
-
-            let mut left = ElementDescriptor::new(LEFT);
-            left.add_model(ModelIdentifier::SIG(0x1001));
-            let mut right = ElementDescriptor::new(RIGHT);
-            right.add_model(ModelIdentifier::SIG(0x1001));
-            let mut composition: Composition<()> = Composition::new(
-                CompanyIdentifier(0x0003),
-                ProductIdentifier(0x0001),
-                VersionIdentifier(0x0001),
-            );
-            composition.add_element(front).unwrap();
-            composition.add_element(left).unwrap();
-            composition.add_element(right).unwrap();
+            let composition = MicrobitComposition::composition().unwrap();
 
             let mut config: ProvisionedConfiguration = (device_info, secrets, network_state).into();
 
-            // Bind all models in front element
-            for i in 0..3 {
-                config
-                    .bindings_mut()
-                    .bind(
-                        &composition,
-                        0,
-                        composition[0][i].model_identifier,
-                        app_key_idx,
-                    )
-                    .unwrap();
+            // Bind all models of every element
+            for (i, element) in MicrobitComposition::ELEMENTS.iter().enumerate() {
+                for model in element.models {
+                    config
+                        .bindings_mut()
+                        .bind(&composition, i, *model, app_key_idx)
+                        .unwrap();
+                }
             }
 
-            // Bind first element of left and right elements
-            for i in 1..3 {
+            // Publications of the front element: the on/off state, and the periodic battery
+            // and sensor statuses
+            let front =
+                MicrobitComposition::element_index(MicrobitComposition::FRONT.location()).unwrap();
+            for (model, period_secs) in [
+                (GENERIC_ONOFF_SERVER, None),
+                (GENERIC_BATTERY_SERVER, Some(60)),
+                (SENSOR_SETUP_SERVER, Some(1)),
+            ] {
                 config
-                    .bindings_mut()
-                    .bind(
+                    .publications_mut()
+                    .set(
                         &composition,
-                        i,
-                        composition[i][0].model_identifier,
-                        app_key_idx,
+                        front,
+                        pub_set(address, app_key_idx, model, period_secs),
                     )
                     .unwrap();
             }
 
-            // Button publication
-            config
-                .publications_mut()
-                .set(
-                    &composition,
-                    0,
-                    pub_set(
-                        address,
-                        app_key_idx,
-                        composition[0][0].model_identifier,
-                        None,
-                    ),
-                )
-                .unwrap();
-
-            // Battery service publication
-            config
-                .publications_mut()
-                .set(
-                    &composition,
-                    0,
-                    pub_set(
-                        address,
-                        app_key_idx,
-                        composition[0][1].model_identifier,
-                        Some(60),
-                    ),
-                )
-                .unwrap();
-
-            // Sensor publication
-            config
-                .publications_mut()
-                .set(
-                    &composition,
-                    0,
-                    pub_set(
-                        address,
-                        app_key_idx,
-                        composition[0][2].model_identifier,
-                        Some(1),
-                    ),
-                )
-                .unwrap();
-
             provision(common, flash_address, chip_erase, config)?;
             Ok(())
         }
@@ -0,0 +1,132 @@
+//! Compare the encodings of raw messages, for the frames a gateway forwards from a deployment of
+//! 150 micro:bits. Each node publishes a sensor status and a battery status, and the buttons
+//! generate onoff messages.
+//!
+//! Run with `cargo bench -p sensor-model --features std`. The size of each encoding is printed
+//! before the throughput measurements.
+use btmesh_models::{
+    generic::{
+        battery::{
+            GenericBatteryFlags, GenericBatteryFlagsCharging, GenericBatteryFlagsIndicator,
+            GenericBatteryFlagsPresence, GenericBatteryMessage, GenericBatteryStatus,
+        },
+        onoff::{GenericOnOffMessage, Set as GenericOnOffSet},
+    },
+    sensor::SensorStatus,
+    Message,
+};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sensor_model::*;
+
+const NODES: u16 = 150;
+
+fn raw<M: Message>(address: u16, msg: &M) -> RawMessage {
+    let mut raw = RawMessage::from_message(msg).unwrap();
+    raw.address = Some(address);
+    raw.location = 0x0100;
+    raw.metadata = Metadata {
+        source: Some(address),
+        ttl: Some(5),
+        rssi: Some(-70),
+    };
+    raw
+}
+
+/// One round of frames from every node in the deployment.
+fn deployment() -> Vec<RawMessage> {
+    let payload = SensorPayload {
+        temperature: HalfDegreesCelsius::from_celsius(22.5),
+        acceleration: Acceleration {
+            x: MilliG(-12),
+            y: MilliG(40),
+            z: MilliG(-1010),
+        },
+        noise: NoiseLevel(12),
+        present: PropertySet::all(),
+    };
+    let sensor = SensorMessage::Status(SensorStatus::new(payload));
+    let battery = GenericBatteryMessage::Status(GenericBatteryStatus::new(
+        80,
+        0,
+        0,
+        GenericBatteryFlags {
+            presence: GenericBatteryFlagsPresence::PresentNotRemovable,
+            indicator: GenericBatteryFlagsIndicator::Unknown,
+            charging: GenericBatteryFlagsCharging::Unknown,
+        },
+    ));
+    let onoff = GenericOnOffMessage::Set(GenericOnOffSet {
+        on_off: 1,
+        tid: 0,
+        transition_time: None,
+        delay: None,
+    });
+
+    (0..NODES)
+        .flat_map(|node| {
+            let address = 0x00ab + node;
+            [
+                raw(address, &sensor),
+                raw(address, &battery),
+                raw(address, &onoff),
+            ]
+        })
+        .collect()
+}
+
+fn report_sizes(messages: &[RawMessage]) {
+    println!("{} frames from {} nodes:", messages.len(), NODES);
+    for content_type in ContentType::ALL {
+        let total: usize = messages
+            .iter()
+            .map(|m| content_type.encode(m).unwrap().len())
+            .sum();
+        println!(
+            "  {:<10} {:>7} bytes, {:>6.1} bytes per frame",
+            content_type.to_string(),
+            total,
+            total as f64 / messages.len() as f64
+        );
+    }
+}
+
+fn bench_encodings(c: &mut Criterion) {
+    let messages = deployment();
+    report_sizes(&messages);
+
+    let mut group = c.benchmark_group("raw_message");
+    group.throughput(Throughput::Elements(messages.len() as u64));
+    for content_type in ContentType::ALL {
+        let encoded: Vec<Vec<u8>> = messages
+            .iter()
+            .map(|m| content_type.encode(m).unwrap())
+            .collect();
+
+        group.bench_with_input(
+            BenchmarkId::new("encode", content_type),
+            &messages,
+            |b, messages| {
+                b.iter(|| {
+                    for m in messages {
+                        black_box(content_type.encode(m).unwrap());
+                    }
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("decode", content_type),
+            &encoded,
+            |b, encoded| {
+                b.iter(|| {
+                    for data in encoded {
+                        black_box(content_type.decode(data).unwrap());
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encodings);
+criterion_main!(benches);
@@ -0,0 +1,5 @@
+//! Print the JSON schema of the raw message wire format.
+fn main() {
+    let schema = sensor_model::RawMessage::json_schema();
+    println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+}
@@ -0,0 +1,19 @@
+#![no_main]
+use btmesh_common::opcode::Opcode;
+use btmesh_models::{sensor::PropertyId, Model};
+use libfuzzer_sys::fuzz_target;
+use sensor_model::{SensorClient, SensorPayload, PROPERTIES};
+
+// Sensor Status
+const SENSOR_STATUS: Opcode = Opcode::OneOctet(0x52);
+
+fuzz_target!(|data: &[u8]| {
+    let _ = SensorClient::parse(&SENSOR_STATUS, data);
+
+    if let Some((selector, params)) = data.split_first() {
+        let mut payload = SensorPayload::default();
+        let property = PROPERTIES[*selector as usize % PROPERTIES.len()];
+        let _ = payload.try_decode(property.id, params);
+        let _ = payload.try_decode(PropertyId(u16::from(*selector)), params);
+    }
+});
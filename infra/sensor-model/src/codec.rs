@@ -0,0 +1,248 @@
+//! Encodings of [`RawMessage`] on the wire, selected by content type.
+//!
+//! JSON is the default and remains readable by every consumer. The binary encodings carry the
+//! same fields as the version 1 JSON envelope, but in a fraction of the size, which matters when
+//! a gateway forwards the frames of a large number of nodes.
+use crate::{Metadata, RawMessage, RawMessageError, UncheckedRawMessage, RAW_MESSAGE_VERSION};
+use core::{fmt, str::FromStr};
+use serde::{Deserialize, Serialize};
+
+/// Encoding of a [`RawMessage`], identified by its MQTT or CloudEvents content type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ContentType {
+    #[default]
+    Json,
+    Cbor,
+    Postcard,
+}
+
+impl ContentType {
+    pub const ALL: [ContentType; 3] = [Self::Json, Self::Cbor, Self::Postcard];
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Cbor => "application/cbor",
+            Self::Postcard => "application/x-postcard",
+        }
+    }
+
+    /// Content type matching a MIME type. Parameters such as `charset` are ignored.
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        let essence = mime.split(';').next().unwrap_or_default().trim();
+        Self::ALL
+            .into_iter()
+            .find(|c| c.mime().eq_ignore_ascii_case(essence))
+    }
+
+    pub fn encode(&self, message: &RawMessage) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Self::Json => {
+                serde_json::to_vec(message).map_err(|e| CodecError::Encode(e.to_string()))
+            }
+            Self::Cbor => {
+                let mut data = Vec::new();
+                ciborium::ser::into_writer(&Compact::from(message), &mut data)
+                    .map_err(|e| CodecError::Encode(e.to_string()))?;
+                Ok(data)
+            }
+            Self::Postcard => postcard::to_allocvec(&Compact::from(message))
+                .map_err(|e| CodecError::Encode(e.to_string())),
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<RawMessage, CodecError> {
+        match self {
+            Self::Json => {
+                serde_json::from_slice(data).map_err(|e| CodecError::Decode(e.to_string()))
+            }
+            Self::Cbor => ciborium::de::from_reader::<Compact, _>(data)
+                .map_err(|e| CodecError::Decode(e.to_string()))?
+                .try_into()
+                .map_err(CodecError::Message),
+            Self::Postcard => postcard::from_bytes::<Compact>(data)
+                .map_err(|e| CodecError::Decode(e.to_string()))?
+                .try_into()
+                .map_err(CodecError::Message),
+        }
+    }
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Json => "json",
+            Self::Cbor => "cbor",
+            Self::Postcard => "postcard",
+        })
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = CodecError;
+
+    /// Parse either a short name (`json`, `cbor`, `postcard`) or a MIME type.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|c| c.to_string().eq_ignore_ascii_case(s))
+            .or_else(|| Self::from_mime(s))
+            .ok_or_else(|| CodecError::UnsupportedContentType(s.to_string()))
+    }
+}
+
+/// Error encoding or decoding a [`RawMessage`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    UnsupportedContentType(String),
+    Encode(String),
+    Decode(String),
+    Message(RawMessageError),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedContentType(c) => write!(f, "unsupported content type '{c}'"),
+            Self::Encode(e) => write!(f, "error encoding raw message: {e}"),
+            Self::Decode(e) => write!(f, "error decoding raw message: {e}"),
+            Self::Message(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Binary representation of a message. Postcard is not self-describing, so all fields are
+/// always present and in a fixed order.
+#[derive(Serialize, Deserialize)]
+struct Compact {
+    version: u8,
+    address: Option<u16>,
+    location: u16,
+    #[serde(with = "serde_bytes")]
+    opcode: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    parameters: Vec<u8>,
+    source: Option<u16>,
+    ttl: Option<u8>,
+    rssi: Option<i8>,
+}
+
+impl From<&RawMessage> for Compact {
+    fn from(msg: &RawMessage) -> Self {
+        Self {
+            version: RAW_MESSAGE_VERSION,
+            address: msg.address,
+            location: msg.location,
+            opcode: msg.opcode_bytes().to_vec(),
+            parameters: msg.parameters().to_vec(),
+            source: msg.metadata.source,
+            ttl: msg.metadata.ttl,
+            rssi: msg.metadata.rssi,
+        }
+    }
+}
+
+impl TryFrom<Compact> for RawMessage {
+    type Error = RawMessageError;
+
+    fn try_from(compact: Compact) -> Result<Self, Self::Error> {
+        if compact.version != RAW_MESSAGE_VERSION {
+            return Err(RawMessageError::UnsupportedVersion(compact.version));
+        }
+        UncheckedRawMessage {
+            address: compact.address,
+            location: compact.location,
+            opcode: compact.opcode,
+            parameters: compact.parameters,
+            metadata: Metadata {
+                source: compact.source,
+                ttl: compact.ttl,
+                rssi: compact.rssi,
+            },
+            ..Default::default()
+        }
+        .try_into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> RawMessage {
+        UncheckedRawMessage {
+            address: Some(0x00ab),
+            location: 0x0100,
+            opcode: vec![0x82, 0x03],
+            parameters: vec![0x01, 0x00],
+            metadata: Metadata {
+                source: Some(0x00ab),
+                ttl: Some(5),
+                rssi: Some(-70),
+            },
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for content_type in ContentType::ALL {
+            let data = content_type.encode(&message()).unwrap();
+            assert_eq!(
+                content_type.decode(&data).unwrap(),
+                message(),
+                "{content_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_binary_is_smaller() {
+        let json = ContentType::Json.encode(&message()).unwrap().len();
+        for content_type in [ContentType::Cbor, ContentType::Postcard] {
+            assert!(content_type.encode(&message()).unwrap().len() < json / 2);
+        }
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            ContentType::from_mime("application/cbor"),
+            Some(ContentType::Cbor)
+        );
+        assert_eq!(
+            ContentType::from_mime("application/json; charset=utf-8"),
+            Some(ContentType::Json)
+        );
+        assert_eq!(ContentType::from_mime("text/plain"), None);
+        assert_eq!("postcard".parse(), Ok(ContentType::Postcard));
+        assert_eq!("application/x-postcard".parse(), Ok(ContentType::Postcard));
+        assert!("xml".parse::<ContentType>().is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(ContentType::Postcard.decode(&[]).is_err());
+        assert!(ContentType::Cbor.decode(&[0xff, 0x00]).is_err());
+
+        let mut data = ContentType::Postcard.encode(&message()).unwrap();
+        data[0] = 2;
+        assert_eq!(
+            ContentType::Postcard.decode(&data),
+            Err(CodecError::Message(RawMessageError::UnsupportedVersion(2)))
+        );
+
+        // Turn the opcode into a truncated three-octet opcode
+        let mut data = ContentType::Cbor.encode(&message()).unwrap();
+        let opcode = data.windows(2).position(|w| w == [0x82, 0x03]).unwrap();
+        data[opcode] = 0xc0;
+        assert!(matches!(
+            ContentType::Cbor.decode(&data),
+            Err(CodecError::Message(RawMessageError::InvalidOpcode(_)))
+        ));
+    }
+}
@@ -0,0 +1,139 @@
+//! Composition of the micro:bit node: its elements, their locations and the models they host.
+//!
+//! Everything that needs to agree with the firmware on the element layout (the gateway, the
+//! simulators and the pre-provisioning tool) derives it from [`MicrobitComposition`].
+use btmesh_common::{
+    location::{Location, FRONT, LEFT, RIGHT},
+    CompanyIdentifier, Composition, ElementDescriptor, InsufficientBuffer, ModelIdentifier,
+    ProductIdentifier, VersionIdentifier,
+};
+use btmesh_models::{
+    generic::{
+        battery::GENERIC_BATTERY_SERVER,
+        onoff::{GENERIC_ONOFF_CLIENT, GENERIC_ONOFF_SERVER},
+    },
+    sensor::SENSOR_SETUP_SERVER,
+};
+
+/// An element of the node.
+pub struct ElementLayout {
+    pub name: &'static str,
+    pub location: Location,
+    /// Models hosted by the element, in the order they are declared by the firmware.
+    pub models: &'static [ModelIdentifier],
+}
+
+impl ElementLayout {
+    /// The location descriptor, as carried in mesh messages.
+    pub fn location(&self) -> u16 {
+        self.location.0
+    }
+
+    /// Index of a model within the element.
+    pub fn model_index(&self, model: ModelIdentifier) -> Option<usize> {
+        self.models.iter().position(|m| *m == model)
+    }
+}
+
+/// The micro:bit node, as declared by the `#[device]` and `#[element]` attributes of the firmware.
+pub struct MicrobitComposition;
+
+impl MicrobitComposition {
+    pub const CID: CompanyIdentifier = CompanyIdentifier(0x0003);
+    pub const PID: ProductIdentifier = ProductIdentifier(0x0001);
+    pub const VID: VersionIdentifier = VersionIdentifier(0x0001);
+
+    /// Element hosting the display, battery and sensors.
+    pub const FRONT: ElementLayout = ElementLayout {
+        name: "front",
+        location: FRONT,
+        models: &[
+            GENERIC_ONOFF_SERVER,
+            GENERIC_BATTERY_SERVER,
+            SENSOR_SETUP_SERVER,
+        ],
+    };
+
+    /// Element of the 'A' button.
+    pub const LEFT: ElementLayout = ElementLayout {
+        name: "left",
+        location: LEFT,
+        models: &[GENERIC_ONOFF_CLIENT],
+    };
+
+    /// Element of the 'B' button.
+    pub const RIGHT: ElementLayout = ElementLayout {
+        name: "right",
+        location: RIGHT,
+        models: &[GENERIC_ONOFF_CLIENT],
+    };
+
+    /// All elements, in element index order.
+    pub const ELEMENTS: &'static [ElementLayout] = &[Self::FRONT, Self::LEFT, Self::RIGHT];
+
+    /// Index of the element at a location.
+    pub fn element_index(location: u16) -> Option<usize> {
+        Self::ELEMENTS.iter().position(|e| e.location() == location)
+    }
+
+    /// Build the composition data of the node.
+    pub fn composition() -> Result<Composition<()>, InsufficientBuffer> {
+        let mut composition = Composition::new(Self::CID, Self::PID, Self::VID);
+        for element in Self::ELEMENTS {
+            let mut descriptor = ElementDescriptor::new(element.location);
+            for model in element.models {
+                descriptor.add_model(*model);
+            }
+            composition
+                .add_element(descriptor)
+                .map_err(|_| InsufficientBuffer)?;
+        }
+        Ok(composition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(MicrobitComposition::FRONT.location(), 0x0100);
+        assert_eq!(MicrobitComposition::LEFT.location(), 0x010D);
+        assert_eq!(MicrobitComposition::RIGHT.location(), 0x010E);
+
+        assert_eq!(
+            MicrobitComposition::FRONT.models,
+            &[
+                ModelIdentifier::SIG(0x1000),
+                ModelIdentifier::SIG(0x100C),
+                ModelIdentifier::SIG(0x1101)
+            ]
+        );
+        assert_eq!(
+            MicrobitComposition::LEFT.models,
+            &[ModelIdentifier::SIG(0x1001)]
+        );
+        assert_eq!(
+            MicrobitComposition::RIGHT.models,
+            &[ModelIdentifier::SIG(0x1001)]
+        );
+
+        assert_eq!(MicrobitComposition::element_index(0x010E), Some(2));
+        assert_eq!(MicrobitComposition::element_index(0x0001), None);
+        assert_eq!(
+            MicrobitComposition::FRONT.model_index(SENSOR_SETUP_SERVER),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_composition() {
+        let composition = MicrobitComposition::composition().unwrap();
+        for (i, element) in MicrobitComposition::ELEMENTS.iter().enumerate() {
+            for (j, model) in element.models.iter().enumerate() {
+                assert_eq!(composition[i][j].model_identifier, *model);
+            }
+        }
+    }
+}
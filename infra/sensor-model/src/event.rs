@@ -0,0 +1,326 @@
+//! Dispatching of the mesh messages exchanged with a micro:bit.
+//!
+//! [`MeshEvent`] covers every model the device exposes, so that a received frame can be parsed
+//! with a single call instead of trying each model in turn.
+use crate::{SensorClient, SensorMessage, SensorQuery, SetupMessage};
+use btmesh_common::{opcode::Opcode, InsufficientBuffer, ParseError};
+use btmesh_models::{
+    foundation::configuration::{ConfigurationClient, ConfigurationMessage, ConfigurationServer},
+    generic::{
+        battery::{GenericBatteryClient, GenericBatteryMessage, GenericBatteryServer},
+        onoff::{GenericOnOffClient, GenericOnOffMessage, GenericOnOffServer},
+    },
+    Message, Model,
+};
+use heapless::Vec;
+
+pub const GENERIC_ONOFF_GET: Opcode = Opcode::TwoOctet(0x82, 0x01);
+pub const GENERIC_ONOFF_SET: Opcode = Opcode::TwoOctet(0x82, 0x02);
+pub const GENERIC_ONOFF_SET_UNACKNOWLEDGED: Opcode = Opcode::TwoOctet(0x82, 0x03);
+pub const GENERIC_ONOFF_STATUS: Opcode = Opcode::TwoOctet(0x82, 0x04);
+pub const GENERIC_BATTERY_GET: Opcode = Opcode::TwoOctet(0x82, 0x23);
+pub const GENERIC_BATTERY_STATUS: Opcode = Opcode::TwoOctet(0x82, 0x24);
+
+/// A message of any of the models of the micro:bit.
+#[derive(Debug)]
+pub enum MeshEvent {
+    /// Generic OnOff get, set and status, used by the buttons and the display.
+    OnOff(GenericOnOffMessage),
+    /// Generic Battery get and status.
+    Battery(GenericBatteryMessage),
+    /// Sensor status and descriptor status.
+    Sensor(SensorMessage),
+    /// Sensor get and descriptor get, sent to the sensor server.
+    SensorQuery(SensorQuery),
+    /// Sensor cadence and setting messages of the sensor setup server.
+    SensorSetup(SetupMessage),
+    /// Configuration messages, exchanged with the provisioner using the device key.
+    Config(ConfigurationMessage),
+}
+
+impl MeshEvent {
+    /// Parse a message, returning `None` if the opcode does not belong to any of the models.
+    pub fn parse(opcode: &Opcode, params: &[u8]) -> Result<Option<Self>, ParseError> {
+        // Server and client models parse the messages sent in each direction
+        if let Some(message) = GenericOnOffServer::parse(opcode, params)? {
+            return Ok(Some(Self::OnOff(message)));
+        }
+        if let Some(message) = GenericOnOffClient::parse(opcode, params)? {
+            return Ok(Some(Self::OnOff(message)));
+        }
+        if let Some(message) = GenericBatteryServer::parse(opcode, params)? {
+            return Ok(Some(Self::Battery(message)));
+        }
+        if let Some(message) = GenericBatteryClient::parse(opcode, params)? {
+            return Ok(Some(Self::Battery(message)));
+        }
+        if let Some(message) = SensorClient::parse(opcode, params)? {
+            return Ok(Some(Self::Sensor(message)));
+        }
+        if let Some(message) = SensorQuery::parse(opcode, params)? {
+            return Ok(Some(Self::SensorQuery(message)));
+        }
+        if let Some(message) = SetupMessage::parse(opcode, params)? {
+            return Ok(Some(Self::SensorSetup(message)));
+        }
+        if let Some(message) = ConfigurationServer::parse(opcode, params)? {
+            return Ok(Some(Self::Config(message)));
+        }
+        if let Some(message) = ConfigurationClient::parse(opcode, params)? {
+            return Ok(Some(Self::Config(message)));
+        }
+        Ok(None)
+    }
+
+    /// Emit the opcode and the parameters of the message.
+    pub fn emit<const O: usize, const P: usize>(
+        &self,
+        opcode: &mut Vec<u8, O>,
+        parameters: &mut Vec<u8, P>,
+    ) -> Result<(), InsufficientBuffer> {
+        self.opcode().emit(opcode)?;
+        self.emit_parameters(parameters)
+    }
+}
+
+impl Message for MeshEvent {
+    fn opcode(&self) -> Opcode {
+        match self {
+            Self::OnOff(message) => message.opcode(),
+            Self::Battery(message) => message.opcode(),
+            Self::Sensor(message) => message.opcode(),
+            Self::SensorQuery(message) => message.opcode(),
+            Self::SensorSetup(message) => message.opcode(),
+            Self::Config(message) => message.opcode(),
+        }
+    }
+
+    fn emit_parameters<const N: usize>(
+        &self,
+        xmit: &mut Vec<u8, N>,
+    ) -> Result<(), InsufficientBuffer> {
+        match self {
+            Self::OnOff(message) => message.emit_parameters(xmit),
+            Self::Battery(message) => message.emit_parameters(xmit),
+            Self::Sensor(message) => message.emit_parameters(xmit),
+            Self::SensorQuery(message) => message.emit_parameters(xmit),
+            Self::SensorSetup(message) => message.emit_parameters(xmit),
+            Self::Config(message) => message.emit_parameters(xmit),
+        }
+    }
+}
+
+impl From<GenericOnOffMessage> for MeshEvent {
+    fn from(message: GenericOnOffMessage) -> Self {
+        Self::OnOff(message)
+    }
+}
+
+impl From<GenericBatteryMessage> for MeshEvent {
+    fn from(message: GenericBatteryMessage) -> Self {
+        Self::Battery(message)
+    }
+}
+
+impl From<SensorMessage> for MeshEvent {
+    fn from(message: SensorMessage) -> Self {
+        Self::Sensor(message)
+    }
+}
+
+impl From<SensorQuery> for MeshEvent {
+    fn from(message: SensorQuery) -> Self {
+        Self::SensorQuery(message)
+    }
+}
+
+impl From<SetupMessage> for MeshEvent {
+    fn from(message: SetupMessage) -> Self {
+        Self::SensorSetup(message)
+    }
+}
+
+impl From<ConfigurationMessage> for MeshEvent {
+    fn from(message: ConfigurationMessage) -> Self {
+        Self::Config(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Acceleration, HalfDegreesCelsius, MilliG, NoiseLevel, PropertySet, SensorPayload,
+        DEFAULT_CADENCES, NOISE,
+    };
+    use btmesh_models::{
+        foundation::configuration::node_reset::NodeResetMessage,
+        generic::{
+            battery::{
+                GenericBatteryFlags, GenericBatteryFlagsCharging, GenericBatteryFlagsIndicator,
+                GenericBatteryFlagsPresence, GenericBatteryStatus,
+            },
+            onoff::Set as GenericOnOffSet,
+        },
+        sensor::SensorStatus,
+    };
+
+    type Frame = (Vec<u8, 3>, Vec<u8, 386>);
+
+    fn emit(event: &MeshEvent) -> Frame {
+        let mut frame: Frame = (Vec::new(), Vec::new());
+        event.emit(&mut frame.0, &mut frame.1).unwrap();
+        frame
+    }
+
+    /// Parse the emitted frame, and check that it is emitted identically again.
+    fn roundtrip(event: MeshEvent) -> MeshEvent {
+        let (opcode, parameters) = emit(&event);
+        let (parsed_opcode, rest) = Opcode::split(&opcode).unwrap();
+        assert!(rest.is_empty());
+        let parsed = MeshEvent::parse(&parsed_opcode, &parameters)
+            .unwrap()
+            .unwrap_or_else(|| panic!("{event:?} not parsed"));
+        assert_eq!(emit(&parsed), (opcode, parameters), "{event:?}");
+        parsed
+    }
+
+    fn set() -> GenericOnOffSet {
+        GenericOnOffSet {
+            on_off: 1,
+            tid: 3,
+            transition_time: None,
+            delay: None,
+        }
+    }
+
+    #[test]
+    fn test_onoff() {
+        assert!(matches!(
+            roundtrip(GenericOnOffMessage::Set(set()).into()),
+            MeshEvent::OnOff(GenericOnOffMessage::Set(_))
+        ));
+        assert!(matches!(
+            roundtrip(GenericOnOffMessage::SetUnacknowledged(set()).into()),
+            MeshEvent::OnOff(GenericOnOffMessage::SetUnacknowledged(_))
+        ));
+        assert!(matches!(
+            roundtrip(GenericOnOffMessage::Get.into()),
+            MeshEvent::OnOff(GenericOnOffMessage::Get)
+        ));
+
+        // A status with only the present state
+        assert!(matches!(
+            MeshEvent::parse(&GENERIC_ONOFF_STATUS, &[1]),
+            Ok(Some(MeshEvent::OnOff(GenericOnOffMessage::Status(_))))
+        ));
+    }
+
+    #[test]
+    fn test_battery() {
+        let status = GenericBatteryStatus::new(
+            80,
+            0,
+            0,
+            GenericBatteryFlags {
+                presence: GenericBatteryFlagsPresence::PresentRemovable,
+                indicator: GenericBatteryFlagsIndicator::Unknown,
+                charging: GenericBatteryFlagsCharging::Unknown,
+            },
+        );
+        let event = roundtrip(GenericBatteryMessage::Status(status).into());
+        assert_eq!(event.opcode(), GENERIC_BATTERY_STATUS);
+        assert!(matches!(
+            event,
+            MeshEvent::Battery(GenericBatteryMessage::Status(_))
+        ));
+        assert!(matches!(
+            roundtrip(GenericBatteryMessage::Get.into()),
+            MeshEvent::Battery(GenericBatteryMessage::Get)
+        ));
+    }
+
+    #[test]
+    fn test_sensor() {
+        let payload = SensorPayload {
+            temperature: HalfDegreesCelsius(45),
+            acceleration: Acceleration {
+                x: MilliG(-12),
+                y: MilliG(40),
+                z: MilliG(-1010),
+            },
+            noise: NoiseLevel(12),
+            present: PropertySet::all(),
+        };
+        match roundtrip(SensorMessage::Status(SensorStatus::new(payload.clone())).into()) {
+            MeshEvent::Sensor(SensorMessage::Status(status)) => assert_eq!(status.data, payload),
+            event => panic!("unexpected {event:?}"),
+        }
+        assert!(matches!(
+            roundtrip(SensorQuery::Get(Some(NOISE.id)).into()),
+            MeshEvent::SensorQuery(SensorQuery::Get(Some(_)))
+        ));
+        assert!(matches!(
+            roundtrip(SensorQuery::DescriptorGet(None).into()),
+            MeshEvent::SensorQuery(SensorQuery::DescriptorGet(None))
+        ));
+    }
+
+    #[test]
+    fn test_sensor_setup() {
+        assert!(matches!(
+            roundtrip(SetupMessage::CadenceGet(NOISE.id).into()),
+            MeshEvent::SensorSetup(SetupMessage::CadenceGet(_))
+        ));
+        let (property, cadence) = &DEFAULT_CADENCES[0];
+        assert!(matches!(
+            roundtrip(SetupMessage::CadenceSet(property.id, *cadence).into()),
+            MeshEvent::SensorSetup(SetupMessage::CadenceSet(..))
+        ));
+    }
+
+    #[test]
+    fn test_config() {
+        assert!(matches!(
+            roundtrip(ConfigurationMessage::from(NodeResetMessage::Reset).into()),
+            MeshEvent::Config(_)
+        ));
+    }
+
+    #[test]
+    fn test_opcodes() {
+        let onoff = [
+            (MeshEvent::from(GenericOnOffMessage::Get), GENERIC_ONOFF_GET),
+            (GenericOnOffMessage::Set(set()).into(), GENERIC_ONOFF_SET),
+            (
+                GenericOnOffMessage::SetUnacknowledged(set()).into(),
+                GENERIC_ONOFF_SET_UNACKNOWLEDGED,
+            ),
+        ];
+        for (event, opcode) in onoff {
+            assert_eq!(event.opcode(), opcode, "{event:?}");
+        }
+        let battery = MeshEvent::from(GenericBatteryMessage::Get);
+        assert_eq!(battery.opcode(), GENERIC_BATTERY_GET);
+        let status = SensorMessage::Status(SensorStatus::new(SensorPayload::default()));
+        assert_eq!(MeshEvent::from(status).opcode(), SENSOR_STATUS);
+        assert_eq!(MeshEvent::from(SensorQuery::Get(None)).opcode(), SENSOR_GET);
+    }
+
+    #[test]
+    fn test_unknown() {
+        let opcode = Opcode::TwoOctet(0x82, 0xff);
+        assert!(matches!(MeshEvent::parse(&opcode, &[]), Ok(None)));
+    }
+
+    #[test]
+    fn test_malformed() {
+        // A sensor status missing the last byte of the noise level
+        let event = MeshEvent::from(SensorMessage::Status(SensorStatus::new(
+            SensorPayload::default(),
+        )));
+        let (_, parameters) = emit(&event);
+        let truncated = &parameters[..parameters.len() - 1];
+        assert!(MeshEvent::parse(&event.opcode(), truncated).is_err());
+    }
+}
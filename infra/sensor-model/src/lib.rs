@@ -1,4 +1,4 @@
-#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 use btmesh_common::{InsufficientBuffer, ParseError};
 use btmesh_models::sensor::{
     CadenceDescriptor, PropertyId, SensorClient as SC, SensorConfig, SensorData, SensorDescriptor,
@@ -6,69 +6,94 @@ use btmesh_models::sensor::{
 };
 use heapless::Vec;
 
+#[cfg(feature = "std")]
+mod codec;
+mod composition;
+mod event;
+mod property;
+mod query;
+#[cfg(feature = "std")]
+mod raw;
+mod setup;
+mod units;
+#[cfg(feature = "std")]
+pub use codec::*;
+pub use composition::*;
+pub use event::*;
+pub use property::*;
+pub use query::*;
+#[cfg(feature = "std")]
+pub use raw::*;
+pub use setup::*;
+pub use units::*;
+
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct MicrobitSensorConfig;
 
-pub type SensorServer = SensorSetupServer<MicrobitSensorConfig, 3, 1>;
-pub type SensorClient = SC<MicrobitSensorConfig, 3, 1>;
-pub type SensorMessage = SM<MicrobitSensorConfig, 3, 1>;
+pub type SensorServer = SensorSetupServer<MicrobitSensorConfig, NUM_PROPERTIES, 1>;
+pub type SensorClient = SC<MicrobitSensorConfig, NUM_PROPERTIES, 1>;
+pub type SensorMessage = SM<MicrobitSensorConfig, NUM_PROPERTIES, 1>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct SensorPayload {
-    pub temperature: i8,
+    pub temperature: HalfDegreesCelsius,
     pub acceleration: Acceleration,
-    pub noise: u8,
+    pub noise: NoiseLevel,
+    /// Properties that were present in the decoded frame. Encoding always emits all properties.
+    #[serde(skip)]
+    pub present: PropertySet,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Acceleration {
-    pub x: i16,
-    pub y: i16,
-    pub z: i16,
+    pub x: MilliG,
+    pub y: MilliG,
+    pub z: MilliG,
 }
 
 impl Default for Acceleration {
     fn default() -> Self {
-        Self { x: 0, y: 0, z: 0 }
+        Self {
+            x: MilliG(0),
+            y: MilliG(0),
+            z: MilliG(0),
+        }
     }
 }
 
-const PROP_TEMP: PropertyId = PropertyId(0x4F);
-const PROP_XL: PropertyId = PropertyId(0x4242);
-const PROP_NOISE: PropertyId = PropertyId(0x79);
-
 impl Default for SensorPayload {
     fn default() -> Self {
         Self {
-            temperature: 0,
-            noise: 0,
+            temperature: HalfDegreesCelsius(0),
+            noise: NoiseLevel(0),
             acceleration: Acceleration::default(),
+            present: PropertySet::empty(),
         }
     }
 }
 
+impl SensorPayload {
+    /// Whether the property was present in the decoded frame.
+    pub fn is_present(&self, property: &SensorProperty) -> bool {
+        self.present.contains(property.id)
+    }
+
+    /// Decode a single property, checking the data against the property descriptor.
+    pub fn try_decode(&mut self, id: PropertyId, params: &[u8]) -> Result<(), DecodeError> {
+        let property =
+            SensorProperty::lookup(id).ok_or(DecodeError::UnknownProperty { property: id.0 })?;
+        property.decode(self, params)
+    }
+}
+
 impl SensorData for SensorPayload {
     fn decode(&mut self, id: PropertyId, params: &[u8]) -> Result<(), ParseError> {
-        if id == PROP_TEMP {
-            self.temperature = params[0] as i8;
-            Ok(())
-        } else if id == PROP_XL {
-            self.acceleration = Acceleration::default();
-            self.acceleration.x = i16::from_le_bytes([params[0], params[1]]);
-            self.acceleration.y = i16::from_le_bytes([params[2], params[3]]);
-            self.acceleration.z = i16::from_le_bytes([params[4], params[5]]);
-            Ok(())
-        } else if id == PROP_NOISE {
-            self.noise = params[0];
-            Ok(())
-        } else {
-            Err(ParseError::InvalidValue)
-        }
+        Ok(self.try_decode(id, params)?)
     }
 
     fn encode<const N: usize>(
@@ -76,19 +101,8 @@ impl SensorData for SensorPayload {
         property: PropertyId,
         xmit: &mut Vec<u8, N>,
     ) -> Result<(), InsufficientBuffer> {
-        if property == PROP_TEMP {
-            xmit.extend_from_slice(&self.temperature.to_le_bytes())
-                .map_err(|_| InsufficientBuffer)?;
-        } else if property == PROP_XL {
-            xmit.extend_from_slice(&self.acceleration.x.to_le_bytes())
-                .map_err(|_| InsufficientBuffer)?;
-            xmit.extend_from_slice(&self.acceleration.y.to_le_bytes())
-                .map_err(|_| InsufficientBuffer)?;
-            xmit.extend_from_slice(&self.acceleration.z.to_le_bytes())
-                .map_err(|_| InsufficientBuffer)?;
-        } else if property == PROP_NOISE {
-            xmit.extend_from_slice(&self.noise.to_le_bytes())
-                .map_err(|_| InsufficientBuffer)?;
+        if let Some(property) = SensorProperty::lookup(property) {
+            property.encoding.encode(property.get(self), xmit)?;
         }
         Ok(())
     }
@@ -97,42 +111,135 @@ impl SensorData for SensorPayload {
 impl SensorConfig for MicrobitSensorConfig {
     type Data = SensorPayload;
 
-    const DESCRIPTORS: &'static [SensorDescriptor] = &[
-        SensorDescriptor::new(PROP_TEMP, 1),
-        SensorDescriptor::new(PROP_XL, 6),
-        SensorDescriptor::new(PROP_NOISE, 1),
-    ];
+    const DESCRIPTORS: &'static [SensorDescriptor] = property::DESCRIPTORS;
 }
 
 impl SensorSetupConfig for MicrobitSensorConfig {
-    const CADENCE_DESCRIPTORS: &'static [CadenceDescriptor] = &[];
-    const SETTING_DESCRIPTORS: &'static [SettingDescriptor] = &[];
+    const CADENCE_DESCRIPTORS: &'static [CadenceDescriptor] = setup::CADENCE_DESCRIPTORS;
+    const SETTING_DESCRIPTORS: &'static [SettingDescriptor] = setup::SETTING_DESCRIPTORS;
 }
 
-/// RawMessage contains the opcode and message payload
-#[cfg(feature = "std")]
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub struct RawMessage {
-    #[serde(default)]
-    pub address: Option<u16>,
-    pub location: u16,
-    pub opcode: std::vec::Vec<u8>,
-    pub parameters: std::vec::Vec<u8>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btmesh_models::{sensor::SensorStatus, Message, Model};
+    use proptest::prelude::*;
 
-#[cfg(feature = "std")]
-impl btmesh_models::Message for RawMessage {
-    fn opcode(&self) -> btmesh_common::opcode::Opcode {
-        let (opcode, _) = btmesh_common::opcode::Opcode::split(&self.opcode[..]).unwrap();
-        opcode
+    fn encode(payload: SensorPayload) -> Vec<u8, 386> {
+        let msg = SensorMessage::Status(SensorStatus::new(payload));
+        let mut parameters = Vec::new();
+        msg.emit_parameters(&mut parameters).unwrap();
+        parameters
     }
 
-    fn emit_parameters<const N: usize>(
-        &self,
-        parameters: &mut heapless::Vec<u8, N>,
-    ) -> Result<(), InsufficientBuffer> {
-        parameters
-            .extend_from_slice(&self.parameters[..])
-            .map_err(|_| InsufficientBuffer)
+    fn parse(parameters: &[u8]) -> Result<Option<SensorMessage>, ParseError> {
+        let msg = SensorMessage::Status(SensorStatus::new(SensorPayload::default()));
+        SensorClient::parse(&msg.opcode(), parameters)
+    }
+
+    prop_compose! {
+        fn payload()(temperature: i8, x: i16, y: i16, z: i16, noise: u8) -> SensorPayload {
+            SensorPayload {
+                temperature: HalfDegreesCelsius(temperature),
+                acceleration: Acceleration {
+                    x: MilliG(x),
+                    y: MilliG(y),
+                    z: MilliG(z),
+                },
+                noise: NoiseLevel(noise),
+                present: PropertySet::all(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncated_property() {
+        let mut payload = SensorPayload::default();
+        assert_eq!(
+            payload.try_decode(ACCELERATION.id, &[1, 0, 2, 0]),
+            Err(DecodeError::Truncated {
+                property: 0x4242,
+                expected: 6,
+                actual: 4
+            })
+        );
+        assert_eq!(
+            payload.try_decode(TEMPERATURE.id, &[]),
+            Err(DecodeError::Truncated {
+                property: 0x4F,
+                expected: 1,
+                actual: 0
+            })
+        );
+        assert_eq!(payload, SensorPayload::default());
+    }
+
+    #[test]
+    fn test_presence() {
+        let mut payload = SensorPayload::default();
+        payload.try_decode(NOISE.id, &[42]).unwrap();
+        assert!(payload.is_present(&NOISE));
+        assert!(!payload.is_present(&TEMPERATURE));
+        assert!(!payload.is_present(&ACCELERATION));
+        assert_eq!(payload.present.iter().map(|p| p.name).next(), Some("noise"));
+    }
+
+    #[test]
+    fn test_unknown_property() {
+        let mut payload = SensorPayload::default();
+        assert_eq!(
+            payload.try_decode(PropertyId(0x1234), &[0]),
+            Err(DecodeError::UnknownProperty { property: 0x1234 })
+        );
+    }
+
+    #[test]
+    fn test_encoding_mismatch() {
+        let mut xmit: Vec<u8, 8> = Vec::new();
+        assert_eq!(
+            NOISE.encoding.encode(Value::I16(-1), &mut xmit),
+            Err(EncodeError::Mismatch {
+                encoding: Encoding::U8,
+                value: Value::I16(-1)
+            })
+        );
+        assert!(xmit.is_empty());
+
+        let mut xmit: Vec<u8, 4> = Vec::new();
+        assert_eq!(
+            ACCELERATION
+                .encoding
+                .encode(Value::Vec3I16(1, 2, 3), &mut xmit),
+            Err(EncodeError::InsufficientBuffer)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_roundtrip(payload in payload()) {
+            let parameters = encode(payload.clone());
+            match parse(&parameters) {
+                Ok(Some(SensorMessage::Status(status))) => prop_assert_eq!(status.data, payload),
+                other => prop_assert!(false, "unexpected parse result: {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_truncated_frames_do_not_panic(payload in payload(), len in 0usize..16) {
+            let parameters = encode(payload);
+            let len = len.min(parameters.len());
+            let _ = parse(&parameters[..len]);
+        }
+
+        #[test]
+        fn test_arbitrary_frames_do_not_panic(parameters in proptest::collection::vec(any::<u8>(), 0..64)) {
+            let _ = parse(&parameters);
+        }
+
+        #[test]
+        fn test_decode_arbitrary_property(id: u16, params in proptest::collection::vec(any::<u8>(), 0..8)) {
+            let mut payload = SensorPayload::default();
+            let _ = payload.try_decode(PropertyId(id), &params);
+        }
     }
 }
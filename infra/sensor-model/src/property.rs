@@ -0,0 +1,289 @@
+//! Registry of the sensor properties exposed by the micro:bit sensor server.
+//!
+//! Each entry describes how a property is laid out on the wire and which field of the
+//! [`SensorPayload`] it maps to. Decoding, encoding and the sensor descriptor table are all
+//! derived from [`PROPERTIES`], so adding a property is a matter of adding an entry here. The
+//! physical unit of a property is carried by the type of its payload field, see `units.rs`.
+use crate::{Acceleration, HalfDegreesCelsius, MilliG, NoiseLevel, SensorPayload};
+use btmesh_common::{InsufficientBuffer, ParseError};
+use btmesh_models::sensor::{PropertyId, SensorDescriptor};
+use heapless::Vec;
+
+/// Wire encoding of a property value. All multi-byte values are little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Encoding {
+    I8,
+    U8,
+    I16,
+    U16,
+    /// Three consecutive `i16` values (x, y, z).
+    Vec3I16,
+}
+
+/// A decoded property value, as it is represented on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Value {
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    Vec3I16(i16, i16, i16),
+}
+
+impl Value {
+    /// Scalar magnitude of the value, used for threshold comparisons. For vectors this is the
+    /// largest absolute component.
+    pub fn magnitude(&self) -> i32 {
+        match *self {
+            Self::I8(v) => v as i32,
+            Self::U8(v) => v as i32,
+            Self::I16(v) => v as i32,
+            Self::U16(v) => v as i32,
+            Self::Vec3I16(x, y, z) => (x as i32).abs().max((y as i32).abs()).max((z as i32).abs()),
+        }
+    }
+}
+
+impl Encoding {
+    /// Number of bytes used by a value on the wire.
+    pub const fn size(&self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::Vec3I16 => 6,
+        }
+    }
+
+    /// Decode a value from the start of `params`, or `None` if there are not enough bytes.
+    pub fn decode(&self, params: &[u8]) -> Option<Value> {
+        let params = params.get(..self.size())?;
+        Some(match self {
+            Self::I8 => Value::I8(params[0] as i8),
+            Self::U8 => Value::U8(params[0]),
+            Self::I16 => Value::I16(i16::from_le_bytes([params[0], params[1]])),
+            Self::U16 => Value::U16(u16::from_le_bytes([params[0], params[1]])),
+            Self::Vec3I16 => Value::Vec3I16(
+                i16::from_le_bytes([params[0], params[1]]),
+                i16::from_le_bytes([params[2], params[3]]),
+                i16::from_le_bytes([params[4], params[5]]),
+            ),
+        })
+    }
+
+    /// Encode a value at the end of `xmit`. The value must be of this encoding.
+    pub fn encode<const N: usize>(
+        &self,
+        value: Value,
+        xmit: &mut Vec<u8, N>,
+    ) -> Result<(), EncodeError> {
+        match (self, value) {
+            (Self::I8, Value::I8(v)) => xmit.extend_from_slice(&v.to_le_bytes()),
+            (Self::U8, Value::U8(v)) => xmit.extend_from_slice(&v.to_le_bytes()),
+            (Self::I16, Value::I16(v)) => xmit.extend_from_slice(&v.to_le_bytes()),
+            (Self::U16, Value::U16(v)) => xmit.extend_from_slice(&v.to_le_bytes()),
+            (Self::Vec3I16, Value::Vec3I16(x, y, z)) => [x, y, z]
+                .iter()
+                .try_for_each(|v| xmit.extend_from_slice(&v.to_le_bytes())),
+            _ => {
+                return Err(EncodeError::Mismatch {
+                    encoding: *self,
+                    value,
+                })
+            }
+        }
+        .map_err(|_| EncodeError::InsufficientBuffer)
+    }
+}
+
+/// Error encoding a sensor property value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EncodeError {
+    /// The value does not fit in the buffer.
+    InsufficientBuffer,
+    /// The value is not of the encoding of the property.
+    Mismatch { encoding: Encoding, value: Value },
+}
+
+impl From<EncodeError> for InsufficientBuffer {
+    /// Mesh messages can only fail to encode for lack of space, so a mismatch is reported as
+    /// such once it reaches them.
+    fn from(_: EncodeError) -> Self {
+        InsufficientBuffer
+    }
+}
+
+/// Error decoding a sensor property from a status message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    /// The property id is not part of the registry.
+    UnknownProperty { property: u16 },
+    /// The message carries fewer bytes than the property descriptor requires.
+    Truncated {
+        property: u16,
+        expected: usize,
+        actual: usize,
+    },
+    /// The data is malformed or out of range for the property.
+    InvalidValue { property: u16 },
+}
+
+impl From<DecodeError> for ParseError {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::UnknownProperty { .. } | DecodeError::InvalidValue { .. } => {
+                ParseError::InvalidValue
+            }
+            DecodeError::Truncated { .. } => ParseError::InvalidLength,
+        }
+    }
+}
+
+/// Declarative description of a single sensor property.
+pub struct SensorProperty {
+    pub id: PropertyId,
+    /// Field name used when the payload is represented as JSON.
+    pub name: &'static str,
+    pub encoding: Encoding,
+    get: fn(&SensorPayload) -> Value,
+    set: fn(&mut SensorPayload, Value),
+}
+
+impl SensorProperty {
+    pub const fn size(&self) -> usize {
+        self.encoding.size()
+    }
+
+    pub const fn descriptor(&self) -> SensorDescriptor {
+        SensorDescriptor::new(self.id, self.encoding.size())
+    }
+
+    /// Find the registry entry for a property id.
+    pub fn lookup(id: PropertyId) -> Option<&'static SensorProperty> {
+        PROPERTIES.iter().copied().find(|p| p.id == id)
+    }
+
+    /// Decode this property from `params` and store it in the payload.
+    pub fn decode(&self, payload: &mut SensorPayload, params: &[u8]) -> Result<(), DecodeError> {
+        let value = self.encoding.decode(params).ok_or(DecodeError::Truncated {
+            property: self.id.0,
+            expected: self.size(),
+            actual: params.len(),
+        })?;
+        self.set(payload, value);
+        payload.present.insert(self.id);
+        Ok(())
+    }
+
+    /// Read this property from the payload.
+    pub fn get(&self, payload: &SensorPayload) -> Value {
+        (self.get)(payload)
+    }
+
+    /// Store a value of this property in the payload.
+    pub fn set(&self, payload: &mut SensorPayload, value: Value) {
+        (self.set)(payload, value)
+    }
+}
+
+pub const TEMPERATURE: SensorProperty = SensorProperty {
+    id: PropertyId(0x4F),
+    name: "temperature",
+    encoding: Encoding::I8,
+    get: |p| Value::I8(p.temperature.0),
+    set: |p, v| {
+        if let Value::I8(v) = v {
+            p.temperature = HalfDegreesCelsius(v);
+        }
+    },
+};
+
+pub const ACCELERATION: SensorProperty = SensorProperty {
+    id: PropertyId(0x4242),
+    name: "acceleration",
+    encoding: Encoding::Vec3I16,
+    get: |p| Value::Vec3I16(p.acceleration.x.0, p.acceleration.y.0, p.acceleration.z.0),
+    set: |p, v| {
+        if let Value::Vec3I16(x, y, z) = v {
+            p.acceleration = Acceleration {
+                x: MilliG(x),
+                y: MilliG(y),
+                z: MilliG(z),
+            };
+        }
+    },
+};
+
+pub const NOISE: SensorProperty = SensorProperty {
+    id: PropertyId(0x79),
+    name: "noise",
+    encoding: Encoding::U8,
+    get: |p| Value::U8(p.noise.0),
+    set: |p, v| {
+        if let Value::U8(v) = v {
+            p.noise = NoiseLevel(v);
+        }
+    },
+};
+
+macro_rules! properties {
+    ($($property:ident),* $(,)?) => {
+        /// All properties of the sensor server, in descriptor order.
+        pub const PROPERTIES: &[&SensorProperty] = &[$(&$property),*];
+
+        /// Number of entries in [`PROPERTIES`].
+        pub const NUM_PROPERTIES: usize = [$(stringify!($property)),*].len();
+
+        pub(crate) const DESCRIPTORS: &[SensorDescriptor] = &[$($property.descriptor()),*];
+    };
+}
+
+properties!(TEMPERATURE, ACCELERATION, NOISE);
+
+/// A set of properties from the registry, used to track which properties a frame carried.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PropertySet(u32);
+
+impl PropertySet {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn all() -> Self {
+        Self((1 << PROPERTIES.len()) - 1)
+    }
+
+    fn bit(id: PropertyId) -> u32 {
+        PROPERTIES
+            .iter()
+            .position(|p| p.id == id)
+            .map(|i| 1 << i)
+            .unwrap_or(0)
+    }
+
+    pub fn insert(&mut self, id: PropertyId) {
+        self.0 |= Self::bit(id);
+    }
+
+    pub fn contains(&self, id: PropertyId) -> bool {
+        let bit = Self::bit(id);
+        bit != 0 && self.0 & bit == bit
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Iterate over the registry entries contained in this set.
+    pub fn iter(&self) -> impl Iterator<Item = &'static SensorProperty> {
+        let set = *self;
+        PROPERTIES
+            .iter()
+            .copied()
+            .filter(move |p| set.contains(p.id))
+    }
+}
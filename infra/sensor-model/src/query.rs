@@ -0,0 +1,90 @@
+//! Sensor Get and Sensor Descriptor Get messages, sent to query a sensor server on demand.
+use crate::property::DecodeError;
+use btmesh_common::{opcode::Opcode, InsufficientBuffer};
+use btmesh_models::sensor::PropertyId;
+use heapless::Vec;
+
+pub const SENSOR_DESCRIPTOR_GET: Opcode = Opcode::TwoOctet(0x82, 0x30);
+pub const SENSOR_GET: Opcode = Opcode::TwoOctet(0x82, 0x31);
+pub const SENSOR_DESCRIPTOR_STATUS: Opcode = Opcode::OneOctet(0x51);
+/// Reply to a Sensor Get, also published periodically by the device.
+pub const SENSOR_STATUS: Opcode = Opcode::OneOctet(0x52);
+
+/// A query of the sensor server. Without a property id, all properties are queried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SensorQuery {
+    DescriptorGet(Option<PropertyId>),
+    Get(Option<PropertyId>),
+}
+
+fn property_id(params: &[u8]) -> Result<Option<PropertyId>, DecodeError> {
+    match params {
+        [] => Ok(None),
+        [lo, hi] => Ok(Some(PropertyId(u16::from_le_bytes([*lo, *hi])))),
+        _ => Err(DecodeError::Truncated {
+            property: 0,
+            expected: 2,
+            actual: params.len(),
+        }),
+    }
+}
+
+impl SensorQuery {
+    /// Parse a query, returning `None` if the opcode is not a query opcode.
+    pub fn parse(opcode: &Opcode, params: &[u8]) -> Result<Option<Self>, DecodeError> {
+        if *opcode == SENSOR_DESCRIPTOR_GET {
+            Ok(Some(Self::DescriptorGet(property_id(params)?)))
+        } else if *opcode == SENSOR_GET {
+            Ok(Some(Self::Get(property_id(params)?)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl btmesh_models::Message for SensorQuery {
+    fn opcode(&self) -> Opcode {
+        match self {
+            Self::DescriptorGet(_) => SENSOR_DESCRIPTOR_GET,
+            Self::Get(_) => SENSOR_GET,
+        }
+    }
+
+    fn emit_parameters<const N: usize>(
+        &self,
+        xmit: &mut Vec<u8, N>,
+    ) -> Result<(), InsufficientBuffer> {
+        match self {
+            Self::DescriptorGet(Some(id)) | Self::Get(Some(id)) => xmit
+                .extend_from_slice(&id.0.to_le_bytes())
+                .map_err(|_| InsufficientBuffer),
+            Self::DescriptorGet(None) | Self::Get(None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NOISE;
+    use btmesh_models::Message;
+
+    #[test]
+    fn test_query_codec() {
+        for query in [
+            SensorQuery::Get(None),
+            SensorQuery::Get(Some(NOISE.id)),
+            SensorQuery::DescriptorGet(None),
+            SensorQuery::DescriptorGet(Some(NOISE.id)),
+        ] {
+            let mut parameters: Vec<u8, 2> = Vec::new();
+            query.emit_parameters(&mut parameters).unwrap();
+            assert_eq!(
+                SensorQuery::parse(&query.opcode(), &parameters),
+                Ok(Some(query))
+            );
+        }
+        assert!(SensorQuery::parse(&SENSOR_GET, &[0x79]).is_err());
+    }
+}
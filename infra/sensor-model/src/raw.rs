@@ -0,0 +1,478 @@
+//! JSON wire format of raw mesh messages, exchanged between the gateway, the simulators and the
+//! model converter.
+//!
+//! Messages are serialized through a versioned envelope. Version 0 is the original format
+//! without a `version` field, with the opcode and parameters as arrays of numbers. Version 1
+//! adds metadata about the received frame, and optionally encodes the opcode and parameters as
+//! hex or base64 strings.
+use btmesh_common::{opcode::Opcode, InsufficientBuffer};
+use core::fmt;
+use schemars::{schema::RootSchema, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+/// The version written when serializing a [`RawMessage`].
+pub const RAW_MESSAGE_VERSION: u8 = 1;
+
+/// Encoding of the opcode and parameter bytes in the JSON representation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteEncoding {
+    /// An array of numbers, the only encoding supported by version 0.
+    #[default]
+    Array,
+    Hex,
+    Base64,
+}
+
+impl ByteEncoding {
+    fn is_array(&self) -> bool {
+        *self == Self::Array
+    }
+}
+
+/// Information about how a message was received from the mesh.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Metadata {
+    /// Unicast address of the element that sent the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<u16>,
+    /// Remaining time to live of the received frame.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u8>,
+    /// Signal strength of the received frame, in dBm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i8>,
+}
+
+impl Metadata {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Largest parameters accepted in a [`RawMessage`]. An access message carries at most 384 bytes,
+/// some of which are taken by the opcode.
+pub const MAX_PARAMETERS_LEN: usize = 380;
+
+/// A raw message as it is read from the wire, before its opcode and parameters are validated.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Envelope", into = "Envelope")]
+pub struct UncheckedRawMessage {
+    pub address: Option<u16>,
+    pub location: u16,
+    pub opcode: Vec<u8>,
+    pub parameters: Vec<u8>,
+    pub metadata: Metadata,
+    /// Encoding used for the opcode and parameters when serializing the message.
+    pub encoding: ByteEncoding,
+}
+
+/// RawMessage contains the opcode and message payload
+///
+/// The opcode and parameters are validated when the message is created, so that it can be
+/// sent to the mesh without further checks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedRawMessage", into = "UncheckedRawMessage")]
+pub struct RawMessage {
+    pub address: Option<u16>,
+    pub location: u16,
+    pub metadata: Metadata,
+    /// Encoding used for the opcode and parameters when serializing the message.
+    pub encoding: ByteEncoding,
+    opcode: Opcode,
+    opcode_bytes: Vec<u8>,
+    parameters: Vec<u8>,
+}
+
+impl RawMessage {
+    pub fn new(opcode: Opcode, parameters: Vec<u8>) -> Result<Self, RawMessageError> {
+        let mut opcode_bytes: heapless::Vec<u8, 3> = heapless::Vec::new();
+        opcode
+            .emit(&mut opcode_bytes)
+            .map_err(|_| RawMessageError::InvalidOpcode(Vec::new()))?;
+        Self::try_from(UncheckedRawMessage {
+            opcode: opcode_bytes.to_vec(),
+            parameters,
+            ..Default::default()
+        })
+    }
+
+    /// Create a raw message from a mesh model message.
+    pub fn from_message<M: btmesh_models::Message>(message: &M) -> Result<Self, RawMessageError> {
+        // Emit into a buffer the size of a whole access message, the length is checked below
+        let mut parameters: heapless::Vec<u8, 384> = heapless::Vec::new();
+        message
+            .emit_parameters(&mut parameters)
+            .map_err(|_| RawMessageError::ParametersTooLong(parameters.capacity() + 1))?;
+        Self::new(message.opcode(), parameters.to_vec())
+    }
+
+    /// The opcode, as it is encoded on the wire.
+    pub fn opcode_bytes(&self) -> &[u8] {
+        &self.opcode_bytes
+    }
+
+    pub fn parameters(&self) -> &[u8] {
+        &self.parameters
+    }
+
+    /// JSON schema of the serialized message.
+    pub fn json_schema() -> RootSchema {
+        schemars::schema_for!(Envelope)
+    }
+}
+
+impl PartialEq for RawMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.address == other.address
+            && self.location == other.location
+            && self.metadata == other.metadata
+            && self.encoding == other.encoding
+            && self.opcode_bytes == other.opcode_bytes
+            && self.parameters == other.parameters
+    }
+}
+
+impl Eq for RawMessage {}
+
+impl TryFrom<UncheckedRawMessage> for RawMessage {
+    type Error = RawMessageError;
+
+    fn try_from(msg: UncheckedRawMessage) -> Result<Self, Self::Error> {
+        let opcode = match Opcode::split(&msg.opcode[..]) {
+            Ok((opcode, rest)) if rest.is_empty() => opcode,
+            _ => return Err(RawMessageError::InvalidOpcode(msg.opcode)),
+        };
+        if msg.parameters.len() > MAX_PARAMETERS_LEN {
+            return Err(RawMessageError::ParametersTooLong(msg.parameters.len()));
+        }
+        Ok(Self {
+            address: msg.address,
+            location: msg.location,
+            metadata: msg.metadata,
+            encoding: msg.encoding,
+            opcode,
+            opcode_bytes: msg.opcode,
+            parameters: msg.parameters,
+        })
+    }
+}
+
+impl From<RawMessage> for UncheckedRawMessage {
+    fn from(msg: RawMessage) -> Self {
+        Self {
+            address: msg.address,
+            location: msg.location,
+            opcode: msg.opcode_bytes,
+            parameters: msg.parameters,
+            metadata: msg.metadata,
+            encoding: msg.encoding,
+        }
+    }
+}
+
+/// Error reading a [`RawMessage`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawMessageError {
+    UnsupportedVersion(u8),
+    /// The opcode is empty, truncated or followed by extra bytes.
+    InvalidOpcode(Vec<u8>),
+    /// The parameters exceed [`MAX_PARAMETERS_LEN`].
+    ParametersTooLong(usize),
+    /// The bytes of a field are not valid for the declared encoding.
+    InvalidEncoding {
+        field: &'static str,
+        encoding: ByteEncoding,
+    },
+}
+
+impl fmt::Display for RawMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported raw message version {version}")
+            }
+            Self::InvalidOpcode(opcode) => write!(f, "invalid opcode {opcode:02x?}"),
+            Self::ParametersTooLong(len) => write!(
+                f,
+                "parameters are {len} bytes long, at most {MAX_PARAMETERS_LEN} are allowed"
+            ),
+            Self::InvalidEncoding { field, encoding } => {
+                write!(f, "field '{field}' is not valid for encoding {encoding:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RawMessageError {}
+
+/// Bytes, either as an array of numbers or as an encoded string.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum Bytes {
+    Array(Vec<u8>),
+    Encoded(String),
+}
+
+impl Bytes {
+    fn encode(bytes: Vec<u8>, encoding: ByteEncoding) -> Self {
+        match encoding {
+            ByteEncoding::Array => Self::Array(bytes),
+            ByteEncoding::Hex => Self::Encoded(hex::encode(bytes)),
+            ByteEncoding::Base64 => Self::Encoded(base64::encode(bytes)),
+        }
+    }
+
+    fn decode(
+        self,
+        field: &'static str,
+        encoding: ByteEncoding,
+    ) -> Result<Vec<u8>, RawMessageError> {
+        let invalid = RawMessageError::InvalidEncoding { field, encoding };
+        match (self, encoding) {
+            (Self::Array(bytes), _) => Ok(bytes),
+            (Self::Encoded(s), ByteEncoding::Hex) => hex::decode(s).map_err(|_| invalid),
+            (Self::Encoded(s), ByteEncoding::Base64) => base64::decode(s).map_err(|_| invalid),
+            (Self::Encoded(_), ByteEncoding::Array) => Err(invalid),
+        }
+    }
+}
+
+/// A raw mesh message.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[schemars(rename = "RawMessage")]
+struct Envelope {
+    /// Version of the format. Messages without a version are read as version 0.
+    #[serde(default)]
+    version: u8,
+    /// Unicast address of the destination (commands) or source (telemetry) node.
+    #[serde(default)]
+    address: Option<u16>,
+    /// Location descriptor of the element.
+    location: u16,
+    #[serde(default, skip_serializing_if = "ByteEncoding::is_array")]
+    encoding: ByteEncoding,
+    opcode: Bytes,
+    parameters: Bytes,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    metadata: Metadata,
+}
+
+impl TryFrom<Envelope> for UncheckedRawMessage {
+    type Error = RawMessageError;
+
+    fn try_from(envelope: Envelope) -> Result<Self, Self::Error> {
+        if envelope.version > RAW_MESSAGE_VERSION {
+            return Err(RawMessageError::UnsupportedVersion(envelope.version));
+        }
+        Ok(Self {
+            address: envelope.address,
+            location: envelope.location,
+            opcode: envelope.opcode.decode("opcode", envelope.encoding)?,
+            parameters: envelope
+                .parameters
+                .decode("parameters", envelope.encoding)?,
+            metadata: envelope.metadata,
+            encoding: envelope.encoding,
+        })
+    }
+}
+
+impl From<UncheckedRawMessage> for Envelope {
+    fn from(msg: UncheckedRawMessage) -> Self {
+        Self {
+            version: RAW_MESSAGE_VERSION,
+            address: msg.address,
+            location: msg.location,
+            encoding: msg.encoding,
+            opcode: Bytes::encode(msg.opcode, msg.encoding),
+            parameters: Bytes::encode(msg.parameters, msg.encoding),
+            metadata: msg.metadata,
+        }
+    }
+}
+
+impl btmesh_models::Message for RawMessage {
+    fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+
+    fn emit_parameters<const N: usize>(
+        &self,
+        parameters: &mut heapless::Vec<u8, N>,
+    ) -> Result<(), InsufficientBuffer> {
+        parameters
+            .extend_from_slice(&self.parameters[..])
+            .map_err(|_| InsufficientBuffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(encoding: ByteEncoding) -> UncheckedRawMessage {
+        UncheckedRawMessage {
+            address: Some(0x00ab),
+            location: 0x0100,
+            opcode: vec![0x82, 0x03],
+            parameters: vec![0x01, 0x00],
+            metadata: Metadata {
+                source: Some(0x00ab),
+                ttl: Some(5),
+                rssi: Some(-70),
+            },
+            encoding,
+        }
+    }
+
+    #[test]
+    fn test_read_v0() {
+        let msg: UncheckedRawMessage = serde_json::from_value(json!({
+            "address": 171,
+            "location": 256,
+            "opcode": [130, 3],
+            "parameters": [1, 0],
+        }))
+        .unwrap();
+        assert_eq!(
+            msg,
+            UncheckedRawMessage {
+                metadata: Metadata::default(),
+                ..message(ByteEncoding::Array)
+            }
+        );
+
+        let msg: RawMessage = serde_json::from_value(json!({
+            "location": 0,
+            "opcode": [130, 3],
+            "parameters": [],
+        }))
+        .unwrap();
+        assert_eq!(msg.address, None);
+    }
+
+    #[test]
+    fn test_write_v1() {
+        let value = serde_json::to_value(message(ByteEncoding::Array)).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "version": 1,
+                "address": 171,
+                "location": 256,
+                "opcode": [130, 3],
+                "parameters": [1, 0],
+                "metadata": {"source": 171, "ttl": 5, "rssi": -70},
+            })
+        );
+
+        let value = serde_json::to_value(message(ByteEncoding::Hex)).unwrap();
+        assert_eq!(value["encoding"], json!("hex"));
+        assert_eq!(value["opcode"], json!("8203"));
+        assert_eq!(value["parameters"], json!("0100"));
+
+        let value = serde_json::to_value(message(ByteEncoding::Base64)).unwrap();
+        assert_eq!(value["encoding"], json!("base64"));
+        assert_eq!(value["opcode"], json!("ggM="));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for encoding in [ByteEncoding::Array, ByteEncoding::Hex, ByteEncoding::Base64] {
+            let checked = RawMessage::try_from(message(encoding)).unwrap();
+            let data = serde_json::to_string(&checked).unwrap();
+            let msg: RawMessage = serde_json::from_str(&data).unwrap();
+            assert_eq!(msg, checked);
+            assert_eq!(UncheckedRawMessage::from(msg), message(encoding));
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let result = serde_json::from_value::<RawMessage>(json!({
+            "version": 2,
+            "location": 0,
+            "opcode": [130, 3],
+            "parameters": [],
+        }));
+        assert!(result.is_err());
+
+        let result = serde_json::from_value::<RawMessage>(json!({
+            "version": 1,
+            "location": 0,
+            "encoding": "hex",
+            "opcode": "zz",
+            "parameters": "",
+        }));
+        assert!(result.is_err());
+
+        let result = serde_json::from_value::<RawMessage>(json!({
+            "version": 1,
+            "location": 0,
+            "opcode": "8203",
+            "parameters": "",
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_checked() {
+        let msg = RawMessage::try_from(message(ByteEncoding::Array)).unwrap();
+        assert_eq!(msg.opcode_bytes(), &[0x82, 0x03]);
+        assert_eq!(msg.parameters(), &[0x01, 0x00]);
+        assert_eq!(
+            RawMessage::new(btmesh_models::Message::opcode(&msg), vec![0x01, 0x00])
+                .unwrap()
+                .opcode_bytes(),
+            msg.opcode_bytes()
+        );
+
+        for opcode in [vec![], vec![0x82], vec![0x82, 0x03, 0x00]] {
+            let result = RawMessage::try_from(UncheckedRawMessage {
+                opcode: opcode.clone(),
+                ..message(ByteEncoding::Array)
+            });
+            assert_eq!(result.unwrap_err(), RawMessageError::InvalidOpcode(opcode));
+        }
+
+        let result = RawMessage::try_from(UncheckedRawMessage {
+            parameters: vec![0; MAX_PARAMETERS_LEN + 1],
+            ..message(ByteEncoding::Array)
+        });
+        assert_eq!(
+            result.unwrap_err(),
+            RawMessageError::ParametersTooLong(MAX_PARAMETERS_LEN + 1)
+        );
+
+        let result = serde_json::from_value::<RawMessage>(json!({
+            "location": 0,
+            "opcode": [],
+            "parameters": [],
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = serde_json::to_value(RawMessage::json_schema()).unwrap();
+        assert_eq!(schema["title"], json!("RawMessage"));
+        let properties = schema["properties"].as_object().unwrap();
+        for field in [
+            "version",
+            "address",
+            "location",
+            "encoding",
+            "opcode",
+            "parameters",
+            "metadata",
+        ] {
+            assert!(properties.contains_key(field), "missing {field}");
+        }
+        assert_eq!(
+            schema["required"],
+            json!(["location", "opcode", "parameters"])
+        );
+    }
+}
@@ -0,0 +1,927 @@
+//! Sensor Cadence and Sensor Setting states of the sensor setup server.
+//!
+//! The cadence controls how often the sensor server publishes: it publishes faster while a
+//! value is within the fast cadence range, and when publishing on change, only publishes once
+//! a value has moved by more than the status trigger deltas. Settings are additional
+//! parameters attached to a property, such as the window the microphone is sampled over.
+use crate::{
+    property::DecodeError, Encoding, SensorMessage, SensorPayload, SensorProperty, SensorServer,
+    Value, ACCELERATION, NOISE, NUM_PROPERTIES,
+};
+use btmesh_common::{opcode::Opcode, InsufficientBuffer, ParseError};
+use btmesh_models::{
+    sensor::{CadenceDescriptor, PropertyId, SettingDescriptor},
+    Message, Model,
+};
+use heapless::Vec;
+
+const MAX_PERIOD_DIVISOR: u8 = 15;
+const MAX_MIN_INTERVAL: u8 = 26;
+
+/// Status trigger deltas of a sensor cadence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StatusTrigger {
+    /// Deltas in the same format as the property value.
+    Value { down: Value, up: Value },
+    /// Deltas in units of 0.01 percent of the previous value.
+    Percent { down: u16, up: u16 },
+}
+
+/// The Sensor Cadence state of a single property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SensorCadence {
+    /// Divisor of the publish period while in fast cadence, as a power of two.
+    pub period_divisor: u8,
+    pub trigger: StatusTrigger,
+    /// Minimum interval between two status publications, as a power of two in milliseconds.
+    pub min_interval: u8,
+    /// Lower bound of the fast cadence range.
+    pub fast_low: Value,
+    /// Upper bound of the fast cadence range.
+    pub fast_high: Value,
+}
+
+impl SensorCadence {
+    /// Decode the cadence of `property`, as carried by Sensor Cadence Set and Status messages.
+    pub fn decode(property: &SensorProperty, params: &[u8]) -> Result<Self, DecodeError> {
+        let truncated = |expected| DecodeError::Truncated {
+            property: property.id.0,
+            expected,
+            actual: params.len(),
+        };
+        let invalid = DecodeError::InvalidValue {
+            property: property.id.0,
+        };
+
+        let first = *params.first().ok_or_else(|| truncated(1))?;
+        let percent = first & 0x80 != 0;
+        let delta_size = if percent { 2 } else { property.size() };
+        let expected = 1 + 2 * delta_size + 1 + 2 * property.size();
+        if params.len() < expected {
+            return Err(truncated(expected));
+        }
+
+        let period_divisor = first & 0x7F;
+        if period_divisor > MAX_PERIOD_DIVISOR {
+            return Err(invalid);
+        }
+
+        let value = |pos: usize| {
+            property
+                .encoding
+                .decode(&params[pos..])
+                .ok_or_else(|| truncated(expected))
+        };
+
+        let mut pos = 1;
+        let trigger = if percent {
+            let down = u16::from_le_bytes([params[pos], params[pos + 1]]);
+            let up = u16::from_le_bytes([params[pos + 2], params[pos + 3]]);
+            StatusTrigger::Percent { down, up }
+        } else {
+            StatusTrigger::Value {
+                down: value(pos)?,
+                up: value(pos + delta_size)?,
+            }
+        };
+        pos += 2 * delta_size;
+
+        let min_interval = params[pos];
+        if min_interval > MAX_MIN_INTERVAL {
+            return Err(invalid);
+        }
+        pos += 1;
+
+        Ok(Self {
+            period_divisor,
+            trigger,
+            min_interval,
+            fast_low: value(pos)?,
+            fast_high: value(pos + property.size())?,
+        })
+    }
+
+    pub fn encode<const N: usize>(
+        &self,
+        property: &SensorProperty,
+        xmit: &mut Vec<u8, N>,
+    ) -> Result<(), InsufficientBuffer> {
+        let divisor = self.period_divisor & 0x7F;
+        match self.trigger {
+            StatusTrigger::Value { down, up } => {
+                xmit.push(divisor).map_err(|_| InsufficientBuffer)?;
+                property.encoding.encode(down, xmit)?;
+                property.encoding.encode(up, xmit)?;
+            }
+            StatusTrigger::Percent { down, up } => {
+                xmit.push(0x80 | divisor).map_err(|_| InsufficientBuffer)?;
+                xmit.extend_from_slice(&down.to_le_bytes())
+                    .map_err(|_| InsufficientBuffer)?;
+                xmit.extend_from_slice(&up.to_le_bytes())
+                    .map_err(|_| InsufficientBuffer)?;
+            }
+        }
+        xmit.push(self.min_interval)
+            .map_err(|_| InsufficientBuffer)?;
+        property.encoding.encode(self.fast_low, xmit)?;
+        property.encoding.encode(self.fast_high, xmit)?;
+        Ok(())
+    }
+
+    /// Whether `value` is within the fast cadence range.
+    ///
+    /// If the high bound is below the low bound, the range wraps and covers the values outside
+    /// of `high..low` instead.
+    pub fn is_fast(&self, value: Value) -> bool {
+        let low = self.fast_low.magnitude();
+        let high = self.fast_high.magnitude();
+        let value = value.magnitude();
+        if high >= low {
+            value >= low && value <= high
+        } else {
+            value >= low || value <= high
+        }
+    }
+
+    /// Whether the change from `previous` to `current` exceeds the status trigger deltas.
+    pub fn is_triggered(&self, previous: Value, current: Value) -> bool {
+        let previous = previous.magnitude();
+        let delta = current.magnitude() - previous;
+        let (down, up) = match self.trigger {
+            StatusTrigger::Value { down, up } => (down.magnitude(), up.magnitude()),
+            StatusTrigger::Percent { down, up } => {
+                let percent = |delta: i32| {
+                    ((delta.abs() as i64 * 10_000) / previous.abs().max(1) as i64) as i32
+                };
+                return match delta {
+                    d if d > 0 => percent(d) >= (up as i32).max(1),
+                    d if d < 0 => percent(d) >= (down as i32).max(1),
+                    _ => false,
+                };
+            }
+        };
+        match delta {
+            d if d > 0 => d >= up.max(1),
+            d if d < 0 => -d >= down.max(1),
+            _ => false,
+        }
+    }
+
+    /// Minimum interval between two status publications.
+    pub fn min_interval_ms(&self) -> u32 {
+        1 << self.min_interval.min(MAX_MIN_INTERVAL)
+    }
+
+    /// Publish period to use while reporting `value`, given the configured period.
+    pub fn period_ms(&self, period_ms: u32, value: Value) -> u32 {
+        let period = if self.is_fast(value) {
+            period_ms >> self.period_divisor.min(MAX_PERIOD_DIVISOR)
+        } else {
+            period_ms
+        };
+        period.max(self.min_interval_ms())
+    }
+}
+
+/// Publish four times as often while it's loud, and on change when the level moves by 16.
+const NOISE_CADENCE: SensorCadence = SensorCadence {
+    period_divisor: 2,
+    trigger: StatusTrigger::Value {
+        down: Value::U8(16),
+        up: Value::U8(16),
+    },
+    min_interval: 9,
+    fast_low: Value::U8(128),
+    fast_high: Value::U8(u8::MAX),
+};
+
+/// Publish four times as often while the device is being shaken.
+const ACCELERATION_CADENCE: SensorCadence = SensorCadence {
+    period_divisor: 2,
+    trigger: StatusTrigger::Value {
+        down: Value::Vec3I16(256, 256, 256),
+        up: Value::Vec3I16(256, 256, 256),
+    },
+    min_interval: 9,
+    fast_low: Value::Vec3I16(1500, 1500, 1500),
+    fast_high: Value::Vec3I16(i16::MAX, i16::MAX, i16::MAX),
+};
+
+macro_rules! cadences {
+    ($($property:ident => $cadence:ident),* $(,)?) => {
+        /// Default cadence of each property supporting the Sensor Cadence state.
+        pub const DEFAULT_CADENCES: &[(&SensorProperty, SensorCadence)] = &[$((&$property, $cadence)),*];
+
+        pub(crate) const CADENCE_DESCRIPTORS: &[CadenceDescriptor] = &[$(CadenceDescriptor {
+            id: $property.id,
+        }),*];
+    };
+}
+
+cadences!(NOISE => NOISE_CADENCE, ACCELERATION => ACCELERATION_CADENCE);
+
+/// Current cadence of all properties supporting the Sensor Cadence state.
+#[derive(Clone)]
+pub struct Cadences {
+    cadences: Vec<(PropertyId, SensorCadence), NUM_PROPERTIES>,
+}
+
+impl Default for Cadences {
+    fn default() -> Self {
+        Self {
+            cadences: DEFAULT_CADENCES.iter().map(|(p, c)| (p.id, *c)).collect(),
+        }
+    }
+}
+
+impl Cadences {
+    pub fn get(&self, id: PropertyId) -> Option<&SensorCadence> {
+        self.cadences.iter().find(|(p, _)| *p == id).map(|(_, c)| c)
+    }
+
+    /// Replace the cadence of a property. Returns `false` if the property has no cadence.
+    pub fn set(&mut self, id: PropertyId, cadence: SensorCadence) -> bool {
+        match self.cadences.iter_mut().find(|(p, _)| *p == id) {
+            Some((_, c)) => {
+                *c = cadence;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&'static SensorProperty, &SensorCadence)> {
+        self.cadences
+            .iter()
+            .filter_map(|(id, c)| SensorProperty::lookup(*id).map(|p| (p, c)))
+    }
+
+    /// Publish period for a reading: the fastest period of any property in fast cadence.
+    pub fn period_ms(&self, period_ms: u32, payload: &SensorPayload) -> u32 {
+        self.iter()
+            .map(|(p, c)| c.period_ms(period_ms, p.get(payload)))
+            .min()
+            .unwrap_or(period_ms)
+    }
+
+    /// Whether any property changed enough between two readings to publish on change.
+    pub fn is_triggered(&self, previous: &SensorPayload, current: &SensorPayload) -> bool {
+        self.iter()
+            .any(|(p, c)| c.is_triggered(p.get(previous), p.get(current)))
+    }
+}
+
+/// Access permitted to a sensor setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SettingAccess {
+    Read = 0x01,
+    ReadWrite = 0x03,
+}
+
+impl SettingAccess {
+    pub fn parse(access: u8) -> Option<Self> {
+        match access {
+            0x01 => Some(Self::Read),
+            0x03 => Some(Self::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+/// Values of all sensor settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SensorSettings {
+    /// Window the microphone is sampled over for a noise reading, in milliseconds.
+    pub noise_sampling_window: u16,
+}
+
+impl Default for SensorSettings {
+    fn default() -> Self {
+        Self {
+            noise_sampling_window: 50,
+        }
+    }
+}
+
+/// Declarative description of a sensor setting.
+pub struct SensorSetting {
+    /// The property this setting belongs to.
+    pub property: PropertyId,
+    pub id: PropertyId,
+    pub name: &'static str,
+    pub encoding: Encoding,
+    pub access: SettingAccess,
+    get: fn(&SensorSettings) -> Value,
+    set: fn(&mut SensorSettings, Value),
+}
+
+impl SensorSetting {
+    pub const fn descriptor(&self) -> SettingDescriptor {
+        SettingDescriptor {
+            id: self.property,
+            setting: self.id,
+            size: self.encoding.size(),
+        }
+    }
+
+    /// Find the registry entry for a setting of a property.
+    pub fn lookup(property: PropertyId, id: PropertyId) -> Option<&'static SensorSetting> {
+        SETTINGS
+            .iter()
+            .copied()
+            .find(|s| s.property == property && s.id == id)
+    }
+
+    pub fn get(&self, settings: &SensorSettings) -> Value {
+        (self.get)(settings)
+    }
+
+    pub fn set(&self, settings: &mut SensorSettings, value: Value) {
+        (self.set)(settings, value)
+    }
+}
+
+pub const NOISE_SAMPLING_WINDOW: SensorSetting = SensorSetting {
+    property: NOISE.id,
+    id: PropertyId(0x4243),
+    name: "noise_sampling_window",
+    encoding: Encoding::U16,
+    access: SettingAccess::ReadWrite,
+    get: |s| Value::U16(s.noise_sampling_window),
+    set: |s, v| {
+        if let Value::U16(v) = v {
+            s.noise_sampling_window = v;
+        }
+    },
+};
+
+macro_rules! settings {
+    ($($setting:ident),* $(,)?) => {
+        /// All settings of the sensor setup server.
+        pub const SETTINGS: &[&SensorSetting] = &[$(&$setting),*];
+
+        pub(crate) const SETTING_DESCRIPTORS: &[SettingDescriptor] = &[$($setting.descriptor()),*];
+    };
+}
+
+settings!(NOISE_SAMPLING_WINDOW);
+
+pub const SENSOR_CADENCE_GET: Opcode = Opcode::TwoOctet(0x82, 0x34);
+pub const SENSOR_CADENCE_SET: Opcode = Opcode::OneOctet(0x55);
+pub const SENSOR_CADENCE_SET_UNACKNOWLEDGED: Opcode = Opcode::OneOctet(0x56);
+pub const SENSOR_CADENCE_STATUS: Opcode = Opcode::OneOctet(0x57);
+pub const SENSOR_SETTING_GET: Opcode = Opcode::TwoOctet(0x82, 0x36);
+pub const SENSOR_SETTING_SET: Opcode = Opcode::OneOctet(0x59);
+pub const SENSOR_SETTING_SET_UNACKNOWLEDGED: Opcode = Opcode::OneOctet(0x5A);
+pub const SENSOR_SETTING_STATUS: Opcode = Opcode::OneOctet(0x5B);
+
+/// Cadence and setting messages of the sensor setup server, for code handling raw frames.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SetupMessage {
+    CadenceGet(PropertyId),
+    CadenceSet(PropertyId, SensorCadence),
+    CadenceSetUnacknowledged(PropertyId, SensorCadence),
+    /// The cadence is `None` if the property does not support the cadence state.
+    CadenceStatus(PropertyId, Option<SensorCadence>),
+    SettingGet {
+        property: PropertyId,
+        setting: PropertyId,
+    },
+    SettingSet {
+        property: PropertyId,
+        setting: PropertyId,
+        value: Value,
+    },
+    SettingSetUnacknowledged {
+        property: PropertyId,
+        setting: PropertyId,
+        value: Value,
+    },
+    /// The access and value are `None` if the setting does not exist.
+    SettingStatus {
+        property: PropertyId,
+        setting: PropertyId,
+        value: Option<(SettingAccess, Value)>,
+    },
+}
+
+fn property_id(params: &[u8], pos: usize) -> Result<PropertyId, DecodeError> {
+    match params.get(pos..pos + 2) {
+        Some(id) => Ok(PropertyId(u16::from_le_bytes([id[0], id[1]]))),
+        None => Err(DecodeError::Truncated {
+            property: 0,
+            expected: pos + 2,
+            actual: params.len(),
+        }),
+    }
+}
+
+fn cadence(params: &[u8]) -> Result<(PropertyId, SensorCadence), DecodeError> {
+    let id = property_id(params, 0)?;
+    let property =
+        SensorProperty::lookup(id).ok_or(DecodeError::UnknownProperty { property: id.0 })?;
+    Ok((id, SensorCadence::decode(property, &params[2..])?))
+}
+
+fn setting(params: &[u8]) -> Result<(PropertyId, &'static SensorSetting), DecodeError> {
+    let property = property_id(params, 0)?;
+    let id = property_id(params, 2)?;
+    let setting = SensorSetting::lookup(property, id)
+        .ok_or(DecodeError::UnknownProperty { property: id.0 })?;
+    Ok((property, setting))
+}
+
+fn setting_value(setting: &SensorSetting, params: &[u8]) -> Result<Value, DecodeError> {
+    setting
+        .encoding
+        .decode(params)
+        .ok_or(DecodeError::Truncated {
+            property: setting.id.0,
+            expected: setting.encoding.size(),
+            actual: params.len(),
+        })
+}
+
+impl SetupMessage {
+    /// Parse a setup message, returning `None` if the opcode is not a setup opcode.
+    pub fn parse(opcode: &Opcode, params: &[u8]) -> Result<Option<Self>, DecodeError> {
+        let message = if *opcode == SENSOR_CADENCE_GET {
+            Self::CadenceGet(property_id(params, 0)?)
+        } else if *opcode == SENSOR_CADENCE_SET {
+            let (id, cadence) = cadence(params)?;
+            Self::CadenceSet(id, cadence)
+        } else if *opcode == SENSOR_CADENCE_SET_UNACKNOWLEDGED {
+            let (id, cadence) = cadence(params)?;
+            Self::CadenceSetUnacknowledged(id, cadence)
+        } else if *opcode == SENSOR_CADENCE_STATUS {
+            if params.len() == 2 {
+                Self::CadenceStatus(property_id(params, 0)?, None)
+            } else {
+                let (id, cadence) = cadence(params)?;
+                Self::CadenceStatus(id, Some(cadence))
+            }
+        } else if *opcode == SENSOR_SETTING_GET {
+            Self::SettingGet {
+                property: property_id(params, 0)?,
+                setting: property_id(params, 2)?,
+            }
+        } else if *opcode == SENSOR_SETTING_SET {
+            let (property, setting) = setting(params)?;
+            Self::SettingSet {
+                property,
+                setting: setting.id,
+                value: setting_value(setting, &params[4..])?,
+            }
+        } else if *opcode == SENSOR_SETTING_SET_UNACKNOWLEDGED {
+            let (property, setting) = setting(params)?;
+            Self::SettingSetUnacknowledged {
+                property,
+                setting: setting.id,
+                value: setting_value(setting, &params[4..])?,
+            }
+        } else if *opcode == SENSOR_SETTING_STATUS {
+            if params.len() == 4 {
+                Self::SettingStatus {
+                    property: property_id(params, 0)?,
+                    setting: property_id(params, 2)?,
+                    value: None,
+                }
+            } else {
+                let (property, setting) = setting(params)?;
+                let access = params.get(4).and_then(|a| SettingAccess::parse(*a)).ok_or(
+                    DecodeError::InvalidValue {
+                        property: setting.id.0,
+                    },
+                )?;
+                Self::SettingStatus {
+                    property,
+                    setting: setting.id,
+                    value: Some((access, setting_value(setting, &params[5..])?)),
+                }
+            }
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(message))
+    }
+}
+
+impl SetupMessage {
+    /// Read the setup message carried by a message of the sensor setup server, or `None` for
+    /// the other sensor messages.
+    pub fn from_sensor_message(message: &SensorMessage) -> Result<Option<Self>, DecodeError> {
+        let mut parameters: Vec<u8, 386> = Vec::new();
+        if message.emit_parameters(&mut parameters).is_err() {
+            return Ok(None);
+        }
+        Self::parse(&message.opcode(), &parameters)
+    }
+
+    /// The setup message as a message of the sensor setup server, to send it from the model.
+    pub fn to_sensor_message(&self) -> Result<SensorMessage, ParseError> {
+        let mut parameters: Vec<u8, 386> = Vec::new();
+        self.emit_parameters(&mut parameters)
+            .map_err(|_| ParseError::InvalidLength)?;
+        SensorServer::parse(&self.opcode(), &parameters)?.ok_or(ParseError::InvalidValue)
+    }
+}
+
+/// Cadence and setting states of the sensor setup server.
+#[derive(Clone, Default)]
+pub struct SetupState {
+    pub cadences: Cadences,
+    pub settings: SensorSettings,
+}
+
+impl SetupState {
+    /// Apply a cadence or setting message received by the server, returning the status to
+    /// reply with for Get and acknowledged Set messages.
+    pub fn process(&mut self, message: &SetupMessage) -> Option<SetupMessage> {
+        match *message {
+            SetupMessage::CadenceGet(property) => Some(self.cadence_status(property)),
+            SetupMessage::CadenceSet(property, cadence) => {
+                self.cadences.set(property, cadence);
+                Some(self.cadence_status(property))
+            }
+            SetupMessage::CadenceSetUnacknowledged(property, cadence) => {
+                self.cadences.set(property, cadence);
+                None
+            }
+            SetupMessage::SettingGet { property, setting } => {
+                Some(self.setting_status(property, setting))
+            }
+            SetupMessage::SettingSet {
+                property,
+                setting,
+                value,
+            } => {
+                self.set_setting(property, setting, value);
+                Some(self.setting_status(property, setting))
+            }
+            SetupMessage::SettingSetUnacknowledged {
+                property,
+                setting,
+                value,
+            } => {
+                self.set_setting(property, setting, value);
+                None
+            }
+            SetupMessage::CadenceStatus(..) | SetupMessage::SettingStatus { .. } => None,
+        }
+    }
+
+    fn cadence_status(&self, property: PropertyId) -> SetupMessage {
+        SetupMessage::CadenceStatus(property, self.cadences.get(property).copied())
+    }
+
+    fn setting_status(&self, property: PropertyId, setting: PropertyId) -> SetupMessage {
+        SetupMessage::SettingStatus {
+            property,
+            setting,
+            value: SensorSetting::lookup(property, setting)
+                .map(|s| (s.access, s.get(&self.settings))),
+        }
+    }
+
+    /// Set a setting, unless it is read only.
+    fn set_setting(&mut self, property: PropertyId, setting: PropertyId, value: Value) {
+        if let Some(setting) = SensorSetting::lookup(property, setting) {
+            if setting.access == SettingAccess::ReadWrite {
+                setting.set(&mut self.settings, value);
+            }
+        }
+    }
+}
+
+impl Message for SetupMessage {
+    fn opcode(&self) -> Opcode {
+        match self {
+            Self::CadenceGet(_) => SENSOR_CADENCE_GET,
+            Self::CadenceSet(..) => SENSOR_CADENCE_SET,
+            Self::CadenceSetUnacknowledged(..) => SENSOR_CADENCE_SET_UNACKNOWLEDGED,
+            Self::CadenceStatus(..) => SENSOR_CADENCE_STATUS,
+            Self::SettingGet { .. } => SENSOR_SETTING_GET,
+            Self::SettingSet { .. } => SENSOR_SETTING_SET,
+            Self::SettingSetUnacknowledged { .. } => SENSOR_SETTING_SET_UNACKNOWLEDGED,
+            Self::SettingStatus { .. } => SENSOR_SETTING_STATUS,
+        }
+    }
+
+    fn emit_parameters<const N: usize>(
+        &self,
+        xmit: &mut Vec<u8, N>,
+    ) -> Result<(), InsufficientBuffer> {
+        let id = |xmit: &mut Vec<u8, N>, id: &PropertyId| {
+            xmit.extend_from_slice(&id.0.to_le_bytes())
+                .map_err(|_| InsufficientBuffer)
+        };
+        match self {
+            Self::CadenceGet(property) | Self::CadenceStatus(property, None) => id(xmit, property),
+            Self::CadenceSet(property, cadence)
+            | Self::CadenceSetUnacknowledged(property, cadence)
+            | Self::CadenceStatus(property, Some(cadence)) => {
+                id(xmit, property)?;
+                match SensorProperty::lookup(*property) {
+                    Some(p) => cadence.encode(p, xmit),
+                    None => Ok(()),
+                }
+            }
+            Self::SettingGet { property, setting }
+            | Self::SettingStatus {
+                property,
+                setting,
+                value: None,
+            } => {
+                id(xmit, property)?;
+                id(xmit, setting)
+            }
+            Self::SettingSet {
+                property,
+                setting,
+                value,
+            }
+            | Self::SettingSetUnacknowledged {
+                property,
+                setting,
+                value,
+            } => {
+                id(xmit, property)?;
+                id(xmit, setting)?;
+                match SensorSetting::lookup(*property, *setting) {
+                    Some(s) => Ok(s.encoding.encode(*value, xmit)?),
+                    None => Ok(()),
+                }
+            }
+            Self::SettingStatus {
+                property,
+                setting,
+                value: Some((access, value)),
+            } => {
+                id(xmit, property)?;
+                id(xmit, setting)?;
+                xmit.push(*access as u8).map_err(|_| InsufficientBuffer)?;
+                match SensorSetting::lookup(*property, *setting) {
+                    Some(s) => Ok(s.encoding.encode(*value, xmit)?),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TEMPERATURE;
+
+    fn roundtrip(message: SetupMessage) {
+        let mut parameters: Vec<u8, 386> = Vec::new();
+        message.emit_parameters(&mut parameters).unwrap();
+        assert_eq!(
+            SetupMessage::parse(&message.opcode(), &parameters),
+            Ok(Some(message))
+        );
+    }
+
+    #[test]
+    fn test_cadence_codec() {
+        for (property, cadence) in DEFAULT_CADENCES {
+            let mut xmit: Vec<u8, 32> = Vec::new();
+            cadence.encode(property, &mut xmit).unwrap();
+            assert_eq!(xmit.len(), 2 + 4 * property.size());
+            assert_eq!(SensorCadence::decode(property, &xmit), Ok(*cadence));
+        }
+
+        let cadence = SensorCadence {
+            period_divisor: 3,
+            trigger: StatusTrigger::Percent {
+                down: 500,
+                up: 1000,
+            },
+            min_interval: 10,
+            fast_low: Value::U8(10),
+            fast_high: Value::U8(20),
+        };
+        let mut xmit: Vec<u8, 32> = Vec::new();
+        cadence.encode(&NOISE, &mut xmit).unwrap();
+        assert_eq!(&xmit[..], &[0x83, 0xF4, 0x01, 0xE8, 0x03, 10, 10, 20]);
+        assert_eq!(SensorCadence::decode(&NOISE, &xmit), Ok(cadence));
+    }
+
+    #[test]
+    fn test_cadence_decode_errors() {
+        assert_eq!(
+            SensorCadence::decode(&NOISE, &[0x02, 16, 16, 9]),
+            Err(DecodeError::Truncated {
+                property: NOISE.id.0,
+                expected: 6,
+                actual: 4
+            })
+        );
+        assert_eq!(
+            SensorCadence::decode(&NOISE, &[0x10, 16, 16, 9, 128, 255]),
+            Err(DecodeError::InvalidValue {
+                property: NOISE.id.0
+            })
+        );
+        assert_eq!(
+            SensorCadence::decode(&NOISE, &[0x02, 16, 16, 27, 128, 255]),
+            Err(DecodeError::InvalidValue {
+                property: NOISE.id.0
+            })
+        );
+    }
+
+    #[test]
+    fn test_cadence_behaviour() {
+        assert!(!NOISE_CADENCE.is_fast(Value::U8(127)));
+        assert!(NOISE_CADENCE.is_fast(Value::U8(128)));
+        assert_eq!(NOISE_CADENCE.period_ms(4000, Value::U8(10)), 4000);
+        assert_eq!(NOISE_CADENCE.period_ms(4000, Value::U8(200)), 1000);
+        // Never faster than the minimum interval
+        assert_eq!(NOISE_CADENCE.period_ms(1000, Value::U8(200)), 512);
+
+        assert!(!NOISE_CADENCE.is_triggered(Value::U8(100), Value::U8(115)));
+        assert!(NOISE_CADENCE.is_triggered(Value::U8(100), Value::U8(116)));
+        assert!(NOISE_CADENCE.is_triggered(Value::U8(100), Value::U8(84)));
+
+        let wrapped = SensorCadence {
+            fast_low: Value::U8(200),
+            fast_high: Value::U8(50),
+            ..NOISE_CADENCE
+        };
+        assert!(wrapped.is_fast(Value::U8(20)));
+        assert!(!wrapped.is_fast(Value::U8(100)));
+        assert!(wrapped.is_fast(Value::U8(220)));
+
+        let percent = SensorCadence {
+            trigger: StatusTrigger::Percent {
+                down: 1000,
+                up: 1000,
+            },
+            ..NOISE_CADENCE
+        };
+        assert!(!percent.is_triggered(Value::U8(100), Value::U8(109)));
+        assert!(percent.is_triggered(Value::U8(100), Value::U8(110)));
+    }
+
+    #[test]
+    fn test_cadences() {
+        let mut cadences = Cadences::default();
+        let quiet = SensorPayload::default();
+        let loud = SensorPayload {
+            noise: crate::NoiseLevel(200),
+            ..SensorPayload::default()
+        };
+        assert_eq!(cadences.period_ms(4000, &quiet), 4000);
+        assert_eq!(cadences.period_ms(4000, &loud), 1000);
+        assert!(cadences.is_triggered(&quiet, &loud));
+        assert!(!cadences.is_triggered(&quiet, &quiet));
+
+        assert!(!cadences.set(TEMPERATURE.id, NOISE_CADENCE));
+        let slow = SensorCadence {
+            period_divisor: 0,
+            ..NOISE_CADENCE
+        };
+        assert!(cadences.set(NOISE.id, slow));
+        assert_eq!(cadences.get(NOISE.id), Some(&slow));
+        assert_eq!(cadences.period_ms(4000, &loud), 4000);
+    }
+
+    #[test]
+    fn test_setup_messages() {
+        roundtrip(SetupMessage::CadenceGet(NOISE.id));
+        roundtrip(SetupMessage::CadenceSet(NOISE.id, NOISE_CADENCE));
+        roundtrip(SetupMessage::CadenceSetUnacknowledged(
+            ACCELERATION.id,
+            ACCELERATION_CADENCE,
+        ));
+        roundtrip(SetupMessage::CadenceStatus(TEMPERATURE.id, None));
+        roundtrip(SetupMessage::CadenceStatus(NOISE.id, Some(NOISE_CADENCE)));
+        roundtrip(SetupMessage::SettingGet {
+            property: NOISE.id,
+            setting: NOISE_SAMPLING_WINDOW.id,
+        });
+        roundtrip(SetupMessage::SettingSet {
+            property: NOISE.id,
+            setting: NOISE_SAMPLING_WINDOW.id,
+            value: Value::U16(100),
+        });
+        roundtrip(SetupMessage::SettingStatus {
+            property: NOISE.id,
+            setting: NOISE_SAMPLING_WINDOW.id,
+            value: Some((SettingAccess::ReadWrite, Value::U16(100))),
+        });
+
+        assert_eq!(SetupMessage::parse(&Opcode::OneOctet(0x52), &[]), Ok(None));
+        assert_eq!(
+            SetupMessage::parse(&SENSOR_CADENCE_SET, &[0x79]),
+            Err(DecodeError::Truncated {
+                property: 0,
+                expected: 2,
+                actual: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut settings = SensorSettings::default();
+        let setting = SensorSetting::lookup(NOISE.id, NOISE_SAMPLING_WINDOW.id).unwrap();
+        setting.set(&mut settings, Value::U16(200));
+        assert_eq!(settings.noise_sampling_window, 200);
+        assert_eq!(setting.get(&settings), Value::U16(200));
+        assert!(SensorSetting::lookup(TEMPERATURE.id, NOISE_SAMPLING_WINDOW.id).is_none());
+    }
+
+    #[test]
+    fn test_setup_state() {
+        let mut state = SetupState::default();
+        let slow = SensorCadence {
+            period_divisor: 0,
+            ..NOISE_CADENCE
+        };
+        assert_eq!(
+            state.process(&SetupMessage::CadenceSet(NOISE.id, slow)),
+            Some(SetupMessage::CadenceStatus(NOISE.id, Some(slow)))
+        );
+        assert_eq!(state.cadences.get(NOISE.id), Some(&slow));
+        assert_eq!(
+            state.process(&SetupMessage::CadenceSetUnacknowledged(
+                NOISE.id,
+                NOISE_CADENCE
+            )),
+            None
+        );
+        assert_eq!(
+            state.process(&SetupMessage::CadenceGet(NOISE.id)),
+            Some(SetupMessage::CadenceStatus(NOISE.id, Some(NOISE_CADENCE)))
+        );
+        // Properties without a cadence are reported as such
+        assert_eq!(
+            state.process(&SetupMessage::CadenceSet(TEMPERATURE.id, NOISE_CADENCE)),
+            Some(SetupMessage::CadenceStatus(TEMPERATURE.id, None))
+        );
+
+        assert_eq!(
+            state.process(&SetupMessage::SettingSet {
+                property: NOISE.id,
+                setting: NOISE_SAMPLING_WINDOW.id,
+                value: Value::U16(100),
+            }),
+            Some(SetupMessage::SettingStatus {
+                property: NOISE.id,
+                setting: NOISE_SAMPLING_WINDOW.id,
+                value: Some((SettingAccess::ReadWrite, Value::U16(100))),
+            })
+        );
+        assert_eq!(state.settings.noise_sampling_window, 100);
+        assert_eq!(
+            state.process(&SetupMessage::SettingSetUnacknowledged {
+                property: NOISE.id,
+                setting: NOISE_SAMPLING_WINDOW.id,
+                value: Value::U16(200),
+            }),
+            None
+        );
+        assert_eq!(state.settings.noise_sampling_window, 200);
+        assert_eq!(
+            state.process(&SetupMessage::SettingGet {
+                property: TEMPERATURE.id,
+                setting: NOISE_SAMPLING_WINDOW.id,
+            }),
+            Some(SetupMessage::SettingStatus {
+                property: TEMPERATURE.id,
+                setting: NOISE_SAMPLING_WINDOW.id,
+                value: None,
+            })
+        );
+        assert_eq!(
+            state.process(&SetupMessage::CadenceStatus(NOISE.id, None)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_sensor_messages() {
+        let status = SetupMessage::CadenceStatus(NOISE.id, Some(NOISE_CADENCE));
+        let message = status.to_sensor_message().unwrap();
+        assert_eq!(message.opcode(), SENSOR_CADENCE_STATUS);
+        assert_eq!(
+            SetupMessage::from_sensor_message(&message),
+            Ok(Some(status))
+        );
+
+        let set = SetupMessage::SettingSet {
+            property: NOISE.id,
+            setting: NOISE_SAMPLING_WINDOW.id,
+            value: Value::U16(100),
+        };
+        let message = set.to_sensor_message().unwrap();
+        assert_eq!(SetupMessage::from_sensor_message(&message), Ok(Some(set)));
+    }
+}
@@ -0,0 +1,121 @@
+//! Physical units of the sensor readings.
+//!
+//! The types hold the raw value as it is transmitted over the mesh, and convert to and from
+//! SI values. Their serde representation uses the SI value, so JSON consumers don't need to
+//! know about the wire scaling.
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Standard gravity, in m/s².
+pub const STANDARD_GRAVITY: f32 = 9.80665;
+const STANDARD_GRAVITY_F64: f64 = 9.80665;
+
+// Round to the nearest integer, `f32::round` is not available without std.
+fn round(v: f32) -> f32 {
+    if v >= 0.0 {
+        (v + 0.5) as i32 as f32
+    } else {
+        (v - 0.5) as i32 as f32
+    }
+}
+
+/// Temperature in steps of 0.5 °C. Serialized as degrees Celsius.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HalfDegreesCelsius(pub i8);
+
+impl HalfDegreesCelsius {
+    /// Convert from degrees Celsius, rounding to the nearest half degree and saturating at the
+    /// range of the wire format.
+    pub fn from_celsius(celsius: f32) -> Self {
+        Self(round(celsius * 2.0) as i8)
+    }
+
+    pub fn celsius(&self) -> f32 {
+        self.0 as f32 / 2.0
+    }
+}
+
+impl Serialize for HalfDegreesCelsius {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.0 as f64 / 2.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for HalfDegreesCelsius {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        f32::deserialize(deserializer).map(Self::from_celsius)
+    }
+}
+
+/// Acceleration in thousandths of standard gravity. Serialized as m/s².
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MilliG(pub i16);
+
+impl MilliG {
+    /// Convert from m/s², rounding to the nearest milli-g and saturating at the range of the
+    /// wire format.
+    pub fn from_meters_per_second_squared(acceleration: f32) -> Self {
+        Self(round(acceleration * 1000.0 / STANDARD_GRAVITY) as i16)
+    }
+
+    pub fn meters_per_second_squared(&self) -> f32 {
+        self.0 as f32 * STANDARD_GRAVITY / 1000.0
+    }
+}
+
+impl Serialize for MilliG {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Computed in f64, so the JSON value is not polluted by f32 rounding artifacts
+        serializer.serialize_f64(self.0 as f64 * STANDARD_GRAVITY_F64 / 1000.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for MilliG {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        f32::deserialize(deserializer).map(Self::from_meters_per_second_squared)
+    }
+}
+
+/// Sound level as reported by the microphone, between 0 and 255. This is a relative level and
+/// does not correspond to a calibrated decibel value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(transparent)]
+pub struct NoiseLevel(pub u8);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temperature() {
+        assert_eq!(HalfDegreesCelsius(45).celsius(), 22.5);
+        assert_eq!(
+            HalfDegreesCelsius::from_celsius(22.5),
+            HalfDegreesCelsius(45)
+        );
+        assert_eq!(
+            HalfDegreesCelsius::from_celsius(22.3),
+            HalfDegreesCelsius(45)
+        );
+        assert_eq!(
+            HalfDegreesCelsius::from_celsius(-3.2),
+            HalfDegreesCelsius(-6)
+        );
+        assert_eq!(
+            HalfDegreesCelsius::from_celsius(100.0),
+            HalfDegreesCelsius(i8::MAX)
+        );
+    }
+
+    #[test]
+    fn test_acceleration() {
+        assert!((MilliG(1000).meters_per_second_squared() - STANDARD_GRAVITY).abs() < 1e-4);
+        assert_eq!(
+            MilliG::from_meters_per_second_squared(STANDARD_GRAVITY),
+            MilliG(1000)
+        );
+        assert_eq!(MilliG::from_meters_per_second_squared(-4.9), MilliG(-500));
+    }
+}
@@ -13,12 +13,14 @@ use btmesh_models::{
         battery::{
             GenericBatteryFlags, GenericBatteryFlagsCharging, GenericBatteryFlagsIndicator,
             GenericBatteryFlagsPresence, GenericBatteryMessage, GenericBatteryServer,
-            GenericBatteryStatus,
+            GenericBatteryStatus, GENERIC_BATTERY_SERVER,
+        },
+        onoff::{
+            GenericOnOffClient, GenericOnOffMessage, GenericOnOffServer, GENERIC_ONOFF_CLIENT,
+            GENERIC_ONOFF_SERVER,
         },
-        onoff::{GenericOnOffClient, GenericOnOffMessage, GenericOnOffServer},
     },
-    sensor::SensorStatus,
-    Model,
+    sensor::{SensorStatus, SENSOR_SETUP_SERVER},
 };
 use clap::Parser;
 use dbus::Path;
@@ -63,35 +65,41 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let root_path = Path::from("/simulator");
     let app_path = Path::from(format!("{}/{}", root_path.clone(), "application"));
 
-    let front = Path::from(format!("{}/ele{}", root_path.clone(), "00"));
-    let left = Path::from(format!("{}/ele{}", root_path.clone(), "01"));
-    let right = Path::from(format!("{}/ele{}", root_path.clone(), "02"));
+    // The elements and models of the micro:bit, in the same order
+    let element_path = |index: usize| Path::from(format!("{}/ele{:02}", root_path, index));
+    let front = element_path(
+        MicrobitComposition::element_index(MicrobitComposition::FRONT.location()).unwrap(),
+    );
     let sim = Application {
         path: app_path,
-        elements: vec![
-            Element {
-                path: front.clone(),
-                location: Some(0x0100),
-                models: vec![
-                    Arc::new(FromDrogue::new(GenericOnOffServer)),
-                    Arc::new(FromDrogue::new(GenericBatteryServer)),
-                    Arc::new(FromDrogue::new(Sensor::new())),
-                ],
-                control_handle: Some(element_handle.clone()),
-            },
-            Element {
-                path: left,
-                location: Some(0x010D),
-                models: vec![Arc::new(FromDrogue::new(GenericOnOffClient))],
+        elements: MicrobitComposition::ELEMENTS
+            .iter()
+            .enumerate()
+            .map(|(index, element)| Element {
+                path: element_path(index),
+                location: Some(element.location()),
+                models: element
+                    .models
+                    .iter()
+                    .map(|model| match *model {
+                        m if m == GENERIC_ONOFF_SERVER => {
+                            Arc::new(FromDrogue::new(GenericOnOffServer)) as _
+                        }
+                        m if m == GENERIC_ONOFF_CLIENT => {
+                            Arc::new(FromDrogue::new(GenericOnOffClient)) as _
+                        }
+                        m if m == GENERIC_BATTERY_SERVER => {
+                            Arc::new(FromDrogue::new(GenericBatteryServer)) as _
+                        }
+                        m if m == SENSOR_SETUP_SERVER => {
+                            Arc::new(FromDrogue::new(Sensor::new())) as _
+                        }
+                        m => panic!("no simulated model for {:?}", m),
+                    })
+                    .collect(),
                 control_handle: Some(element_handle.clone()),
-            },
-            Element {
-                path: right,
-                location: Some(0x010E),
-                models: vec![Arc::new(FromDrogue::new(GenericOnOffClient))],
-                control_handle: Some(element_handle),
-            },
-        ],
+            })
+            .collect(),
         events_tx: app_tx,
         provisioner: None,
     };
@@ -142,9 +150,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }));
 
                     let data = SensorPayload {
-                        temperature: 22,
+                        temperature: HalfDegreesCelsius::from_celsius(22.0),
                         acceleration: Default::default(),
-                        noise: 0,
+                        noise: NoiseLevel(0),
+                        present: PropertySet::all(),
                     };
 
                     let sensor = SensorMessage::Status(SensorStatus::new(data));
@@ -161,7 +170,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         match msg {
                             ElementMessage::Received(received) => {
                                 println!("Received: {:?}", received);
-                                if let Ok(Some(GenericOnOffMessage::Set(m))) = GenericOnOffServer::parse(&received.opcode, &received.parameters) {
+                                if let Ok(Some(MeshEvent::OnOff(GenericOnOffMessage::Set(m)))) = MeshEvent::parse(&received.opcode, &received.parameters) {
                                     if m.on_off == 1 {
                                         println!("Turn ON");
                                     } else {
@@ -4,23 +4,24 @@ use crate::{
     publisher::{Publisher, PublisherExt},
     utils::InitParams,
 };
-use btmesh_common::opcode::Opcode;
 use btmesh_models::{
     generic::{
         battery::{
             GenericBatteryFlags, GenericBatteryFlagsCharging, GenericBatteryFlagsIndicator,
             GenericBatteryFlagsPresence, GenericBatteryMessage, GenericBatteryStatus,
         },
-        onoff::{GenericOnOffMessage, GenericOnOffServer},
+        onoff::GenericOnOffMessage,
     },
     sensor::SensorStatus,
-    Model,
 };
 use gloo_timers::callback::Interval;
 use gloo_utils::{document, history, window};
 use rand::prelude::random;
 use reqwest::Url;
-use sensor_model::{RawMessage, SensorMessage, SensorPayload};
+use sensor_model::{
+    HalfDegreesCelsius, MeshEvent, NoiseLevel, PropertySet, RawMessage, SensorMessage,
+    SensorPayload,
+};
 use std::{str::FromStr, string::ToString, sync::Arc};
 use wasm_bindgen::{JsCast, JsValue};
 use web_sys::{HtmlInputElement as InputElement, Node};
@@ -140,7 +141,7 @@ pub struct App {
     application: String,
     device: String,
     password: String,
-    temperature: i8,
+    temperature: HalfDegreesCelsius,
 
     refs: Refs,
 }
@@ -178,7 +179,7 @@ impl Component for App {
                 on: false,
                 brightness: 255,
             },
-            temperature: 22,
+            temperature: HalfDegreesCelsius::from_celsius(22.0),
 
             url: url.unwrap_or_else(|| {
                 "wss://mqtt-endpoint-ws-browser-drogue-iot.apps.wonderful.iot-playground.org/mqtt"
@@ -211,7 +212,8 @@ impl Component for App {
                         SensorMessage::Status(SensorStatus::new(SensorPayload {
                             temperature: self.temperature,
                             acceleration: Default::default(),
-                            noise: 0,
+                            noise: NoiseLevel(0),
+                            present: PropertySet::all(),
                         }));
 
                     let _ = simulator.publisher.publish(&sensor);
@@ -244,7 +246,9 @@ impl Component for App {
                 .cast::<InputElement>()
                 .and_then(|input| input.value().parse::<i8>().ok())
             {
-                vec![Msg::Set(Box::new(move |app| app.temperature = temp))]
+                vec![Msg::Set(Box::new(move |app| {
+                    app.temperature = HalfDegreesCelsius(temp)
+                }))]
             } else {
                 vec![]
             }
@@ -366,13 +370,13 @@ impl Component for App {
                     id="temp-slider"
                     class="slider is-half has-output"
                     step="1" min={i8::MIN.to_string()} max={i8::MAX.to_string()}
-                    value={self.temperature.to_string()}
+                    value={self.temperature.0.to_string()}
                     type="range"
                     onchange={on_change_temp.clone().reform(|_|())}
                     oninput={on_change_temp.reform(|_|())}
                     ref={self.refs.temperature.clone()}
                 />
-                <output class="slider" for="temp-slider">{self.temperature as f32 / 2.0}</output>
+                <output class="slider" for="temp-slider">{self.temperature.celsius()}</output>
             </Field>
 
             <Field label="Display">
@@ -449,9 +453,9 @@ impl App {
 
                 let on_command = ctx.link().batch_callback(|command: RawMessage| {
                     log::info!("Received command: {command:?}");
-                    let (opcode, _) = Opcode::split(&command.opcode[..]).unwrap();
-                    if let Ok(Some(GenericOnOffMessage::Set(msg))) =
-                        GenericOnOffServer::parse(&opcode, &command.parameters)
+                    let opcode = btmesh_models::Message::opcode(&command);
+                    if let Ok(Some(MeshEvent::OnOff(GenericOnOffMessage::Set(msg)))) =
+                        MeshEvent::parse(&opcode, command.parameters())
                     {
                         vec![Msg::Set(Box::new(move |app| {
                             app.matrix = MatrixState {